@@ -0,0 +1,771 @@
+// Headless frontend. Drives the same Session/Run as the imgui one with gdb-ish commands read from
+// stdin, so it works over ssh and can be scripted: degrugger-cli ./a.out < commands.txt
+use std::io::{ self, BufRead, Write };
+use std::path::PathBuf;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, Instant };
+
+use iced_x86::FlowControl;
+
+use degrugger::session::{ Session, Run, DebugeeEvent, LOAD_BIAS };
+use degrugger::dwarf_model::{ format_value, value_size };
+use degrugger::insertpoint::{ BreakPoint, Point };
+use degrugger::registers::{ USER_REGS_NAMES, user_reg, decode_eflags };
+use degrugger::stack::walk_stack;
+
+// Debug info is done loading once the worker has been quiet for this long
+const DEBUG_INFO_QUIET: Duration = Duration::from_millis(500);
+const DEBUG_INFO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Source level step gives up after this many instructions
+const MAX_STEP_INSTRUCTIONS: usize = 1_000_000;
+
+// "demangle off" shows linkage names, everything that prints a function name looks at it
+static SHOW_MANGLED: AtomicBool = AtomicBool::new(false);
+// "step-columns on" makes step stop at every column of a line with more than one statement
+static STEP_COLUMNS: AtomicBool = AtomicBool::new(false);
+
+const HELP: &str = "\
+break FILE:LINE | LINE | *ADDR   breakpoint, ADDR is a file offset like in the disassembly
+break FILE:LINE:COLUMN           one statement of a line, info line FILE:LINE lists them
+break FUNCTION                   every function called that, ns::Class::foo or just foo. Only
+                                 the executable's, library functions only have their PLT stub
+rbreak REGEX                     every function whose name matches
+delete ID                        remove a breakpoint
+info breakpoints | regs | debug
+info functions [TEXT]            functions whose (mangled or demangled) name contains TEXT
+info line [FILE:]LINE            columns of the line that have code
+demangle on | off                show demangled or linkage names
+run                              start (or restart) the debugee
+continue                         keep going until the next stop
+step                             until the next source line, steps over calls without line info
+step-columns on | off            step stops at every statement of a line too
+stepi                            single instruction
+finish                           until the current function returns
+bt                               backtrace
+print $REG | *ADDR | NUMBER | VARIABLE
+kill
+quit";
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    // Any number of --debug-dir DIR, searched for separate debug info
+    let mut debug_dirs = vec![];
+    while let Some(i) = args.iter().position(|a| a == "--debug-dir") {
+        if i + 1 >= args.len() {
+            break;
+        }
+        debug_dirs.push(PathBuf::from(args.remove(i + 1)));
+        args.remove(i);
+    }
+    if args.len() < 2 {
+        println!("Usage: {} [--debug-dir DIR]... <executable> [src root]", args[0]);
+        std::process::exit(1);
+    }
+
+    let mut session = match Session::new(args[1].clone(), args.get(2).cloned(), debug_dirs) {
+        Ok(s) => s,
+        Err(_) => {
+            println!("Can't open {}", args[1]);
+            std::process::exit(1);
+        },
+    };
+    wait_for_debug_info(&mut session);
+
+    let stdin = io::stdin();
+    loop {
+        print!("(dgr) ");
+        io::stdout().flush().ok();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {},
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (command, arg) = match line.split_once(' ') {
+            Some((c, a)) => (c, a.trim()),
+            None => (line, ""),
+        };
+        match command {
+            "break" | "b" => cmd_break(&mut session, arg),
+            "rbreak" => cmd_break_functions(&mut session, arg, true),
+            "delete" | "d" => cmd_delete(&mut session, arg),
+            "info" | "i" => match arg {
+                "breakpoints" | "b" => cmd_info_breakpoints(&session),
+                "regs" | "registers" | "r" => cmd_info_regs(&session),
+                "debug" => cmd_info_debug(&session),
+                _ if arg.starts_with("functions") => cmd_info_functions(&session, arg["functions".len()..].trim()),
+                _ if arg.starts_with("line") => cmd_info_line(&session, arg["line".len()..].trim()),
+                _ => println!("info breakpoints | regs | debug | functions | line"),
+            },
+            "step-columns" => match arg {
+                "on" | "" => STEP_COLUMNS.store(true, Ordering::Relaxed),
+                "off" => STEP_COLUMNS.store(false, Ordering::Relaxed),
+                _ => println!("step-columns on | off"),
+            },
+            "demangle" => match arg {
+                "on" | "" => SHOW_MANGLED.store(false, Ordering::Relaxed),
+                "off" => SHOW_MANGLED.store(true, Ordering::Relaxed),
+                _ => println!("demangle on | off"),
+            },
+            "run" | "r" => cmd_run(&mut session),
+            "continue" | "c" => cmd_continue(&mut session),
+            "step" | "s" => cmd_step(&mut session),
+            "stepi" | "si" => cmd_stepi(&mut session),
+            "finish" | "fin" => cmd_finish(&mut session),
+            "bt" | "backtrace" => cmd_bt(&session),
+            "print" | "p" => cmd_print(&session, arg),
+            "kill" | "k" => {
+                if let Some(run) = &mut session.active_run {
+                    run.kill();
+                }
+                session.retire_finished_run();
+            },
+            "quit" | "q" => break,
+            "help" | "h" => println!("{}", HELP),
+            _ => println!("Unknown command \"{}\", try help", command),
+        }
+    }
+    // Dropping the session kills whatever is still running
+}
+
+// Debug info shows up bit by bit from the worker thread, normally the imgui loop picks it up every frame
+fn wait_for_debug_info(session: &mut Session) {
+    let start = Instant::now();
+    let mut last_change = Instant::now();
+    let mut seen = (0, 0, false);
+    while start.elapsed() < DEBUG_INFO_TIMEOUT {
+        session.sync_workers();
+
+        let info = &session.debug_info;
+        let now = (info.src_files.len(), info.debug_info.src_file_info.len(), info.debug_info.decompiled_src.is_some());
+        if now != seen {
+            seen = now;
+            last_change = Instant::now();
+        }
+        if seen.2 && last_change.elapsed() > DEBUG_INFO_QUIET {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    println!("Gave up waiting for debug info");
+}
+
+fn active_run<'a, 'b>(session: &'b Session<'a>) -> Option<&'b Run> {
+    let run = session.active_run.as_ref();
+    if run.is_none() {
+        println!("The program is not being run");
+    }
+    run
+}
+
+// Lowest address on the line, or the next line that has any code
+fn line_addr(session: &Session, file: Option<&str>, line: usize) -> Option<(u64, usize)> {
+    let mut best: Option<(u64, usize)> = None;
+    for (hash, src_file) in &session.debug_info.src_files {
+        if let Some(file) = file {
+            let path = src_file.path.to_string_lossy();
+            if !path.ends_with(file) {
+                continue;
+            }
+        }
+
+        if let Some((l, addr)) = session.debug_info.debug_info.line_with_code(*hash, line) {
+            best = match best {
+                Some((best_addr, best_line)) if (best_line, best_addr) <= (l, addr) => Some((best_addr, best_line)),
+                _ => Some((addr, l)),
+            };
+        }
+    }
+
+    best
+}
+
+// (file hash, line) of the line table row at exactly this offline address
+fn line_start_at(session: &Session, addr: u64) -> Option<(u64, usize)> {
+    match session.debug_info.debug_info.location_at(addr) {
+        Some((hash, location)) if location.addr == addr => Some((hash, location.src_line)),
+        _ => None,
+    }
+}
+
+// (file hash, line) of the closest line table row at or before this offline address
+fn line_at(session: &Session, addr: u64) -> Option<(u64, usize)> {
+    session.debug_info.debug_info.location_at(addr).map(|(hash, location)| (hash, location.src_line))
+}
+
+fn file_name(session: &Session, hash: u64) -> String {
+    match session.debug_info.src_files.get(&hash) {
+        Some(f) => f.path.file_name().map_or("??".to_owned(), |n| n.to_string_lossy().into_owned()),
+        None => "??".to_owned(),
+    }
+}
+
+// Innermost inlined function if there's one
+fn function_name(session: &Session, addr: u64) -> String {
+    let info = &session.debug_info.debug_info;
+    let mangled = SHOW_MANGLED.load(Ordering::Relaxed);
+    match info.inlined_at(addr).first() {
+        Some((subprogram, _)) => subprogram.display_name(mangled).to_owned(),
+        None => info.subprogram_at(addr).map_or("??".to_owned(), |(_, s)| s.display_name(mangled).to_owned()),
+    }
+}
+
+// "func () at file.c:12" and the line itself, gdb style
+fn print_location(session: &Session, addr: u64) {
+    let offline = addr.wrapping_sub(LOAD_BIAS);
+    match session.debug_info.debug_info.location_at(offline) {
+        // Line 0 rows are code the compiler couldn't attribute to any line
+        Some((hash, location)) if location.src_line != 0 => {
+            let line = location.src_line;
+            println!("{} () at {}:{}", function_name(session, offline), file_name(session, hash), line);
+            let text = session.debug_info.src_files.get(&hash)
+                .and_then(|f| f.lines.as_ref())
+                .and_then(|lines| line.checked_sub(1).and_then(|i| lines.get(i)));
+            if let Some(text) = text {
+                println!("{}\t{}", line, text);
+                // Which statement of the line, with the same tabs so it lines up
+                if STEP_COLUMNS.load(Ordering::Relaxed) {
+                    let indent: String = text.chars().take(location.src_col.saturating_sub(1)).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+                    println!("\t{}^", indent);
+                }
+            }
+        },
+        _ => println!("0x{:x} in {} ()", addr, function_name(session, offline)),
+    }
+}
+
+fn report_stop(session: &Session) {
+    let run = match &session.active_run {
+        Some(r) => r,
+        None => {
+            if let Some(description) = session.finished_run.as_ref().and_then(|r| r.exit_description()) {
+                println!("{}", description);
+            }
+            return;
+        },
+    };
+
+    let rip = run.debugee_state.as_ref().map_or(0, |s| s.regs.rip);
+    match &run.debugee_event {
+        Some(DebugeeEvent::BreakpointHit{ id, addr }) => {
+            // Temporary ones (step, finish) are gone by now
+            if session.breakpoints.iter().any(|bp| bp.id == *id) {
+                print!("Breakpoint {}, ", id);
+            }
+            print_location(session, *addr);
+        },
+        Some(DebugeeEvent::StepComplete{ .. }) => print_location(session, rip),
+        Some(DebugeeEvent::SignalReceived{ signal }) => {
+            println!("Program received signal {}", signal);
+            print_location(session, rip);
+        },
+        Some(e) => {
+            println!("Stopped: {:?}", e);
+            print_location(session, rip);
+        },
+        None => {},
+    }
+}
+
+// Where the debugee is as far as the line table goes, see DebugeeState::addr
+fn current_pc(run: &Run) -> u64 {
+    run.debugee_state.as_ref().map_or(0, |s| s.addr)
+}
+
+fn cmd_break(session: &mut Session, arg: &str) {
+    // FILE:LINE:COLUMN, rsplitn gives them back to front
+    let parts: Vec<&str> = arg.rsplitn(3, ':').collect();
+    if let [col, line, file] = parts[..] {
+        if let (Ok(line), Ok(col)) = (line.parse::<usize>(), col.parse::<usize>()) {
+            return cmd_break_column(session, file, line, col);
+        }
+    }
+
+    let location = if let Some(addr) = arg.strip_prefix('*') {
+        u64::from_str_radix(addr.trim_start_matches("0x"), 16).ok().map(|a| (a, 0))
+    } else {
+        let (file, line) = match arg.rsplit_once(':') {
+            Some((f, l)) => (Some(f), l),
+            None => (None, arg),
+        };
+        match line.parse::<usize>() {
+            Ok(line) => line_addr(session, file, line),
+            // ns::foo has colons too
+            Err(_) if !arg.is_empty() => return cmd_break_functions(session, arg, false),
+            Err(_) => {
+                println!("break FILE:LINE | LINE | *ADDR | FUNCTION");
+                return;
+            },
+        }
+    };
+
+    let (addr, line) = match location {
+        Some(l) => l,
+        None => {
+            println!("No code at {}", arg);
+            return;
+        },
+    };
+
+    let bp = BreakPoint::new(Point::new(addr, line as u64));
+    match line {
+        // *ADDR, there might not be any line info at all
+        0 => println!("Breakpoint {} at 0x{:x} in {}", bp.id, addr, function_name(session, addr)),
+        _ => println!("Breakpoint {} at 0x{:x}, line {}", bp.id, addr, line),
+    }
+    session.add_breakpoint(bp);
+}
+
+// Source files whose path ends with file, or all of them
+fn src_file_hashes(session: &Session, file: Option<&str>) -> Vec<u64> {
+    session.debug_info.src_files.iter()
+        .filter(|(_, src_file)| file.map_or(true, |f| src_file.path.to_string_lossy().ends_with(f)))
+        .map(|(hash, _)| *hash)
+        .collect()
+}
+
+fn cmd_break_column(session: &mut Session, file: &str, line: usize, col: usize) {
+    let mut available = vec![];
+    for hash in src_file_hashes(session, Some(file)) {
+        let columns = session.debug_info.debug_info.columns_for_line(hash, line);
+        if let Some((_, addr)) = columns.iter().find(|(c, _)| *c == col) {
+            let bp = BreakPoint::new(Point::new_at_column(*addr, line as u64, col as u64));
+            println!("Breakpoint {} at 0x{:x}, line {} column {}", bp.id, addr, line, col);
+            session.add_breakpoint(bp);
+            return;
+        }
+        available.extend(columns.iter().map(|(c, _)| c.to_string()));
+    }
+
+    match available.is_empty() {
+        true => println!("No code at {}:{}", file, line),
+        false => println!("No code at column {} of {}:{}, try one of {}", col, file, line, available.join(", ")),
+    }
+}
+
+fn cmd_info_line(session: &Session, arg: &str) {
+    let (file, line) = match arg.rsplit_once(':') {
+        Some((f, l)) => (Some(f), l),
+        None => (None, arg),
+    };
+    let line = match line.parse::<usize>() {
+        Ok(l) => l,
+        Err(_) => {
+            println!("info line [FILE:]LINE");
+            return;
+        },
+    };
+
+    for hash in src_file_hashes(session, file) {
+        for (col, addr) in session.debug_info.debug_info.columns_for_line(hash, line) {
+            println!("{}:{}:{} at 0x{:x}", file_name(session, hash), line, col, addr);
+        }
+    }
+}
+
+fn cmd_break_functions(session: &mut Session, pattern: &str, regex: bool) {
+    let info = &session.debug_info.debug_info;
+    let functions = match info.find_functions(pattern, regex) {
+        Ok(f) => f,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        },
+    };
+    if functions.is_empty() {
+        println!("No function matching \"{}\" in the executable, shared libraries aren't searched", pattern);
+        return;
+    }
+
+    let mangled = SHOW_MANGLED.load(Ordering::Relaxed);
+    let mut bps = vec![];
+    for function in functions {
+        let addr = info.breakpoint_addr(function);
+        let line = line_at(session, addr);
+        let bp = BreakPoint::new(Point::new(addr, line.map_or(0, |(_, l)| l) as u64));
+        match line {
+            Some((hash, line)) => println!("Breakpoint {} at 0x{:x}: {} () at {}:{}", bp.id, addr, function.display_name(mangled), file_name(session, hash), line),
+            None => println!("Breakpoint {} at 0x{:x} in {}", bp.id, addr, function.display_name(mangled)),
+        }
+        bps.push(bp);
+    }
+    for bp in bps {
+        session.add_breakpoint(bp);
+    }
+}
+
+fn cmd_delete(session: &mut Session, arg: &str) {
+    let id = match arg.parse::<usize>() {
+        Ok(id) => id,
+        Err(_) => {
+            println!("delete ID");
+            return;
+        },
+    };
+
+    match session.breakpoints.iter().position(|bp| bp.id == id) {
+        Some(i) => {
+            session.breakpoints.remove(i);
+            session.reconcile_bp_state_with_run();
+        },
+        None => println!("No breakpoint {}", id),
+    }
+}
+
+fn cmd_info_breakpoints(session: &Session) {
+    if session.breakpoints.is_empty() {
+        println!("No breakpoints");
+        return;
+    }
+
+    println!("Id  Enabled  Address           Line");
+    for bp in &session.breakpoints {
+        let line = match (bp.point.line_number, bp.point.column) {
+            // Raw address
+            (0, _) => format!("in {}", function_name(session, bp.point.addr)),
+            (line, 0) => format!("{}", line),
+            (line, col) => format!("{}:{}", line, col),
+        };
+        println!("{:<3} {:<8} 0x{:<16x} {}", bp.id, if bp.point.enabled { "y" } else { "n" }, bp.point.addr, line);
+    }
+}
+
+fn cmd_info_debug(session: &Session) {
+    let info = &session.debug_info.debug_info;
+    println!("Debug info: {}", info.dwarf_source);
+    for path in &info.dwarf.missing_dwo {
+        println!("Missing split DWARF {}, only line info for it", path.display());
+    }
+}
+
+fn cmd_info_functions(session: &Session, text: &str) {
+    let mangled = SHOW_MANGLED.load(Ordering::Relaxed);
+    let mut functions: Vec<_> = session.debug_info.debug_info.all_subprograms.iter()
+        .filter(|s| s.high_addr > s.low_addr)
+        .filter(|s| s.name.contains(text) || s.linkage_name.as_ref().map_or(false, |l| l.contains(text)))
+        .collect();
+    functions.sort_by_key(|s| s.low_addr);
+    functions.dedup_by_key(|s| s.low_addr);
+
+    if functions.is_empty() {
+        println!("No functions matching \"{}\"", text);
+    }
+    for function in functions {
+        println!("0x{:<16x} {}", function.low_addr, function.display_name(mangled));
+    }
+}
+
+fn cmd_info_regs(session: &Session) {
+    let state = match active_run(session).and_then(|r| r.debugee_state.as_ref()) {
+        Some(s) => s,
+        None => return,
+    };
+
+    for name in USER_REGS_NAMES {
+        let value = user_reg(&state.regs, name).unwrap();
+        if name == "eflags" {
+            println!("{:<10}0x{:<18x}{}", name, value, decode_eflags(value));
+        } else {
+            println!("{:<10}0x{:<18x}{}", name, value, value as i64);
+        }
+    }
+}
+
+fn cmd_run(session: &mut Session) {
+    if let Some(run) = &mut session.active_run {
+        println!("Restarting");
+        run.kill();
+        session.retire_finished_run();
+    }
+
+    if let Err(e) = session.start_run() {
+        println!("Failed to start: {}", e);
+        return;
+    }
+    wait_and_report(session);
+}
+
+fn cmd_continue(session: &mut Session) {
+    match &mut session.active_run {
+        Some(run) => run.cont(),
+        None => {
+            println!("The program is not being run");
+            return;
+        },
+    }
+    wait_and_report(session);
+}
+
+fn cmd_stepi(session: &mut Session) {
+    match &mut session.active_run {
+        Some(run) => run.step(),
+        None => {
+            println!("The program is not being run");
+            return;
+        },
+    }
+    wait_and_report(session);
+}
+
+// Length of the call instruction at this offline address, None if it isn't one
+fn call_len_at(session: &Session, addr: u64) -> Option<u64> {
+    let decompiled = session.debug_info.debug_info.decompiled_src.as_ref()?;
+    let i = decompiled.addresses.binary_search(&addr).ok()?;
+    let instruction = &decompiled.instructions[i];
+    match instruction.flow_control() {
+        FlowControl::Call | FlowControl::IndirectCall => Some(instruction.len() as u64),
+        _ => None,
+    }
+}
+
+// Libraries, PLT stubs and symbol only functions. Offline address
+fn has_line_info(session: &Session, addr: u64) -> bool {
+    session.debug_info.debug_info.subprogram_at(addr).map_or(false, |(_, s)| s.src_file_hash != 0)
+}
+
+// Continues until the instruction at ret_addr (runtime) runs in the frame that made the call, sp is
+// rsp from right before the call. Uses a temporary breakpoint. False if anything else stopped it first
+fn run_to_return(session: &mut Session, ret_addr: u64, sp: u64) -> bool {
+    let bp = BreakPoint::new(Point::new(ret_addr.wrapping_sub(LOAD_BIAS), 0));
+    let id = bp.id;
+    session.add_breakpoint(bp);
+
+    let mut returned = false;
+    loop {
+        let run = session.active_run.as_mut().unwrap();
+        run.cont();
+        run.wait_for_stop();
+        let hit_ours = matches!(run.debugee_event, Some(DebugeeEvent::BreakpointHit{ id: hit, .. }) if hit == id);
+        if !hit_ours {
+            break;
+        }
+        // A deeper recursive call got back to the same address first
+        if run.debugee_state.as_ref().map_or(false, |s| s.regs.rsp >= sp) {
+            returned = true;
+            break;
+        }
+    }
+
+    session.breakpoints.retain(|bp| bp.id != id);
+    session.reconcile_bp_state_with_run();
+    returned
+}
+
+// Single steps until we're at the start of a different line, or in or out of an inlined call. Steps
+// into calls, unless there's no line info where they go, then it's straight back out
+fn cmd_step(session: &mut Session) {
+    let inline_depth = |session: &Session, pc: u64| session.debug_info.debug_info.dwarf.inlined_at(pc).len();
+    // Column is always 0 unless step-columns is on
+    let columns = STEP_COLUMNS.load(Ordering::Relaxed);
+    let column_at = |session: &Session, pc: u64| match columns {
+        true => session.debug_info.debug_info.location_at(pc).map_or(0, |(_, l)| l.src_col),
+        false => 0,
+    };
+    let start = match &session.active_run {
+        Some(run) => {
+            let pc = current_pc(run).wrapping_sub(LOAD_BIAS);
+            (line_at(session, pc), column_at(session, pc), inline_depth(session, pc))
+        },
+        None => {
+            println!("The program is not being run");
+            return;
+        },
+    };
+
+    set_snapshot_stops(session, false);
+    for _ in 0..MAX_STEP_INSTRUCTIONS {
+        let run = session.active_run.as_ref().unwrap();
+        let call_pc = current_pc(run);
+        let call_sp = run.debugee_state.as_ref().map_or(0, |s| s.regs.rsp);
+        let call_len = call_len_at(session, call_pc.wrapping_sub(LOAD_BIAS));
+
+        let run = session.active_run.as_mut().unwrap();
+        run.step();
+        run.wait_for_stop();
+        if !matches!(run.debugee_event, Some(DebugeeEvent::StepComplete{..})) {
+            break;
+        }
+
+        let callee = current_pc(run).wrapping_sub(LOAD_BIAS);
+        if let Some(len) = call_len {
+            // printf and friends, nothing to see in there
+            if !has_line_info(session, callee) && !run_to_return(session, call_pc + len, call_sp) {
+                break;
+            }
+        }
+
+        let run = session.active_run.as_ref().unwrap();
+        let pc = current_pc(run).wrapping_sub(LOAD_BIAS);
+        let now = line_start_at(session, pc);
+        if now.is_some() && (now, column_at(session, pc), inline_depth(session, pc)) != start {
+            break;
+        }
+    }
+    set_snapshot_stops(session, true);
+    session.retire_finished_run();
+    report_stop(session);
+}
+
+// Only the stop the command ends up at goes on the timeline, not every step on the way there
+fn set_snapshot_stops(session: &mut Session, on: bool) {
+    if let Some(run) = &mut session.active_run {
+        run.snapshot_stops = on;
+        if on {
+            run.snapshot();
+        }
+    }
+}
+
+// Frame pointer based, so only right at the entry (nothing pushed yet) or once rbp is set up
+fn cmd_finish(session: &mut Session) {
+    let (pc, regs) = match active_run(session).and_then(|r| r.debugee_state.as_ref()) {
+        Some(s) => (s.addr.wrapping_sub(LOAD_BIAS), s.regs),
+        None => {
+            println!("The program is not being run");
+            return;
+        },
+    };
+    let at_entry = match session.debug_info.debug_info.subprogram_at(pc) {
+        Some((_, subprogram)) => {
+            println!("Run till exit from {} ()", subprogram.display_name(SHOW_MANGLED.load(Ordering::Relaxed)));
+            subprogram.low_addr == pc
+        },
+        None => false,
+    };
+    // (where the return address is, rsp before the call)
+    let (slot, sp) = match at_entry {
+        true => (regs.rsp, regs.rsp + 8),
+        false => (regs.rbp + 8, regs.rbp + 16),
+    };
+    let ret_addr = match session.active_run.as_ref().unwrap().read_memory(slot, 8) {
+        Ok(bytes) if bytes.len() == 8 => u64::from_le_bytes(bytes[..].try_into().unwrap()),
+        _ => {
+            println!("Cannot access memory at 0x{:x}", slot);
+            return;
+        },
+    };
+
+    set_snapshot_stops(session, false);
+    run_to_return(session, ret_addr, sp);
+    set_snapshot_stops(session, true);
+    session.retire_finished_run();
+    report_stop(session);
+}
+
+fn wait_and_report(session: &mut Session) {
+    if let Some(run) = &mut session.active_run {
+        run.wait_for_stop();
+    }
+    session.retire_finished_run();
+    report_stop(session);
+}
+
+fn cmd_bt(session: &Session) {
+    let run = match active_run(session) {
+        Some(r) => r,
+        None => return,
+    };
+    let state = match &run.debugee_state {
+        Some(s) => s,
+        None => return,
+    };
+
+    let stack = walk_stack(|addr, len| run.read_memory(addr, len).ok(), state, &session.debug_info.debug_info);
+    for (i, frame) in stack.iter().enumerate() {
+        let location = match (&frame.location, frame.file_hash) {
+            (Some(l), Some(hash)) => format!(" at {}:{}", file_name(session, hash), l.src_line),
+            _ => "".to_owned(),
+        };
+        let inlined = match frame.inlined {
+            true => " [inlined]",
+            false => "",
+        };
+        println!("#{:<3} 0x{:016x} in {} (){}{}", i, frame.addr + LOAD_BIAS, frame.subprogram.display_name(SHOW_MANGLED.load(Ordering::Relaxed)), inlined, location);
+    }
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn cmd_print(session: &Session, arg: &str) {
+    if let Some(name) = arg.strip_prefix('$') {
+        let state = match active_run(session).and_then(|r| r.debugee_state.as_ref()) {
+            Some(s) => s,
+            None => return,
+        };
+        match user_reg(&state.regs, name) {
+            Some(value) => println!("${} = 0x{:x} ({})", name, value, value as i64),
+            None => println!("No register {}", name),
+        }
+        return;
+    }
+
+    if let Some(addr) = arg.strip_prefix('*') {
+        let run = match active_run(session) {
+            Some(r) => r,
+            None => return,
+        };
+        let addr = match parse_number(addr.trim()) {
+            Some(a) => a,
+            None => {
+                println!("print *ADDR");
+                return;
+            },
+        };
+        match run.read_memory(addr, 8) {
+            Ok(bytes) if bytes.len() == 8 => {
+                let value = u64::from_le_bytes(bytes[..].try_into().unwrap());
+                println!("*0x{:x} = 0x{:x} ({})", addr, value, value as i64);
+            },
+            _ => println!("Cannot access memory at 0x{:x}", addr),
+        }
+        return;
+    }
+
+    match parse_number(arg) {
+        Some(value) => println!("0x{:x} ({})", value, value),
+        None => print_variable(session, arg),
+    }
+}
+
+// Locals need the frame, so it's only right once the prologue is done, which is where function
+// breakpoints stop anyway
+fn print_variable(session: &Session, name: &str) {
+    let state = match active_run(session).and_then(|r| r.debugee_state.as_ref()) {
+        Some(s) => s,
+        None => return,
+    };
+    let pc = state.addr.wrapping_sub(LOAD_BIAS);
+    let (unit, variable, location) = match session.debug_info.debug_info.dwarf.find_variable(pc, name) {
+        Some(v) => v,
+        None => {
+            println!("No symbol \"{}\" in current context", name);
+            return;
+        },
+    };
+
+    let addr = match location {
+        Some(location) => location.runtime_addr(state.regs.rbp, LOAD_BIAS),
+        None => {
+            println!("Can't tell where \"{}\" is, it might be optimized out", name);
+            return;
+        },
+    };
+    let var_type = unit.resolve_type(variable.type_offset);
+    let size = value_size(var_type);
+    match session.active_run.as_ref().unwrap().read_memory(addr, size) {
+        Ok(bytes) if bytes.len() == size => println!("{} = {}", name, format_value(var_type, &bytes)),
+        _ => println!("Cannot access memory at 0x{:x}", addr),
+    }
+}
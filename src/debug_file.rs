@@ -0,0 +1,137 @@
+use std::fmt;
+use std::path::{ Path, PathBuf };
+
+use object::{ Object, ObjectSection };
+
+use crate::debuginfod::Debuginfod;
+
+// Where distros put separate debug info
+const GLOBAL_DEBUG_DIR: &str = "/usr/lib/debug";
+
+#[derive(Debug, Clone)]
+pub enum DebugInfoSource {
+    // DWARF is in the executable itself
+    Embedded,
+    // <debug dir>/.build-id/xx/yyyy.debug
+    BuildId(PathBuf),
+    // Found through .gnu_debuglink, CRC matched
+    DebugLink(PathBuf),
+    // Fetched by build-id, this is the cached copy
+    Debuginfod(PathBuf),
+    Missing,
+}
+
+impl fmt::Display for DebugInfoSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebugInfoSource::Embedded => write!(f, "embedded in the executable"),
+            DebugInfoSource::BuildId(path) => write!(f, "{} (build-id)", path.display()),
+            DebugInfoSource::DebugLink(path) => write!(f, "{} (debuglink)", path.display()),
+            DebugInfoSource::Debuginfod(path) => write!(f, "{} (debuginfod)", path.display()),
+            DebugInfoSource::Missing => write!(f, "not found"),
+        }
+    }
+}
+
+pub fn has_dwarf(bin_data: &[u8]) -> bool {
+    let object = match object::File::parse(bin_data) {
+        Ok(o) => o,
+        Err(_) => return false,
+    };
+
+    // Also finds .zdebug_info
+    match object.section_by_name(".debug_info") {
+        Some(section) => section.size() > 0,
+        None => false,
+    }
+}
+
+pub fn build_id(bin_data: &[u8]) -> Option<Vec<u8>> {
+    let object = object::File::parse(bin_data).ok()?;
+    match object.build_id() {
+        Ok(Some(id)) if id.len() > 1 => Some(id.to_vec()),
+        _ => None,
+    }
+}
+
+// Same lookup order as gdb: the executable itself, build-id, debuglink, then debuginfod.
+// debug_dirs are searched before the global one
+pub fn find_debug_file(exec_path: &Path, bin_data: &[u8], debug_dirs: &Vec<PathBuf>, debuginfod: Option<&Debuginfod>) -> (DebugInfoSource, Option<Vec<u8>>) {
+    if has_dwarf(bin_data) {
+        return (DebugInfoSource::Embedded, None);
+    }
+
+    let object = match object::File::parse(bin_data) {
+        Ok(o) => o,
+        Err(_) => return (DebugInfoSource::Missing, None),
+    };
+
+    let mut roots = debug_dirs.clone();
+    roots.push(PathBuf::from(GLOBAL_DEBUG_DIR));
+
+    let build_id = build_id(bin_data);
+    if let Some(build_id) = &build_id {
+        let hex: String = build_id.iter().map(|b| format!("{:02x}", b)).collect();
+        for root in &roots {
+            let path = root.join(".build-id").join(&hex[..2]).join(format!("{}.debug", &hex[2..]));
+            if let Some(data) = read_debug_file(&path) {
+                return (DebugInfoSource::BuildId(path), Some(data));
+            }
+        }
+    }
+
+    if let Ok(Some((name, crc))) = object.gnu_debuglink() {
+        let name = String::from_utf8_lossy(name).into_owned();
+        let exec_path = exec_path.canonicalize().unwrap_or(exec_path.to_path_buf());
+        let exec_dir = exec_path.parent().unwrap_or(Path::new("/"));
+
+        let mut candidates = vec![exec_dir.join(&name), exec_dir.join(".debug").join(&name)];
+        for root in &roots {
+            // <root>/<exec dir>/<name>, the exec dir is absolute so it can't be joined directly
+            candidates.push(root.join(exec_dir.strip_prefix("/").unwrap_or(exec_dir)).join(&name));
+            candidates.push(root.join(&name));
+        }
+
+        for path in candidates {
+            // The debuglink can have the same name as the exec
+            if path == exec_path {
+                continue;
+            }
+            let data = match read_debug_file(&path) {
+                Some(d) => d,
+                None => continue,
+            };
+
+            if crc32fast::hash(&data) != crc {
+                println!("Ignoring {}, debuglink CRC doesn't match", path.display());
+                continue;
+            }
+            return (DebugInfoSource::DebugLink(path), Some(data));
+        }
+    }
+
+    if let (Some(build_id), Some(debuginfod)) = (&build_id, debuginfod) {
+        if let Some(path) = debuginfod.debuginfo(build_id) {
+            if let Some(data) = read_debug_file(&path) {
+                return (DebugInfoSource::Debuginfod(path), Some(data));
+            }
+        }
+    }
+
+    (DebugInfoSource::Missing, None)
+}
+
+fn read_debug_file(path: &Path) -> Option<Vec<u8>> {
+    if !path.is_file() {
+        return None;
+    }
+
+    let data = std::fs::read(path).ok()?;
+    match has_dwarf(&data) {
+        true => Some(data),
+        false => {
+            println!("Ignoring {}, it has no DWARF", path.display());
+            None
+        },
+    }
+}
@@ -0,0 +1,310 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io::{ Read, Write };
+use std::net::{ TcpStream, ToSocketAddrs };
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+
+// debuginfod client. Servers come from DEBUGINFOD_URLS (space separated), everything fetched is kept
+// in the cache dir and served from there without touching the network again.
+// Only plain http, https servers get dropped with a warning. Anything a server said it doesn't have
+// isn't asked for again for the rest of the session.
+// A local stand-in is just a directory with the same layout served over http, e.g.
+//   srv/buildid/<build id>/debuginfo
+//   srv/buildid/<build id>/source/root/project/main.c
+//   python3 -m http.server -d srv 8002 & DEBUGINFOD_URLS=http://localhost:8002 degrugger-cli ./main
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 4;
+
+pub struct Debuginfod {
+    urls: Vec<String>,
+    cache_dir: PathBuf,
+    // Full URLs that were a 404
+    not_found: RefCell<HashSet<String>>,
+}
+
+impl Debuginfod {
+    pub fn from_env() -> Self {
+        let mut urls: Vec<String> = match std::env::var("DEBUGINFOD_URLS") {
+            Ok(urls) => urls.split_whitespace().map(|u| u.trim_end_matches('/').to_owned()).collect(),
+            Err(_) => vec![],
+        };
+        // No TLS
+        urls.retain(|url| match url.starts_with("http://") {
+            true => true,
+            false => {
+                println!("debuginfod: only http:// servers are supported, ignoring {}", url);
+                false
+            },
+        });
+
+        let cache_dir = match std::env::var("DEBUGINFOD_CACHE_PATH") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => {
+                let cache = match std::env::var("XDG_CACHE_HOME") {
+                    Ok(path) => PathBuf::from(path),
+                    Err(_) => PathBuf::from(std::env::var("HOME").unwrap_or("/tmp".to_owned())).join(".cache"),
+                };
+                cache.join("degrugger").join("debuginfod")
+            },
+        };
+
+        Debuginfod{ urls: urls, cache_dir: cache_dir, not_found: RefCell::new(HashSet::new()) }
+    }
+
+    pub fn debuginfo(&self, build_id: &[u8]) -> Option<PathBuf> {
+        let build_id = hex(build_id);
+        self.fetch(&build_id, "debuginfo", "debuginfo")
+    }
+
+    // path is the absolute path from the DWARF
+    pub fn source(&self, build_id: &[u8], path: &Path) -> Option<PathBuf> {
+        let build_id = hex(build_id);
+        let path = path.to_string_lossy();
+        // Flattened so every source file of a build-id sits in the same dir
+        let cache_name = format!("source{}", path.replace('/', "#"));
+        self.fetch(&build_id, &format!("source{}", escape_path(&path)), &cache_name)
+    }
+
+    fn fetch(&self, build_id: &str, kind: &str, cache_name: &str) -> Option<PathBuf> {
+        let cached = self.cache_dir.join(build_id).join(cache_name);
+        if cached.is_file() {
+            return Some(cached);
+        }
+
+        for url in &self.urls {
+            let url = format!("{}/buildid/{}/{}", url, build_id, kind);
+            if self.not_found.borrow().contains(&url) {
+                continue;
+            }
+            println!("debuginfod: fetching {}", url);
+            let data = match http_get(&url) {
+                Ok(Some(d)) => d,
+                Ok(None) => {
+                    println!("debuginfod: {} not found", url);
+                    self.not_found.borrow_mut().insert(url);
+                    continue;
+                },
+                Err(e) => {
+                    println!("debuginfod: {}", e);
+                    continue;
+                },
+            };
+
+            // Written next to it first, so a half written file never looks like a cache hit
+            let tmp = self.cache_dir.join(build_id).join(format!("{}.part", cache_name));
+            let stored = std::fs::create_dir_all(cached.parent().unwrap())
+                .and_then(|_| std::fs::write(&tmp, &data))
+                .and_then(|_| std::fs::rename(&tmp, &cached));
+            if let Err(e) = stored {
+                println!("debuginfod: can't cache {}: {}", cached.display(), e);
+                continue;
+            }
+            return Some(cached);
+        }
+
+        None
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn escape_path(path: &str) -> String {
+    let mut escaped = String::new();
+    for b in path.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => escaped.push(b as char),
+            _ => escaped.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    escaped
+}
+
+// Just enough HTTP/1.1 for debuginfod. No TLS, so https servers get skipped. None for a 404
+fn http_get(url: &str) -> Result<Option<Vec<u8>>, String> {
+    let mut url = url.to_owned();
+    for _ in 0..=MAX_REDIRECTS {
+        let rest = url.strip_prefix("http://").ok_or(format!("only http:// is supported, skipping {}", url))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let addr = match host.contains(':') {
+            true => host.to_owned(),
+            false => format!("{}:80", host),
+        };
+
+        let socket_addr = addr.to_socket_addrs().map_err(|e| format!("{}: {}", host, e))?
+            .next().ok_or(format!("{}: no address", host))?;
+        let mut stream = TcpStream::connect_timeout(&socket_addr, CONNECT_TIMEOUT).map_err(|e| format!("{}: {}", host, e))?;
+        stream.set_read_timeout(Some(READ_TIMEOUT)).ok();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: degrugger\r\nAccept-Encoding: identity\r\nConnection: close\r\n\r\n", path, host)
+            .map_err(|e| format!("{}: {}", host, e))?;
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response).map_err(|e| format!("{}: {}", url, e))?;
+
+        let header_end = response.windows(4).position(|w| w == b"\r\n\r\n").ok_or(format!("{}: broken response", url))?;
+        let header = String::from_utf8_lossy(&response[..header_end]).into_owned();
+        let body = &response[header_end + 4..];
+
+        let mut lines = header.split("\r\n");
+        let status: u32 = lines.next().and_then(|l| l.split_whitespace().nth(1)).and_then(|s| s.parse().ok())
+            .ok_or(format!("{}: broken status line", url))?;
+        let mut location = None;
+        let mut content_length = None;
+        let mut chunked = false;
+        for line in lines {
+            let (name, value) = match line.split_once(':') {
+                Some((n, v)) => (n.trim().to_ascii_lowercase(), v.trim()),
+                None => continue,
+            };
+            match name.as_str() {
+                "location" => location = Some(value.to_owned()),
+                "content-length" => content_length = value.parse::<usize>().ok(),
+                "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                _ => {},
+            }
+        }
+
+        match status {
+            200 => {},
+            404 => return Ok(None),
+            301 | 302 | 303 | 307 | 308 => {
+                let location = location.ok_or(format!("{}: redirect without a location", url))?;
+                url = match location.starts_with('/') {
+                    true => format!("http://{}{}", host, location),
+                    false => location,
+                };
+                continue;
+            },
+            _ => return Err(format!("{}: HTTP {}", url, status)),
+        }
+
+        if chunked {
+            return dechunk(body).map(Some).ok_or(format!("{}: broken chunked body", url));
+        }
+        return match content_length {
+            Some(len) if body.len() < len => Err(format!("{}: connection closed after {} of {} bytes", url, body.len(), len)),
+            Some(len) => Ok(Some(body[..len].to_vec())),
+            None => Ok(Some(body.to_vec())),
+        };
+    }
+
+    Err(format!("{}: too many redirects", url))
+}
+
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut data = vec![];
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size_str = String::from_utf8_lossy(&body[..line_end]).into_owned();
+        // Chunk extensions after ';' don't matter
+        let size = usize::from_str_radix(size_str.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(data);
+        }
+        if body.len() < size + 2 {
+            return None;
+        }
+
+        data.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::{ Arc, Mutex };
+
+    // Stand-in server on a random port. Paths it was asked for, in order
+    fn serve() -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let log = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = vec![];
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).into_owned();
+                let path = request.split_whitespace().nth(1).unwrap_or("").to_owned();
+                log.lock().unwrap().push(path.clone());
+
+                let response: &[u8] = match path.as_str() {
+                    "/buildid/abcd/debuginfo" => b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nELF!!",
+                    "/buildid/abcd/source/src/main.c" => b"HTTP/1.1 302 Found\r\nLocation: /moved/main.c\r\nContent-Length: 0\r\n\r\n",
+                    "/moved/main.c" => b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nint \r\n7\r\nmain();\r\n0\r\n\r\n",
+                    _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+                };
+                stream.write_all(response).ok();
+            }
+        });
+        (url, requests)
+    }
+
+    fn client(url: String, name: &str) -> Debuginfod {
+        let cache_dir = std::env::temp_dir().join(format!("degrugger-debuginfod-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&cache_dir).ok();
+        Debuginfod{ urls: vec![url], cache_dir: cache_dir, not_found: RefCell::new(HashSet::new()) }
+    }
+
+    #[test]
+    fn fetched_then_cached() {
+        let (url, requests) = serve();
+        let debuginfod = client(url, "cached");
+
+        let path = debuginfod.debuginfo(&[0xab, 0xcd]).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"ELF!!");
+        let path = debuginfod.source(&[0xab, 0xcd], Path::new("/src/main.c")).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"int main();");
+        assert_eq!(requests.lock().unwrap().len(), 3);
+
+        // Straight from the cache dir
+        assert!(debuginfod.debuginfo(&[0xab, 0xcd]).is_some());
+        assert!(debuginfod.source(&[0xab, 0xcd], Path::new("/src/main.c")).is_some());
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        std::fs::remove_dir_all(&debuginfod.cache_dir).ok();
+    }
+
+    #[test]
+    fn not_found_asked_once() {
+        let (url, requests) = serve();
+        let debuginfod = client(url, "not-found");
+
+        assert!(debuginfod.source(&[0xab, 0xcd], Path::new("/src/other file.c")).is_none());
+        assert!(debuginfod.source(&[0xab, 0xcd], Path::new("/src/other file.c")).is_none());
+        assert!(debuginfod.debuginfo(&[0xef]).is_none());
+        assert_eq!(*requests.lock().unwrap(), vec!["/buildid/abcd/source/src/other%20file.c", "/buildid/ef/debuginfo"]);
+    }
+
+    #[test]
+    fn chunked_body() {
+        assert_eq!(dechunk(b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\n\r\n"), Some(b"Wikipedia".to_vec()));
+        assert_eq!(dechunk(b"0\r\n\r\n"), Some(vec![]));
+        // Cut off
+        assert_eq!(dechunk(b"a\r\nshort\r\n"), None);
+        assert_eq!(dechunk(b"zz\r\n"), None);
+    }
+
+    #[test]
+    fn paths_escaped() {
+        assert_eq!(escape_path("/usr/src/foo-1.0/a_b.c"), "/usr/src/foo-1.0/a_b.c");
+        assert_eq!(escape_path("/src/my file.c"), "/src/my%20file.c");
+        assert_eq!(escape_path("/src/c++/ä.c"), "/src/c%2B%2B/%C3%A4.c");
+    }
+}
@@ -0,0 +1,918 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+
+use gimli::{ AttributeValue, DwAte, DwTag, DwoId, EndianSlice, LittleEndian, Reader, ReaderOffset, RunTimeEndian, SectionId };
+use object::{ Object, ObjectSection };
+
+use crate::offline_debug_info::{ BreakableSrcLocation, Subprogram };
+use crate::symbols::demangle;
+
+type OfflineAddr = u64;
+type Slice<'a> = EndianSlice<'a, RunTimeEndian>;
+
+// Everything we want out of the DWARF sections, parsed once and owned, so nothing has to go back to
+// the raw sections (and gimli's lifetimes) after loading
+pub struct DwarfModel {
+    pub units: Vec<CompileUnit>,
+    // Source file -> (unit, file index) of every file table entry that resolves to it
+    pub files: HashMap<PathBuf, Vec<(usize, u64)>>,
+    // Split DWARF files skeleton units point at that we couldn't find. Those units only have line info
+    pub missing_dwo: Vec<PathBuf>,
+    // (low, high, unit, index into the unit's inlined) of every inlined range, sorted by low
+    inlined_ranges: Vec<(OfflineAddr, OfflineAddr, usize, usize)>,
+    // Longest one, so lookups know how far back to look
+    max_inlined_len: u64,
+}
+
+pub struct CompileUnit {
+    pub name: String,
+    pub comp_dir: PathBuf,
+    // Indexed by DWARF file index. 0 is only a real file from DWARF 5 on
+    pub files: Vec<Option<PathBuf>>,
+    // Line program, in the order it was encoded
+    pub rows: Vec<LineRow>,
+    pub subprograms: Vec<DwarfSubprogram>,
+    pub inlined: Vec<InlinedCall>,
+    pub blocks: Vec<LexicalBlock>,
+    pub variables: Vec<Variable>,
+    // By unit offset of the DIE, which is what DW_AT_type points at
+    pub types: HashMap<usize, Type>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LineRow {
+    pub addr: OfflineAddr,
+    // Index into the unit's files
+    pub file: u64,
+    // 0 if the row has no line
+    pub line: usize,
+    // 0 is the left edge
+    pub col: usize,
+    pub is_stmt: bool,
+    pub prologue_end: bool,
+    // First address past a sequence, there's no instruction here
+    pub end_sequence: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct DwarfSubprogram {
+    // Qualified with the namespaces and classes it's in, C++ style
+    pub name: String,
+    // Mangled
+    pub linkage_name: Option<String>,
+    // The range with the entry point. Optimized code can have more, e.g. a foo.cold part
+    pub low_addr: OfflineAddr,
+    pub high_addr: OfflineAddr,
+    // [low, high) of all of them, entry range included
+    pub ranges: Vec<(OfflineAddr, OfflineAddr)>,
+    pub decl_file: Option<PathBuf>,
+    pub decl_line: usize,
+    // Abstract instance of an inlined function (DW_AT_inline), no code of its own
+    pub inline: bool,
+    // Indices into the unit's variables
+    pub variables: Vec<usize>,
+    // DW_AT_frame_base, what DW_OP_fbreg is relative to
+    pub frame_base: Option<Vec<u8>>,
+}
+
+// DW_TAG_inlined_subroutine, a function body copied into its caller
+#[derive(Debug, Clone)]
+pub struct InlinedCall {
+    // Index into the unit's subprograms, the abstract instance with the name
+    pub origin: Option<usize>,
+    // [low, high), there's usually more than one with optimizations on
+    pub ranges: Vec<(OfflineAddr, OfflineAddr)>,
+    // Where it got inlined, in the caller
+    pub call_file: Option<PathBuf>,
+    pub call_line: usize,
+    pub call_col: usize,
+    // Index into the unit's inlined, for calls inlined into an inlined function
+    pub parent: Option<usize>,
+}
+
+// DW_TAG_lexical_block, the scope of the variables declared in it
+#[derive(Debug, Clone)]
+pub struct LexicalBlock {
+    pub ranges: Vec<(OfflineAddr, OfflineAddr)>,
+    // Index into the unit's blocks
+    pub parent: Option<usize>,
+}
+
+// One level of inlining at an address, see DwarfModel::inlined_at
+#[derive(Debug, Clone)]
+pub struct InlinedFrame {
+    pub name: String,
+    pub linkage_name: Option<String>,
+    pub decl_file: Option<PathBuf>,
+    pub decl_line: usize,
+    // The range containing the address
+    pub low_addr: OfflineAddr,
+    pub high_addr: OfflineAddr,
+    pub ranges: Vec<(OfflineAddr, OfflineAddr)>,
+    pub call_file: Option<PathBuf>,
+    pub call_line: usize,
+    pub call_col: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Variable {
+    pub name: String,
+    pub parameter: bool,
+    // Unit offset, key into the unit's types
+    pub type_offset: Option<usize>,
+    pub decl_file: Option<PathBuf>,
+    pub decl_line: usize,
+    // DW_AT_location if it's a single expression. Location lists aren't kept yet
+    pub location: Option<Vec<u8>>,
+    // Index into the unit's subprograms, None for globals
+    pub subprogram: Option<usize>,
+    // Index into the unit's blocks, None if it's visible in the whole subprogram
+    pub block: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Type {
+    pub tag: DwTag,
+    pub name: Option<String>,
+    pub byte_size: Option<u64>,
+    // Pointee, element, typedef'd type etc. Unit offset
+    pub inner: Option<usize>,
+    // Base types only, signed/float etc
+    pub encoding: Option<DwAte>,
+}
+
+// Where a variable lives, for the handful of expressions unoptimized code uses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VariableLocation {
+    // DW_OP_addr, offline address
+    Static(OfflineAddr),
+    // Offset from the CFA, rbp + 16 once the prologue has pushed rbp
+    Cfa(i64),
+    Rbp(i64),
+}
+
+impl VariableLocation {
+    // Needs the frame, so it's only right once the prologue is done
+    pub fn runtime_addr(&self, rbp: u64, load_bias: u64) -> u64 {
+        match self {
+            VariableLocation::Static(addr) => addr + load_bias,
+            VariableLocation::Cfa(offset) => (rbp + 16).wrapping_add(*offset as u64),
+            VariableLocation::Rbp(offset) => rbp.wrapping_add(*offset as u64),
+        }
+    }
+}
+
+// How much of a variable to read for format_value. Big structs get cut off, it's a debugger not a
+// hex editor
+pub fn value_size(var_type: Option<&Type>) -> usize {
+    var_type.and_then(|t| t.byte_size).unwrap_or(8).min(64) as usize
+}
+
+pub fn format_value(var_type: Option<&Type>, bytes: &[u8]) -> String {
+    let mut word = [0u8; 8];
+    let n = bytes.len().min(8);
+    word[..n].copy_from_slice(&bytes[..n]);
+    let raw = u64::from_le_bytes(word);
+
+    match var_type.map(|t| (t.tag, t.encoding)) {
+        Some((gimli::DW_TAG_pointer_type, _)) | Some((gimli::DW_TAG_reference_type, _)) | Some((gimli::DW_TAG_rvalue_reference_type, _)) => format!("0x{:x}", raw),
+        Some((gimli::DW_TAG_base_type, Some(gimli::DW_ATE_float))) if n == 4 => format!("{}", f32::from_bits(raw as u32)),
+        Some((gimli::DW_TAG_base_type, Some(gimli::DW_ATE_float))) if n == 8 => format!("{}", f64::from_bits(raw)),
+        Some((gimli::DW_TAG_base_type, Some(gimli::DW_ATE_boolean))) => format!("{}", raw != 0),
+        Some((gimli::DW_TAG_base_type, Some(gimli::DW_ATE_signed))) | Some((gimli::DW_TAG_base_type, Some(gimli::DW_ATE_signed_char))) | Some((gimli::DW_TAG_enumeration_type, _)) if n > 0 => {
+            // Sign extend from however wide it is
+            let shift = 64 - 8 * n as u32;
+            format!("{}", ((raw << shift) as i64) >> shift)
+        },
+        Some((gimli::DW_TAG_base_type, _)) => format!("{}", raw),
+        // Structs, arrays etc
+        _ => format!("{{ {} }}", bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")),
+    }
+}
+
+// Their names end up in front of the names of the functions inside of them
+const SCOPE_TAGS: [DwTag; 4] = [
+    gimli::DW_TAG_namespace,
+    gimli::DW_TAG_structure_type,
+    gimli::DW_TAG_class_type,
+    gimli::DW_TAG_union_type,
+];
+
+const TYPE_TAGS: [DwTag; 13] = [
+    gimli::DW_TAG_base_type,
+    gimli::DW_TAG_pointer_type,
+    gimli::DW_TAG_reference_type,
+    gimli::DW_TAG_rvalue_reference_type,
+    gimli::DW_TAG_const_type,
+    gimli::DW_TAG_volatile_type,
+    gimli::DW_TAG_typedef,
+    gimli::DW_TAG_structure_type,
+    gimli::DW_TAG_union_type,
+    gimli::DW_TAG_class_type,
+    gimli::DW_TAG_enumeration_type,
+    gimli::DW_TAG_array_type,
+    gimli::DW_TAG_subroutine_type,
+];
+
+impl DwarfModel {
+    pub fn empty() -> Self {
+        DwarfModel{ units: vec![], files: HashMap::new(), missing_dwo: vec![], inlined_ranges: vec![], max_inlined_len: 0 }
+    }
+
+    // Functions inlined at addr, innermost first. Empty if addr isn't in inlined code
+    pub fn inlined_at(&self, addr: OfflineAddr) -> Vec<InlinedFrame> {
+        let mut frames = vec![];
+
+        // Nested calls are inside of their parent, so the innermost one starts last
+        let end = self.inlined_ranges.partition_point(|(low, _, _, _)| *low <= addr);
+        let innermost = self.inlined_ranges[..end].iter().rev()
+            .take_while(|(low, _, _, _)| addr - low < self.max_inlined_len)
+            .filter(|(low, high, _, _)| *low <= addr && addr < *high)
+            .max_by_key(|(low, _, unit, i)| (*low, self.inlined_depth(*unit, *i)));
+        let (low_addr, high_addr, unit_index, mut i) = match innermost {
+            Some(r) => *r,
+            None => return frames,
+        };
+
+        let unit = &self.units[unit_index];
+        let (mut low_addr, mut high_addr) = (low_addr, high_addr);
+        loop {
+            let call = &unit.inlined[i];
+            let origin = call.origin.map(|o| &unit.subprograms[o]);
+            frames.push(InlinedFrame{
+                name: origin.map_or("??".to_owned(), |o| o.name.clone()),
+                linkage_name: origin.and_then(|o| o.linkage_name.clone()),
+                decl_file: origin.and_then(|o| o.decl_file.clone()),
+                decl_line: origin.map_or(0, |o| o.decl_line),
+                low_addr: low_addr,
+                high_addr: high_addr,
+                ranges: call.ranges.clone(),
+                call_file: call.call_file.clone(),
+                call_line: call.call_line,
+                call_col: call.call_col,
+            });
+
+            i = match call.parent {
+                Some(p) => p,
+                None => break,
+            };
+            // The parent's range that contains addr
+            match unit.inlined[i].ranges.iter().find(|(low, high)| *low <= addr && addr < *high) {
+                Some((low, high)) => (low_addr, high_addr) = (*low, *high),
+                None => break,
+            }
+        }
+
+        frames
+    }
+
+    // Innermost variable called name in scope at addr, otherwise a global. Its unit has the types
+    pub fn find_variable(&self, addr: OfflineAddr, name: &str) -> Option<(&CompileUnit, &Variable, Option<VariableLocation>)> {
+        if let Some(local) = self.locals_at(addr).into_iter().find(|(_, v, _)| v.name == name) {
+            return Some(local);
+        }
+
+        for unit in &self.units {
+            if let Some(variable) = unit.variables.iter().find(|v| v.subprogram.is_none() && v.name == name) {
+                let location = variable.location.as_deref().and_then(|expr| variable_location(expr, None));
+                return Some((unit, variable, location));
+            }
+        }
+        None
+    }
+
+    // Parameters and locals of the function around addr that are in scope there, in declaration
+    // order. Shadowed ones are left out
+    pub fn locals_at(&self, addr: OfflineAddr) -> Vec<(&CompileUnit, &Variable, Option<VariableLocation>)> {
+        for unit in &self.units {
+            let function = unit.subprograms.iter().enumerate()
+                .find(|(_, s)| !s.inline && s.ranges.iter().any(|(low, high)| *low <= addr && addr < *high));
+            let (i, subprogram) = match function {
+                Some(f) => f,
+                None => continue,
+            };
+
+            let variables = unit.variables_at(i, addr);
+            return variables.iter()
+                // Deepest block wins, that's the one shadowing the others
+                .filter(|v| !variables.iter().any(|other| other.name == v.name && unit.block_depth(other.block) > unit.block_depth(v.block)))
+                .map(|v| (unit, *v, v.location.as_deref().and_then(|expr| variable_location(expr, subprogram.frame_base.as_deref()))))
+                .collect();
+        }
+        vec![]
+    }
+
+    fn inlined_depth(&self, unit: usize, mut i: usize) -> usize {
+        let mut depth = 0;
+        while let Some(parent) = self.units[unit].inlined[i].parent {
+            depth += 1;
+            i = parent;
+        }
+        depth
+    }
+
+    // exec_path is only used to find <exec>.dwp, bin_data can be a separate debug file
+    pub fn parse(bin_data: &[u8], exec_path: &Path) -> Result<Self, ()> {
+        let object = object::File::parse(bin_data).map_err(|e| println!("Can't parse executable: {}", e))?;
+        let endian = if object.is_little_endian() { RunTimeEndian::Little } else { RunTimeEndian::Big };
+
+        let load_section = |id: SectionId| -> Result<Cow<[u8]>, gimli::Error> {
+            Ok(section_data(&object, id, false))
+        };
+        // Closure needs the explicit type, otherwise it can't be generic over the lifetime
+        let borrow_section: &dyn for<'b> Fn(&'b Cow<[u8]>) -> EndianSlice<'b, RunTimeEndian> =
+            &|section| EndianSlice::new(&*section, endian);
+
+        let dwarf_cow = gimli::Dwarf::load(&load_section).map_err(|e| println!("Can't load DWARF: {}", e))?;
+        let dwarf = dwarf_cow.borrow(&borrow_section);
+
+        // All the .dwo files of the exec packed into one
+        let mut dwp_path = exec_path.as_os_str().to_owned();
+        dwp_path.push(".dwp");
+        let dwp_data = std::fs::read(&dwp_path).ok();
+        let dwp_object = dwp_data.as_ref().and_then(|data| object::File::parse(&**data).ok());
+        // DwarfPackage wants readers straight away, so the sections have to be loaded up front
+        let dwp_sections: HashMap<SectionId, Cow<[u8]>> = match &dwp_object {
+            Some(o) => DWP_SECTIONS.iter().map(|id| (*id, section_data(o, *id, true))).collect(),
+            None => HashMap::new(),
+        };
+        let dwp = match dwp_object {
+            Some(_) => {
+                let load_dwp_section = |id: SectionId| -> Result<Slice, gimli::Error> {
+                    Ok(EndianSlice::new(dwp_sections.get(&id).map_or(&[][..], |data| &**data), endian))
+                };
+                match gimli::DwarfPackage::load(load_dwp_section, EndianSlice::new(&[][..], endian)) {
+                    Ok(p) => {
+                        println!("Using split DWARF package {}", Path::new(&dwp_path).display());
+                        Some(p)
+                    },
+                    Err(e) => {
+                        println!("Can't load split DWARF package {}: {}", Path::new(&dwp_path).display(), e);
+                        None
+                    },
+                }
+            },
+            None => None,
+        };
+
+        let mut model = Self::empty();
+        let mut iter = dwarf.units();
+        loop {
+            let header = match iter.next() {
+                Ok(Some(h)) => h,
+                Ok(None) => break,
+                Err(e) => {
+                    println!("Broken unit header, skipping the rest: {}", e);
+                    break;
+                },
+            };
+            let unit = match dwarf.unit(header) {
+                Ok(u) => u,
+                Err(e) => {
+                    println!("Skipping broken unit: {}", e);
+                    continue;
+                },
+            };
+            let unit = match dwo_path(&dwarf, &unit) {
+                Ok(Some(path)) => match parse_split_unit(&dwarf, &unit, &path, dwp.as_ref(), endian) {
+                    Ok(Some(u)) => Ok(u),
+                    Ok(None) => {
+                        println!("WARNING: can't find split DWARF {}, no functions or variables from it", path.display());
+                        model.missing_dwo.push(path);
+                        parse_unit(&dwarf, &unit, None)
+                    },
+                    Err(e) => Err(e),
+                },
+                Ok(None) => parse_unit(&dwarf, &unit, None),
+                Err(e) => Err(e),
+            };
+            let unit = match unit {
+                Ok(u) => u,
+                Err(e) => {
+                    println!("Skipping broken unit: {}", e);
+                    continue;
+                },
+            };
+
+            let index = model.units.len();
+            for (file_index, path) in unit.files.iter().enumerate() {
+                if let Some(path) = path {
+                    model.files.entry(path.clone()).or_default().push((index, file_index as u64));
+                }
+            }
+            for (i, call) in unit.inlined.iter().enumerate() {
+                for (low, high) in &call.ranges {
+                    model.inlined_ranges.push((*low, *high, index, i));
+                    model.max_inlined_len = model.max_inlined_len.max(high - low);
+                }
+            }
+            model.units.push(unit);
+        }
+        model.inlined_ranges.sort_by_key(|(low, _, _, _)| *low);
+
+        Ok(model)
+    }
+
+    // What the UI wants to know about a single source file
+    pub fn file_view(&self, path: &Path, src_file_hash: u64) -> (Vec<BreakableSrcLocation>, Vec<Subprogram>) {
+        let mut breakable_src_locs = vec![];
+        let mut subprograms = vec![];
+
+        let entries = match self.files.get(path) {
+            Some(e) => e,
+            None => return (breakable_src_locs, subprograms),
+        };
+
+        let mut unit_indices: Vec<usize> = entries.iter().map(|(unit, _)| *unit).collect();
+        unit_indices.dedup();
+        for unit_index in unit_indices {
+            let unit = &self.units[unit_index];
+            let file_indices: Vec<u64> = entries.iter().filter(|(u, _)| *u == unit_index).map(|(_, f)| *f).collect();
+
+            let locs_in_unit: Vec<BreakableSrcLocation> = unit.rows.iter()
+                .filter(|row| !row.end_sequence && file_indices.contains(&row.file))
+                .map(|row| BreakableSrcLocation{ addr: row.addr, src_line: row.line, src_col: row.col.max(1) })
+                .collect();
+
+            for dwarf_subprogram in &unit.subprograms {
+                if dwarf_subprogram.decl_file.as_deref() != Some(path) {
+                    continue;
+                }
+                if dwarf_subprogram.ranges.is_empty() || dwarf_subprogram.inline {
+                    continue;
+                }
+
+                let mut subprogram = Subprogram{ name: dwarf_subprogram.name.clone(), linkage_name: dwarf_subprogram.linkage_name.clone(), low_addr: dwarf_subprogram.low_addr, high_addr: dwarf_subprogram.high_addr, ranges: dwarf_subprogram.ranges.clone(), src_file_hash: src_file_hash, start_line: 0, end_line: 0 };
+                for location in &locs_in_unit {
+                    if location.addr == subprogram.low_addr {
+                        subprogram.start_line = location.src_line;
+                    }
+                }
+
+                let mut highest_end_addr = 0;
+                for location in &locs_in_unit {
+                    let in_subprogram = subprogram.ranges.iter().any(|(low, high)| *low <= location.addr && location.addr < *high);
+                    if location.addr != subprogram.low_addr && in_subprogram && location.src_line >= subprogram.end_line && location.addr >= highest_end_addr {
+                        highest_end_addr = location.addr;
+                        subprogram.end_line = location.src_line;
+                    }
+                }
+
+                // TODO: well if there's a single line function...
+                if subprogram.start_line == subprogram.end_line {
+                    continue;
+                }
+
+                subprograms.push(subprogram);
+            }
+
+            breakable_src_locs.extend(locs_in_unit);
+        }
+
+        (breakable_src_locs, subprograms)
+    }
+}
+
+const DWP_SECTIONS: [SectionId; 11] = [
+    SectionId::DebugCuIndex,
+    SectionId::DebugTuIndex,
+    SectionId::DebugAbbrev,
+    SectionId::DebugInfo,
+    SectionId::DebugLine,
+    SectionId::DebugStr,
+    SectionId::DebugStrOffsets,
+    SectionId::DebugLoc,
+    SectionId::DebugLocLists,
+    SectionId::DebugRngLists,
+    SectionId::DebugTypes,
+];
+
+// section_by_name also finds .zdebug_* and uncompressed_data handles those and SHF_COMPRESSED.
+// Sections in .dwo and .dwp files are called .debug_*.dwo
+fn section_data<'d>(object: &object::File<'d>, id: SectionId, dwo: bool) -> Cow<'d, [u8]> {
+    let name = match dwo {
+        true => id.dwo_name(),
+        false => Some(id.name()),
+    };
+
+    match name.and_then(|n| object.section_by_name(n)) {
+        Some(section) => match section.uncompressed_data() {
+            Ok(data) => data,
+            Err(e) => {
+                println!("Can't decompress {}: {}", section.name().unwrap_or("??"), e);
+                Cow::Borrowed(&[][..])
+            },
+        },
+        None => Cow::Borrowed(&[][..]),
+    }
+}
+
+// Where a skeleton unit's split DWARF is, relative to its comp dir. None if it's a normal unit
+fn dwo_path<R: Reader>(dwarf: &gimli::Dwarf<R>, unit: &gimli::Unit<R>) -> gimli::Result<Option<PathBuf>> {
+    let name = match unit.dwo_name()? {
+        Some(v) => attr_string(dwarf, unit, v)?,
+        None => return Ok(None),
+    };
+
+    let mut path = match &unit.comp_dir {
+        Some(d) => PathBuf::from(d.to_string_lossy()?.into_owned()),
+        None => PathBuf::new(),
+    };
+    // Absolute names replace the comp dir
+    path.push(name);
+    Ok(Some(path))
+}
+
+// Ok(None) if neither the .dwp nor the .dwo have the unit
+fn parse_split_unit<'a>(dwarf: &gimli::Dwarf<Slice<'a>>, skeleton: &gimli::Unit<Slice<'a>>, dwo_path: &Path, dwp: Option<&gimli::DwarfPackage<Slice<'a>>>, endian: RunTimeEndian) -> gimli::Result<Option<CompileUnit>> {
+    let dwo_id = match skeleton.dwo_id {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+
+    if let Some(dwp) = dwp {
+        if let Some(split_dwarf) = dwp.find_cu(dwo_id, dwarf)? {
+            return parse_split_dwarf(dwarf, skeleton, &split_dwarf, dwo_id);
+        }
+    }
+
+    let data = match std::fs::read(dwo_path) {
+        Ok(d) => d,
+        Err(_) => return Ok(None),
+    };
+    let object = match object::File::parse(&*data) {
+        Ok(o) => o,
+        Err(e) => {
+            println!("Can't parse {}: {}", dwo_path.display(), e);
+            return Ok(None);
+        },
+    };
+
+    let load_section = |id: SectionId| -> Result<Cow<[u8]>, gimli::Error> {
+        Ok(section_data(&object, id, true))
+    };
+    let borrow_section: &dyn for<'b> Fn(&'b Cow<[u8]>) -> EndianSlice<'b, RunTimeEndian> =
+        &|section| EndianSlice::new(&*section, endian);
+
+    let dwo_cow = gimli::Dwarf::load(&load_section)?;
+    let mut split_dwarf = dwo_cow.borrow(&borrow_section);
+    // .debug_addr and friends stay in the exec
+    split_dwarf.make_dwo(dwarf);
+
+    parse_split_dwarf(dwarf, skeleton, &split_dwarf, dwo_id)
+}
+
+fn parse_split_dwarf<'a>(dwarf: &gimli::Dwarf<Slice<'a>>, skeleton: &gimli::Unit<Slice<'a>>, split_dwarf: &gimli::Dwarf<Slice<'a>>, dwo_id: DwoId) -> gimli::Result<Option<CompileUnit>> {
+    let mut iter = split_dwarf.units();
+    while let Some(header) = iter.next()? {
+        let mut unit = split_dwarf.unit(header)?;
+        if unit.dwo_id != Some(dwo_id) {
+            continue;
+        }
+
+        unit.copy_relocated_attributes(skeleton);
+        // The split unit's file table is at the start of .debug_line.dwo, nothing points at it
+        if unit.line_program.is_none() {
+            unit.line_program = split_dwarf.debug_line.program(gimli::DebugLineOffset(0), unit.header.address_size(), skeleton.comp_dir.clone(), skeleton.name.clone()).ok();
+        }
+
+        return parse_unit(split_dwarf, &unit, Some((dwarf, skeleton))).map(Some);
+    }
+
+    Ok(None)
+}
+
+fn attr_string<R: Reader>(dwarf: &gimli::Dwarf<R>, unit: &gimli::Unit<R>, value: AttributeValue<R>) -> gimli::Result<String> {
+    Ok(dwarf.attr_string(unit, value)?.to_string_lossy()?.into_owned())
+}
+
+fn file_table<R: Reader>(dwarf: &gimli::Dwarf<R>, unit: &gimli::Unit<R>, header: &gimli::LineProgramHeader<R>, comp_dir: &Path) -> gimli::Result<Vec<Option<PathBuf>>> {
+    let mut files = vec![];
+    for index in 0..=header.file_names().len() as u64 {
+        let path = match header.file(index) {
+            Some(file) => {
+                let mut path = comp_dir.to_path_buf();
+                // Directory 0 is the compilation directory
+                if file.directory_index() != 0 {
+                    if let Some(dir) = file.directory(header) {
+                        path.push(attr_string(dwarf, unit, dir)?);
+                    }
+                }
+                path.push(attr_string(dwarf, unit, file.path_name())?);
+                Some(path)
+            },
+            None => None,
+        };
+        files.push(path);
+    }
+
+    Ok(files)
+}
+
+impl CompileUnit {
+    fn block_depth(&self, mut block: Option<usize>) -> usize {
+        let mut depth = 0;
+        while let Some(b) = block {
+            depth += 1;
+            block = self.blocks[b].parent;
+        }
+        depth
+    }
+
+    // typedefs and qualifiers peeled off
+    pub fn resolve_type(&self, mut type_offset: Option<usize>) -> Option<&Type> {
+        // Bounded, a broken chain shouldn't hang us
+        for _ in 0..16 {
+            let t = self.types.get(&type_offset?)?;
+            match t.tag {
+                gimli::DW_TAG_typedef | gimli::DW_TAG_const_type | gimli::DW_TAG_volatile_type => type_offset = t.inner,
+                _ => return Some(t),
+            }
+        }
+        None
+    }
+
+    // Variables of the subprogram whose lexical block covers addr
+    pub fn variables_at(&self, subprogram: usize, addr: OfflineAddr) -> Vec<&Variable> {
+        self.subprograms[subprogram].variables.iter()
+            .map(|i| &self.variables[*i])
+            .filter(|variable| {
+                let mut block = variable.block;
+                while let Some(b) = block {
+                    if !self.blocks[b].ranges.iter().any(|(low, high)| *low <= addr && addr < *high) {
+                        return false;
+                    }
+                    block = self.blocks[b].parent;
+                }
+                true
+            })
+            .collect()
+    }
+}
+
+// DW_OP_addr, DW_OP_fbreg (with a DW_OP_call_frame_cfa or rbp frame base) and DW_OP_breg6. Anything
+// fancier, e.g. optimized code with values in registers and location lists, is None
+pub fn variable_location(expr: &[u8], frame_base: Option<&[u8]>) -> Option<VariableLocation> {
+    let (op, operand) = expr.split_first()?;
+    let offset = || EndianSlice::new(operand, LittleEndian).read_sleb128().ok();
+    match gimli::DwOp(*op) {
+        gimli::DW_OP_addr if operand.len() == 8 => Some(VariableLocation::Static(u64::from_le_bytes(operand.try_into().ok()?))),
+        gimli::DW_OP_breg6 => Some(VariableLocation::Rbp(offset()?)),
+        gimli::DW_OP_fbreg => match variable_location(frame_base?, None) {
+            Some(VariableLocation::Cfa(base)) => Some(VariableLocation::Cfa(base + offset()?)),
+            Some(VariableLocation::Rbp(base)) => Some(VariableLocation::Rbp(base + offset()?)),
+            _ => None,
+        },
+        gimli::DW_OP_call_frame_cfa => Some(VariableLocation::Cfa(0)),
+        // DW_OP_reg6, the frame base is rbp itself
+        gimli::DW_OP_reg6 => Some(VariableLocation::Rbp(0)),
+        _ => None,
+    }
+}
+
+// low_pc/high_pc, or DW_AT_ranges from .debug_ranges (DWARF 4) or .debug_rnglists (DWARF 5).
+// Empty ranges are dropped
+fn die_ranges<R: Reader>(dwarf: &gimli::Dwarf<R>, unit: &gimli::Unit<R>, entry: &gimli::DebuggingInformationEntry<R>) -> gimli::Result<Vec<(OfflineAddr, OfflineAddr)>> {
+    let mut ranges = vec![];
+    let mut iter = dwarf.die_ranges(unit, entry)?;
+    while let Some(range) = iter.next()? {
+        if range.begin < range.end {
+            ranges.push((range.begin, range.end));
+        }
+    }
+    Ok(ranges)
+}
+
+// skeleton is set when unit came out of a .dwo or .dwp. The skeleton unit in the exec has the line
+// table, the split unit everything else
+fn parse_unit<R: Reader>(dwarf: &gimli::Dwarf<R>, unit: &gimli::Unit<R>, skeleton: Option<(&gimli::Dwarf<R>, &gimli::Unit<R>)>) -> gimli::Result<CompileUnit> {
+    let (line_dwarf, line_unit) = skeleton.unwrap_or((dwarf, unit));
+
+    let name = match line_unit.name.as_ref().or(unit.name.as_ref()) {
+        Some(n) => n.to_string_lossy()?.into_owned(),
+        None => "".to_owned(),
+    };
+    let comp_dir = match &line_unit.comp_dir {
+        Some(d) => PathBuf::from(d.to_string_lossy()?.into_owned()),
+        None => PathBuf::new(),
+    };
+    let mut cu = CompileUnit{ name: name, comp_dir: comp_dir, files: vec![], rows: vec![], subprograms: vec![], inlined: vec![], blocks: vec![], variables: vec![], types: HashMap::new() };
+
+    if let Some(program) = &line_unit.line_program {
+        cu.files = file_table(line_dwarf, line_unit, program.header(), &cu.comp_dir)?;
+
+        let mut rows = program.clone().rows();
+        while let Some((_, row)) = rows.next_row()? {
+            cu.rows.push(LineRow{
+                addr: row.address(),
+                file: row.file_index(),
+                line: row.line().map_or(0, |l| l.get() as usize),
+                col: match row.column() {
+                    gimli::ColumnType::LeftEdge => 0,
+                    gimli::ColumnType::Column(c) => c.get() as usize,
+                },
+                is_stmt: row.is_stmt(),
+                prologue_end: row.prologue_end(),
+                end_sequence: row.end_sequence(),
+            });
+        }
+    }
+
+    // Split units have their own file table, that's what DW_AT_decl_file indexes
+    let decl_files = match (skeleton, &unit.line_program) {
+        (Some(_), Some(program)) => file_table(dwarf, unit, program.header(), &cu.comp_dir)?,
+        _ => cu.files.clone(),
+    };
+
+    // (depth, subprogram index) of the subprograms we're inside of
+    let mut scopes: Vec<(isize, usize)> = vec![];
+    // (depth, name) of the namespaces and classes we're inside of
+    let mut namespaces: Vec<(isize, String)> = vec![];
+    // (depth, index into inlined) of the inlined calls we're inside of
+    let mut inlined_scopes: Vec<(isize, usize)> = vec![];
+    // (index into inlined, unit offset of the abstract instance)
+    let mut origins: Vec<(usize, usize)> = vec![];
+    // (depth, index into blocks) of the lexical blocks we're inside of
+    let mut block_scopes: Vec<(isize, usize)> = vec![];
+    // Unit offset -> subprogram index, and (subprogram index, unit offset) of the subprograms
+    // that get their name from somewhere else
+    let mut subprogram_offsets: HashMap<usize, usize> = HashMap::new();
+    let mut specifications: Vec<(usize, usize)> = vec![];
+    let mut depth = 0;
+    let mut entries = unit.entries();
+    while let Some((delta_depth, entry)) = entries.next_dfs()? {
+        depth += delta_depth;
+        while scopes.last().map_or(false, |(d, _)| *d >= depth) {
+            scopes.pop();
+        }
+        while namespaces.last().map_or(false, |(d, _)| *d >= depth) {
+            namespaces.pop();
+        }
+        while inlined_scopes.last().map_or(false, |(d, _)| *d >= depth) {
+            inlined_scopes.pop();
+        }
+        while block_scopes.last().map_or(false, |(d, _)| *d >= depth) {
+            block_scopes.pop();
+        }
+
+        let name = match entry.attr_value(gimli::DW_AT_name)? {
+            Some(v) => Some(attr_string(dwarf, unit, v)?),
+            None => None,
+        };
+        let decl_file = match entry.attr_value(gimli::DW_AT_decl_file)? {
+            Some(AttributeValue::FileIndex(i)) => decl_files.get(i as usize).cloned().flatten(),
+            Some(v) => v.udata_value().and_then(|i| decl_files.get(i as usize).cloned().flatten()),
+            None => None,
+        };
+        let decl_line = entry.attr_value(gimli::DW_AT_decl_line)?.and_then(|v| v.udata_value()).unwrap_or(0) as usize;
+        let type_offset = match entry.attr_value(gimli::DW_AT_type)? {
+            Some(AttributeValue::UnitRef(offset)) => Some(offset.0.into_u64() as usize),
+            _ => None,
+        };
+
+        let tag = entry.tag();
+        if SCOPE_TAGS.contains(&tag) {
+            match (&name, tag) {
+                (Some(n), _) => namespaces.push((depth, n.clone())),
+                (None, gimli::DW_TAG_namespace) => namespaces.push((depth, "(anonymous namespace)".to_owned())),
+                // Anonymous struct members are reached through the parent, don't add anything
+                _ => {},
+            }
+        }
+
+        if tag == gimli::DW_TAG_subprogram {
+            let ranges = die_ranges(dwarf, unit, entry)?;
+            // Functions with DW_AT_ranges say where they start with DW_AT_entry_pc, if at all.
+            // Otherwise it's the first range, compilers put the hot part first
+            let entry_pc = match entry.attr_value(gimli::DW_AT_low_pc)?.or(entry.attr_value(gimli::DW_AT_entry_pc)?) {
+                Some(v) => dwarf.attr_address(unit, v)?,
+                None => None,
+            };
+            let (low_addr, high_addr) = match entry_pc {
+                Some(pc) => ranges.iter().find(|(low, high)| *low <= pc && pc < *high).cloned().unwrap_or((pc, pc)),
+                None => ranges.first().cloned().unwrap_or((0, 0)),
+            };
+
+            let linkage_name = match entry.attr_value(gimli::DW_AT_linkage_name)?.or(entry.attr_value(gimli::DW_AT_MIPS_linkage_name)?) {
+                Some(v) => Some(attr_string(dwarf, unit, v)?),
+                None => None,
+            };
+            let name = match (name, &linkage_name) {
+                (Some(n), _) => {
+                    let mut qualified: Vec<&str> = namespaces.iter().map(|(_, n)| n.as_str()).collect();
+                    qualified.push(&n);
+                    qualified.join("::")
+                },
+                (None, Some(l)) => demangle(l).unwrap_or(l.clone()),
+                (None, None) => "".to_owned(),
+            };
+
+            // Out of line definitions and concrete instances of inlined functions only point at
+            // the declaration that has the name
+            let specification = entry.attr_value(gimli::DW_AT_specification)?.or(entry.attr_value(gimli::DW_AT_abstract_origin)?);
+            if let Some(AttributeValue::UnitRef(offset)) = specification {
+                specifications.push((cu.subprograms.len(), offset.0.into_u64() as usize));
+            }
+            subprogram_offsets.insert(entry.offset().0.into_u64() as usize, cu.subprograms.len());
+
+            scopes.push((depth, cu.subprograms.len()));
+            cu.subprograms.push(DwarfSubprogram{
+                name: name,
+                linkage_name: linkage_name,
+                low_addr: low_addr,
+                high_addr: high_addr,
+                ranges: ranges,
+                decl_file: decl_file,
+                decl_line: decl_line,
+                inline: entry.attr_value(gimli::DW_AT_inline)?.is_some(),
+                variables: vec![],
+                frame_base: match entry.attr_value(gimli::DW_AT_frame_base)? {
+                    Some(AttributeValue::Exprloc(expr)) => Some(expr.0.to_slice()?.into_owned()),
+                    _ => None,
+                },
+            });
+        } else if tag == gimli::DW_TAG_inlined_subroutine {
+            let ranges = die_ranges(dwarf, unit, entry)?;
+
+            let call_file = match entry.attr_value(gimli::DW_AT_call_file)? {
+                Some(AttributeValue::FileIndex(i)) => decl_files.get(i as usize).cloned().flatten(),
+                Some(v) => v.udata_value().and_then(|i| decl_files.get(i as usize).cloned().flatten()),
+                None => None,
+            };
+            if let Some(AttributeValue::UnitRef(offset)) = entry.attr_value(gimli::DW_AT_abstract_origin)? {
+                origins.push((cu.inlined.len(), offset.0.into_u64() as usize));
+            }
+
+            inlined_scopes.push((depth, cu.inlined.len()));
+            cu.inlined.push(InlinedCall{
+                origin: None,
+                ranges: ranges,
+                call_file: call_file,
+                call_line: entry.attr_value(gimli::DW_AT_call_line)?.and_then(|v| v.udata_value()).unwrap_or(0) as usize,
+                call_col: entry.attr_value(gimli::DW_AT_call_column)?.and_then(|v| v.udata_value()).unwrap_or(0) as usize,
+                parent: inlined_scopes.iter().rev().nth(1).map(|(_, i)| *i),
+            });
+        } else if tag == gimli::DW_TAG_lexical_block {
+            let parent = block_scopes.last().map(|(_, i)| *i);
+            block_scopes.push((depth, cu.blocks.len()));
+            cu.blocks.push(LexicalBlock{ ranges: die_ranges(dwarf, unit, entry)?, parent: parent });
+        } else if tag == gimli::DW_TAG_variable || tag == gimli::DW_TAG_formal_parameter {
+            let location = match entry.attr_value(gimli::DW_AT_location)? {
+                Some(AttributeValue::Exprloc(expr)) => Some(expr.0.to_slice()?.into_owned()),
+                _ => None,
+            };
+
+            let subprogram = scopes.last().map(|(_, i)| *i);
+            if let Some(i) = subprogram {
+                cu.subprograms[i].variables.push(cu.variables.len());
+            }
+            cu.variables.push(Variable{
+                name: name.unwrap_or_default(),
+                parameter: tag == gimli::DW_TAG_formal_parameter,
+                type_offset: type_offset,
+                decl_file: decl_file,
+                decl_line: decl_line,
+                location: location,
+                subprogram: subprogram,
+                block: block_scopes.last().map(|(_, i)| *i),
+            });
+        } else if TYPE_TAGS.contains(&tag) {
+            let byte_size = entry.attr_value(gimli::DW_AT_byte_size)?.and_then(|v| v.udata_value());
+            let encoding = match entry.attr_value(gimli::DW_AT_encoding)? {
+                Some(AttributeValue::Encoding(e)) => Some(e),
+                _ => None,
+            };
+            cu.types.insert(entry.offset().0.into_u64() as usize, Type{ tag: tag, name: name, byte_size: byte_size, inner: type_offset, encoding: encoding });
+        }
+    }
+
+    // Twice, so a concrete instance -> abstract instance -> declaration chain gets resolved no
+    // matter which order they're in
+    for _ in 0..2 {
+        for (i, offset) in &specifications {
+            let declaration = match subprogram_offsets.get(offset) {
+                Some(d) => cu.subprograms[*d].clone(),
+                None => continue,
+            };
+
+            let subprogram = &mut cu.subprograms[*i];
+            if subprogram.name.is_empty() {
+                subprogram.name = declaration.name;
+            }
+            if subprogram.linkage_name.is_none() {
+                subprogram.linkage_name = declaration.linkage_name;
+            }
+            if subprogram.decl_file.is_none() {
+                subprogram.decl_file = declaration.decl_file;
+                subprogram.decl_line = declaration.decl_line;
+            }
+        }
+    }
+
+    for (i, offset) in origins {
+        cu.inlined[i].origin = subprogram_offsets.get(&offset).cloned();
+    }
+
+    Ok(cu)
+}
@@ -1,5 +1,9 @@
 use crate::src_file::SrcFile;
 
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+static NEXT_BREAKPOINT_ID: AtomicUsize = AtomicUsize::new(0);
+
 //pub struct Point<'a> {
 #[derive(Debug)]
 pub struct Point<'a> {
@@ -16,7 +20,10 @@ pub struct Point<'a> {
 
     //file: &'a SrcFile,
     //file: Path,
+    // 0 for raw address breakpoints, e.g. from the disassembly
     pub line_number: u64,
+    // For lines with more than one statement, 0 is the whole line
+    pub column: u64,
     //file_checksum: Hash, // For checking if file is outdated, etc
 
     //group: Group, 
@@ -47,6 +54,7 @@ impl<'a> Point<'a> {
 
             //file: file,
             line_number: line_number,
+            column: 0,
 
             //group: Group, 
 
@@ -54,6 +62,12 @@ impl<'a> Point<'a> {
             phantom: std::marker::PhantomData,
         }
     }
+
+    pub fn new_at_column(addr: u64, line_number: u64, column: u64) -> Self {
+        let mut point = Point::new(addr, line_number);
+        point.column = column;
+        point
+    }
 }
 
 trait InsertPoint {
@@ -63,6 +77,8 @@ trait InsertPoint {
 
 #[derive(Debug)]
 pub struct BreakPoint<'a> {
+    // Unique for the lifetime of the debugger, so events can refer to it
+    pub id: usize,
     pub point: Point<'a>,
 
     //contition: TriggerCondition,
@@ -71,7 +87,7 @@ pub struct BreakPoint<'a> {
 
 impl<'a> BreakPoint<'a> {
     pub fn new(point: Point<'a>) -> Self {
-        BreakPoint{ point: point }
+        BreakPoint{ id: NEXT_BREAKPOINT_ID.fetch_add(1, Ordering::Relaxed), point: point }
     }
 }
 
@@ -0,0 +1,19 @@
+// Everything that doesn't need a display, shared by the imgui frontend and degrugger-cli
+pub mod session;
+pub mod src_file;
+pub mod insertpoint;
+pub mod patcher;
+pub mod tracer;
+pub mod registers;
+pub mod signals;
+pub mod recording;
+pub mod timeline;
+pub mod stack;
+pub mod offline_debug_info;
+pub mod dwarf_model;
+pub mod debug_file;
+pub mod debuginfod;
+pub mod symbols;
+
+pub use crate::src_file::SrcFile;
+pub use crate::offline_debug_info::OfflineDebugInfo;
@@ -1,8 +1,7 @@
 use std::path::Path;
 use mint::*;
 
-use crate::session::Session;
-mod session;
+use degrugger::session::Session;
 
 use imgui::*;
 use imgui::sys;
@@ -10,31 +9,30 @@ use std::ptr::null;
 
 mod support;
 
-use crate::src_file::SrcFile;
-mod src_file;
+use degrugger::src_file::SrcFile;
 
-mod insertpoint;
-use crate::insertpoint::BreakPoint;
-use crate::insertpoint::Point;
+use degrugger::insertpoint::BreakPoint;
+use degrugger::insertpoint::Point;
 
-mod patcher;
+use degrugger::signals;
+use degrugger::stack::walk_stack;
+use degrugger::registers::{ VectorLane, EFLAGS, decode_eflags, f80_to_f64 };
 
-use crate::session::Run;
+use degrugger::session::Run;
 
-use crate::session::DebugeeState;
-use crate::session::Function; // TEMP
-use core::ffi::c_void; // TEMP
+use degrugger::session::{ DebugeeState, LOAD_BIAS };
+use degrugger::session::Function; // TEMP
 use nix::unistd::Pid; // TEMP
 
-use nix::sys::{ptrace, wait::waitpid}; // TEMP
+use nix::sys::signal::Signal;
 
-mod offline_debug_info;
-use crate::offline_debug_info::*;
+use degrugger::offline_debug_info::*;
 
 use std::collections::HashMap;
 
 struct UserInputs {
     cont: bool,
+    pause: bool,
     focus_bp: bool,
 }
 
@@ -42,14 +40,57 @@ struct DebuggerContext<'a> {
     path_input: String,
     relevant_src_input: String,
     filter_irrelevant_src: bool,
+    // Colon separated, like gdb's debug-file-directory
+    debug_dirs_input: String,
 
     session: Result<Session<'a>, ()>,
 
     hex_values: bool,
+    // Linkage names instead of the demangled ones
+    show_mangled: bool,
+    vector_lane: usize,
+    inject_signal: usize,
+
+    // Recording window's "last change" query
+    last_change_addr: String,
+    last_change_size: i32,
+    last_change: Option<String>,
+
+    timeline: TimelineView,
+    function_break: FunctionBreakView,
+    disassembly: DisassemblyView,
 
     user_inputs: UserInputs,
 }
 
+struct TimelineView {
+    // Index into the run's snapshots, None follows the live debugee
+    selected: Option<usize>,
+    // Snapshots being compared
+    diff_a: i32,
+    diff_b: i32,
+    watch_addr: String,
+    watch_size: i32,
+}
+
+struct DisassemblyView {
+    jump_input: String,
+    // Scrolled to on the next frame
+    jump_to: Option<u64>,
+    // Last jump target, stays highlighted
+    highlighted: Option<u64>,
+    // Source lines between the instructions
+    show_source: bool,
+    show_bytes: bool,
+}
+
+struct FunctionBreakView {
+    input: String,
+    regex: bool,
+    // What the last "Break" did
+    result: Option<String>,
+}
+
 //fn text_window(ui: &imgui::Ui, state: &Option<DebugeeState>, line_nums: &Vec<String>, lines: &Vec<String>, breakpoints: &mut Vec<BreakPoint>, debug_info: &HashMap<u64, Arc<SrcFileDebugInfo>>)
 
 #[derive(Debug)]
@@ -60,101 +101,29 @@ struct StackNode {
     location: Option<BreakableSrcLocation>,
     file_hash: Option<u64>,
     addr: u64,
+    inlined: bool,
     //folded: bool,
     //hovered: bool,
 }
 
-fn generate_stack(pid: Pid, state: &DebugeeState, debug_info: &ThinOfflineDebugInfo) -> Vec<StackNode> {
-    let mut stack = vec![];
-
-    let mut frame_base = state.regs.rbp;
-    let mut ret_addr = state.regs.rip - 1;
-    let mut frame_counter = 0;
-    loop {
-        if frame_base == 0 {
-            break;
-        }
-
-        ret_addr -= 0x555555555040 - 0x1040;
-        let mut call_addr = match &debug_info.decompiled_src {
-            Some(src) => {
-                let mut prev_addr = ret_addr;
-                for addr in &src.addresses {
-                    if *addr == ret_addr {
-                        break;
-                    }
-                    prev_addr = *addr;
-                }
-                prev_addr
-            },
-            None => ret_addr,
+// read_memory is either the live debugee or a snapshot of it
+fn generate_stack(read_memory: impl Fn(u64, usize) -> Option<Vec<u8>>, state: &DebugeeState, debug_info: &ThinOfflineDebugInfo) -> Vec<StackNode> {
+    let mut stack: Vec<StackNode> = walk_stack(read_memory, state, debug_info).into_iter().map(|frame| {
+        let c = match frame.subprogram_index % 6 {
+            0 => Vector4{ x: 0.0, y: 0.0, z: 1.0, w: 1.0 },
+            1 => Vector4{ x: 0.0, y: 1.0, z: 0.0, w: 1.0 },
+            2 => Vector4{ x: 0.0, y: 1.0, z: 1.0, w: 1.0 },
+            3 => Vector4{ x: 1.0, y: 0.0, z: 0.0, w: 1.0 },
+            4 => Vector4{ x: 1.0, y: 0.0, z: 1.0, w: 1.0 },
+            5 => Vector4{ x: 1.0, y: 1.0, z: 0.0, w: 1.0 },
+            _ => Vector4{ x: 0.0, y: 0.0, z: 0.0, w: 0.0 },
         };
-        if stack.len() == 0 {
-            // First hit has the correct addr
-            call_addr = ret_addr;
-        }
-
-        let mut found = false;
-        let mut subprogram_index = 0;
-        for subprogram in &*debug_info.all_subprograms {
-            let ret_addr_in_subprogram = subprogram.low_addr <= ret_addr && ret_addr <= subprogram.high_addr;
-            let call_addr_in_subprogram = subprogram.low_addr <= call_addr && call_addr <= subprogram.high_addr;
-
-            if ret_addr_in_subprogram && call_addr_in_subprogram {
-                let mut bp_location = None;
-                let mut file_hash = None;
-                'outer: for (hash, src_file_info) in &debug_info.src_file_info {
-                    for bp in &src_file_info.breakable_locations {
-                        // TODO: this is bad!!!!!
-                        if bp.addr == call_addr {
-                            bp_location = Some(bp.clone());
-                            file_hash = Some(*hash);
-                            break 'outer;
-                        }
-                        if bp.addr == ret_addr {
-                            bp_location = Some(bp.clone());
-                            file_hash = Some(*hash);
-                            break 'outer;
-                        }
-                    }
-                }
-
-                frame_counter += 1;
-                found = true;
-
-                let c = match subprogram_index % 6 {
-                    0 => Vector4{ x: 0.0, y: 0.0, z: 1.0, w: 1.0 },
-                    1 => Vector4{ x: 0.0, y: 1.0, z: 0.0, w: 1.0 },
-                    2 => Vector4{ x: 0.0, y: 1.0, z: 1.0, w: 1.0 },
-                    3 => Vector4{ x: 1.0, y: 0.0, z: 0.0, w: 1.0 },
-                    4 => Vector4{ x: 1.0, y: 0.0, z: 1.0, w: 1.0 },
-                    5 => Vector4{ x: 1.0, y: 1.0, z: 0.0, w: 1.0 },
-                    _ => Vector4{ x: 0.0, y: 0.0, z: 0.0, w: 0.0 },
-                };
-                let node = StackNode{ color: c, selected: false, subprogram: subprogram.clone(), location: bp_location, file_hash: file_hash, addr: call_addr };
-                //println!("{:?}", node);
-                //println!("{ret_addr}, {call_addr}; {:x}, {:x}", ret_addr, call_addr);
-                stack.push(node);
-
-            }
-            subprogram_index += 1;
-        }
-
-        if !found {
-            break;
-        }
-
-        // TODO: move this. Can crash if the child has died
-        ret_addr = ptrace::read(pid, (frame_base + 8) as *mut c_void).unwrap() as u64;
-        frame_base = ptrace::read(pid, frame_base as *mut c_void).unwrap() as u64;
-    }
+        StackNode{ color: c, selected: false, subprogram: frame.subprogram, location: frame.location, file_hash: frame.file_hash, addr: frame.addr, inlined: frame.inlined }
+    }).collect();
     if stack.len() > 0 {
         stack[0].selected = true;
     }
 
-    //println!("");
-    //println!("");
-
     return stack.into_iter().rev().collect();
 }
 
@@ -184,22 +153,29 @@ fn inlined_stack_window(ui: &imgui::Ui, state: &DebugeeState, line_num_str: &Vec
             false => 0.1,
         };
 
-        let src_file = match src_files.get(&node.file_hash.unwrap()) {
+        // Functions from the symbol table have no source
+        let src_file = match node.file_hash.and_then(|hash| src_files.get(&hash)) {
             Some(src) => src,
             None => continue,
         };
-        if src_file.lines.is_none() {
+        let subprogram = &node.subprogram;
+        // Inlined frames without a DW_AT_decl_line have nowhere to start from
+        if src_file.lines.is_none() || subprogram.start_line == 0 {
             continue;
         }
 
-        let subprogram = &node.subprogram;
         let end_line = match &node.location {
             Some(l) => l.src_line,
             None => subprogram.end_line,
         };
 
         for line_num in subprogram.start_line - 1..end_line {
-            let line = &src_file.lines.as_ref().unwrap()[line_num];
+            // The debug info can be out of date with the file on disk
+            let line = match src_file.lines.as_ref().unwrap().get(line_num) {
+                Some(l) => l,
+                None => break,
+            };
+            let line_num_text = line_num_str.get(line_num).map_or("", |s| s.as_str());
             let is_bp_line = false;
             if is_bp_line {
                 background_color = Vector4{ x: 1.0, y: 0.8, z: 0.0, w: 0.7};
@@ -211,8 +187,8 @@ fn inlined_stack_window(ui: &imgui::Ui, state: &DebugeeState, line_num_str: &Vec
             draw_list.add_rect(start, end, background_color).filled(true).build();
 
             // Line number
-            draw_list.add_text(start, ImColor32::WHITE, &line_num_str[line_num]);
-            start.x += ui.calc_text_size(&line_num_str[line_num])[0];
+            draw_list.add_text(start, ImColor32::WHITE, line_num_text);
+            start.x += ui.calc_text_size(line_num_text)[0];
 
             // Code
             draw_list.add_text(start, ImColor32::WHITE, line);
@@ -229,7 +205,7 @@ fn inlined_stack_window(ui: &imgui::Ui, state: &DebugeeState, line_num_str: &Vec
             draw_list.add_rect(start, end, darker_background).filled(true).build();
 
             draw_list.add_text(start, Vector4{ x: 1.0, y: 0.2, z: 0.2, w: 1.0}, " fold");
-            start.x += ui.calc_text_size(&line_num_str[0])[0];
+            start.x += ui.calc_text_size(line_num_str.first().map_or("", |s| s.as_str()))[0];
 
             actual_line_num += 1;
         }
@@ -285,9 +261,11 @@ fn inlined_stack_window(ui: &imgui::Ui, state: &DebugeeState, line_num_str: &Vec
     //}
 }
 
-fn disassembly_window(ui: &imgui::Ui, inputs: &UserInputs, state: &Option<DebugeeState>, line_num_str: &Vec<String>, breakpoints: &mut Vec<BreakPoint>, debug_info: &ThinOfflineDebugInfo)
+fn disassembly_window(ui: &imgui::Ui, inputs: &UserInputs, state: Option<&DebugeeState>, line_num_str: &Vec<String>, breakpoints: &mut Vec<BreakPoint>, debug_info: &ThinOfflineDebugInfo, src_files: &HashMap<u64, Arc<SrcFile>>, view: &mut DisassemblyView, show_mangled: bool)
 {
-    let w = ui.window("Disassembly").begin();
+    let w = ui.window("Disassembly")
+        .menu_bar(true)
+        .begin();
     if w.is_none() {
         return;
     }
@@ -297,7 +275,30 @@ fn disassembly_window(ui: &imgui::Ui, inputs: &UserInputs, state: &Option<Debuge
         return;
     }
     let decompiled_src = &debug_info.decompiled_src.as_ref().unwrap();
-    let lines = &decompiled_src.decompiled_src;
+
+    // In the menu bar so it doesn't scroll away with the instructions
+    if let Some(menu_bar_token) = ui.begin_menu_bar() {
+        ui.set_next_item_width(ui.calc_text_size("0x0000000000000000")[0]);
+        let entered = ui.input_text("##jump", &mut view.jump_input)
+            .hint("addr or file offset")
+            .flags(InputTextFlags::ENTER_RETURNS_TRUE)
+            .build();
+        if ui.button("Jump") || entered {
+            match u64::from_str_radix(view.jump_input.trim().trim_start_matches("0x"), 16) {
+                // Copied out of a register or the stack
+                Ok(addr) if addr >= LOAD_BIAS => view.jump_to = Some(addr - LOAD_BIAS),
+                Ok(addr) => view.jump_to = Some(addr),
+                Err(_) => view.highlighted = None,
+            }
+        }
+        ui.checkbox("Source", &mut view.show_source);
+        ui.checkbox("Bytes", &mut view.show_bytes);
+        menu_bar_token.end();
+    }
+    let rows = match view.show_source {
+        true => &decompiled_src.interleaved_rows,
+        false => &decompiled_src.rows,
+    };
 
     let char_height = ui.calc_text_size(&" ")[1];
     let char_width = ui.calc_text_size(&" ")[0];
@@ -308,20 +309,24 @@ fn disassembly_window(ui: &imgui::Ui, inputs: &UserInputs, state: &Option<Debuge
     let content_size = ui.content_region_max();
 
     let mut bp_addr = match state {
-        Some(s) => s.regs.rip - (0x555555555040 - 0x1040) - 1,
+        Some(s) => s.addr - LOAD_BIAS,
         None => 0,
     };
 
     let scroll_max_y = ui.scroll_max_y();
     if inputs.focus_bp {
-        for i in 0..lines.len() {
-            let addr = decompiled_src.addresses[i];
-            if bp_addr == addr {
-                let perc = i as f32 / lines.len() as f32;
-                ui.set_scroll_y(perc * scroll_max_y);
-            }
+        if let Ok(i) = decompiled_src.addresses.binary_search(&bp_addr) {
+            let perc = DecompiledSrc::row_of(rows, i) as f32 / rows.len() as f32;
+            ui.set_scroll_y(perc * scroll_max_y);
         }
     }
+    // Instruction containing the address, a few lines from the top
+    if let Some(addr) = view.jump_to.take() {
+        let i = decompiled_src.addresses.partition_point(|a| *a <= addr).saturating_sub(1);
+        let row = DecompiledSrc::row_of(rows, i);
+        ui.set_scroll_y((row.saturating_sub(5) as f32) * char_height);
+        view.highlighted = decompiled_src.addresses.get(i).cloned();
+    }
     let scroll_x = ui.scroll_x();
     let scroll_y = ui.scroll_y();
 
@@ -330,13 +335,15 @@ fn disassembly_window(ui: &imgui::Ui, inputs: &UserInputs, state: &Option<Debuge
     let starting_line = ((scroll_y as f32 - char_height + 1.0) / char_height) as usize;
     let adjusted_content_size = Vector2{x: content_size[0], y:content_size[1] + scroll_y};
     let visible_lines = (adjusted_content_size.y as f32 / char_height) as usize + 1;
-    let ending_line = std::cmp::min(lines.len(), starting_line + visible_lines);
+    let ending_line = std::cmp::min(rows.len(), starting_line + visible_lines);
 
     //println!("{starting_line} {visible_lines} {ending_line}");
 
     let mut end = Vector2{ x: 0.0, y: 0.0 };
-    for (i, line) in lines[starting_line..ending_line].iter().enumerate() {
-        let addr = decompiled_src.addresses[i + starting_line];
+    for (i, row) in rows[starting_line..ending_line].iter().enumerate() {
+        // Headers and source lines take the address of the instruction after them
+        let addr = decompiled_src.addresses[row.instruction];
+        let is_instruction = row.kind == DisassemblyRowKind::Instruction;
         let line_num = i + starting_line;
         let red = Vector4{ x: 0.1, y: 0.1, z: 0.1, w: 1.0};
         let green = Vector4{ x: 0.2, y: 0.2, z: 0.2, w: 1.0};
@@ -345,18 +352,17 @@ fn disassembly_window(ui: &imgui::Ui, inputs: &UserInputs, state: &Option<Debuge
         let mut subprogram_index = 0;
         let mut found_subprogram = false;
         let mut is_bp_func = false;
-        for subprogram in &*debug_info.all_subprograms {
-            if subprogram.low_addr <= addr && addr <= subprogram.high_addr {
-                found_subprogram = true;
-                is_bp_func = subprogram.low_addr <= bp_addr && bp_addr <= subprogram.high_addr;
-                break;
-            }
-            subprogram_index += 1;
+        let mut function_name = None;
+        if let Some((i, subprogram)) = debug_info.subprogram_at(addr) {
+            subprogram_index = i;
+            found_subprogram = true;
+            is_bp_func = subprogram.contains(bp_addr);
+            function_name = Some(subprogram.display_name(show_mangled));
         }
     
         let mut background_color = red;
         //let mut background_color = if line_num % 2 == 0 { red } else { green };
-        let is_bp_line = bp_addr == addr;
+        let is_bp_line = is_instruction && bp_addr == addr;
         if is_bp_line {
             background_color = Vector4{ x: 1.0, y: 0.8, z: 0.0, w: 0.7};
         }
@@ -364,6 +370,9 @@ fn disassembly_window(ui: &imgui::Ui, inputs: &UserInputs, state: &Option<Debuge
         let mut start = Vector2{ x: start_cursor[0] + scroll_x, y: start_cursor[1] + (line_num as f32) * char_height };
         end = Vector2{ x: start.x + content_size[0], y: start.y + char_height };
         draw_list.add_rect(start, end, background_color).filled(true).build();
+        if is_instruction && view.highlighted == Some(addr) && !is_bp_line {
+            draw_list.add_rect(start, end, Vector4{ x: 0.3, y: 0.6, z: 1.0, w: 0.4 }).filled(true).build();
+        }
 
         if found_subprogram && !is_bp_line {
             let alpha = match is_bp_func {
@@ -401,9 +410,34 @@ fn disassembly_window(ui: &imgui::Ui, inputs: &UserInputs, state: &Option<Debuge
         start.x += ui.calc_text_size(&line_num_str[line_num])[0];
 
         // Code
-        draw_list.add_text(start, ImColor32::WHITE, line);
+        match row.kind {
+            DisassemblyRowKind::Function => {
+                let name = function_name.unwrap_or("??");
+                draw_list.add_text(start, ImColor32::WHITE, format!("{:016x} <{}>:", addr, name));
+            },
+            DisassemblyRowKind::Source{ file_hash, line } => {
+                let text = match src_files.get(&file_hash) {
+                    Some(file) => match file.lines.as_ref().and_then(|lines| lines.get(line - 1)) {
+                        Some(src_line) => format!("{: >5}  {}", line, src_line.trim_end()),
+                        None => format!("{}:{}", file.path.display(), line),
+                    },
+                    // Not loaded (or filtered out), the line number is all we have
+                    None => format!("{: >5}", line),
+                };
+                draw_list.add_text(start, Vector4{ x: 0.5, y: 0.8, z: 0.5, w: 1.0 }, text);
+            },
+            DisassemblyRowKind::Instruction => {
+                let text = &decompiled_src.decompiled_src[row.instruction];
+                let line = match view.show_bytes {
+                    // 15 bytes is the longest an instruction can be
+                    true => format!("{:016x}  {:<30}  {}", addr, decompiled_src.bytes[row.instruction], text),
+                    false => format!("{:016x}  {}", addr, text),
+                };
+                draw_list.add_text(start, ImColor32::WHITE, line);
+            },
+        }
     }
-    ui.dummy(Vector2{ x: end.x - start_cursor[0], y: char_height * lines.len() as f32 });
+    ui.dummy(Vector2{ x: end.x - start_cursor[0], y: char_height * rows.len() as f32 });
 
     // Code text
 
@@ -413,48 +447,43 @@ fn disassembly_window(ui: &imgui::Ui, inputs: &UserInputs, state: &Option<Debuge
     let e = Vector2{ x: start.x + char_width, y: start.y + char_height * (visible_lines as f32) };
     draw_list.add_rect(start, e, c).filled(true).build();
 
-    // BPs
-    //line_num = 0;
-    //for _ in lines {
-    //    let start = Vector2{ x: start_cursor[0] + scroll_x, y: start_cursor[1] + (line_num as f32) * char_height - scroll_y };
-    //    let end = Vector2{ x: start.x + char_width * 6.0, y: start.y + char_height };
-
-    //    let mut enabled = false;
-    //    if let Some(addr) = file.line_to_addr.get(&(line_num + 1)) {
-    //        let mut matching_bp: Option<&mut BreakPoint> = None;
-    //        for bp in breakpoints.iter_mut() {
-    //            if bp.point.addr == *addr {
-    //                enabled = bp.point.enabled;
-    //                matching_bp = Some(bp);
-    //                break;
-    //            }
-    //        }
-
-    //        if ui.is_mouse_hovering_rect(start, end) && ui.is_mouse_clicked(imgui::MouseButton::Left) {
-    //            if let Some(ref mut bp) = matching_bp {
-    //                enabled = false;
-    //                bp.point.enabled = enabled;
-    //            } else {
-    //                enabled = true;
-    //                breakpoints.push(BreakPoint::new(Point::new(*addr, (line_num + 1) as u64)));
+    // BPs, on any instruction. Left click toggles, right click removes. Whatever the code window
+    // set shows up here too since it's all addresses in the end
+    for line_num in starting_line..ending_line {
+        if rows[line_num].kind != DisassemblyRowKind::Instruction {
+            continue;
+        }
+        let addr = decompiled_src.addresses[rows[line_num].instruction];
+        let start = Vector2{ x: start_cursor[0] + scroll_x, y: start_cursor[1] + (line_num as f32) * char_height };
+        let end = Vector2{ x: start.x + char_width * 6.0, y: start.y + char_height };
 
-    //                let max_bp_count = breakpoints.len() - 1;
-    //                matching_bp = Some(&mut breakpoints[max_bp_count]);
-    //            }
-    //        }
-    //    }
+        let matching_bp = breakpoints.iter().position(|bp| bp.point.addr == addr);
+        if ui.is_mouse_hovering_rect(start, end) {
+            if ui.is_mouse_clicked(imgui::MouseButton::Left) {
+                match matching_bp {
+                    Some(i) => breakpoints[i].point.enabled = !breakpoints[i].point.enabled,
+                    // Not tied to a line, even if the instruction has one
+                    None => breakpoints.push(BreakPoint::new(Point::new(addr, 0))),
+                }
+            } else if ui.is_mouse_clicked(imgui::MouseButton::Right) {
+                if let Some(i) = matching_bp {
+                    breakpoints.remove(i);
+                }
+            }
+        }
 
-    //    if enabled {
-    //        let r = Vector4{ x: 1.0, y: 0.2, z: 0.2, w: 1.0};
-    //        let c = Vector2{ x: start.x + char_width * 5.5, y: start.y + char_height * 0.5 };
-    //        draw_list.add_circle(c, char_width / 2.0, r).filled(true).build();
-    //    }
-    //    line_num += 1;
-    //}
+        let r = Vector4{ x: 1.0, y: 0.2, z: 0.2, w: 1.0};
+        let c = Vector2{ x: start.x + char_width * 5.5, y: start.y + char_height * 0.5 };
+        match breakpoints.iter().find(|bp| bp.point.addr == addr) {
+            Some(bp) if bp.point.enabled => draw_list.add_circle(c, char_width / 2.0, r).filled(true).build(),
+            Some(_) => draw_list.add_circle(c, char_width / 2.0, r).build(),
+            None => {},
+        }
+    }
     w.end();
 }
 
-fn code_windows(ui: &imgui::Ui, user_inputs: &UserInputs, files: &HashMap<u64, Arc<SrcFile>>, state: &Option<DebugeeState>, line_num_str: &Vec<String>, breakpoints: &mut Vec<BreakPoint>, debug_info: &ThinOfflineDebugInfo) {
+fn code_windows(ui: &imgui::Ui, user_inputs: &UserInputs, files: &HashMap<u64, Arc<SrcFile>>, state: Option<&DebugeeState>, line_num_str: &Vec<String>, breakpoints: &mut Vec<BreakPoint>, debug_info: &ThinOfflineDebugInfo) {
     let w = ui.window("Src code").begin();
     if w.is_none() {
         return;
@@ -479,7 +508,7 @@ fn code_windows(ui: &imgui::Ui, user_inputs: &UserInputs, files: &HashMap<u64, A
     w.end();
 }
 
-fn code_windoww(ui: &imgui::Ui, inputs: &UserInputs, file: &SrcFile, state: &Option<DebugeeState>, line_num_str: &Vec<String>, breakpoints: &mut Vec<BreakPoint>, debug_info: &ThinOfflineDebugInfo) {
+fn code_windoww(ui: &imgui::Ui, inputs: &UserInputs, file: &SrcFile, state: Option<&DebugeeState>, line_num_str: &Vec<String>, breakpoints: &mut Vec<BreakPoint>, debug_info: &ThinOfflineDebugInfo) {
     if file.lines.is_none() {
         return;
     }
@@ -496,7 +525,7 @@ fn code_windoww(ui: &imgui::Ui, inputs: &UserInputs, file: &SrcFile, state: &Opt
     let mut bp_line = 0;
     bp_line = match state {
         Some(s) => {
-            let addr = s.regs.rip - (0x555555555040 - 0x1040) - 1;
+            let addr = s.addr - LOAD_BIAS;
             match file.addr_to_line.get(&addr) {
                 Some(l) => *l,
                 None => 0,
@@ -607,34 +636,63 @@ fn code_windoww(ui: &imgui::Ui, inputs: &UserInputs, file: &SrcFile, state: &Opt
     let e = Vector2{ x: start.x + char_width, y: start.y + char_height * (line_num as f32) };
     draw_list.add_rect(start, e, c).filled(true).build();
 
-    // BPs
+    // Lines of this file with an enabled BP on any of their instructions
+    let other_bp_lines: Vec<usize> = breakpoints.iter()
+        .filter(|bp| bp.point.enabled)
+        .filter_map(|bp| debug_info.location_at(bp.point.addr))
+        .filter(|(h, _)| *h == hash)
+        .map(|(_, location)| location.src_line)
+        .collect();
+
+    // BPs. Left click toggles, right click removes
     line_num = 0;
-    for _ in lines {
+    for line in lines {
         let start = Vector2{ x: start_cursor[0] + scroll_x, y: start_cursor[1] + (line_num as f32) * char_height };
         let end = Vector2{ x: start.x + char_width * 6.0, y: start.y + char_height };
 
         let mut enabled = false;
-        if let Some(addr) = file.line_to_addr.get(&(line_num + 1)) {
-            let mut matching_bp: Option<&mut BreakPoint> = None;
-            for bp in breakpoints.iter_mut() {
-                if bp.point.addr == *addr {
-                    enabled = bp.point.enabled;
-                    matching_bp = Some(bp);
-                    break;
+        // Lowest address of the line, that's where the line's code starts
+        if let Some(addr) = debug_info.addresses_for_line(hash, line_num + 1).first() {
+            let matching_bp = breakpoints.iter().position(|bp| bp.point.addr == *addr);
+            let mut exists = matching_bp.is_some();
+            if let Some(i) = matching_bp {
+                enabled = breakpoints[i].point.enabled;
+            }
+
+            if ui.is_mouse_hovering_rect(start, end) {
+                if ui.is_mouse_clicked(imgui::MouseButton::Left) {
+                    match matching_bp {
+                        Some(i) => {
+                            enabled = !enabled;
+                            breakpoints[i].point.enabled = enabled;
+                        },
+                        None => {
+                            enabled = true;
+                            exists = true;
+                            breakpoints.push(BreakPoint::new(Point::new(*addr, (line_num + 1) as u64)));
+                        },
+                    }
+                } else if ui.is_mouse_clicked(imgui::MouseButton::Right) {
+                    if let Some(i) = matching_bp {
+                        breakpoints.remove(i);
+                        enabled = false;
+                        exists = false;
+                    }
                 }
             }
 
-            if ui.is_mouse_hovering_rect(start, end) && ui.is_mouse_clicked(imgui::MouseButton::Left) {
-                if let Some(ref mut bp) = matching_bp {
-                    enabled = false;
-                    bp.point.enabled = enabled;
-                } else {
-                    enabled = true;
-                    breakpoints.push(BreakPoint::new(Point::new(*addr, (line_num + 1) as u64)));
+            // Disabled ones still get a ring so they're not forgotten
+            if exists && !enabled {
+                let r = Vector4{ x: 1.0, y: 0.2, z: 0.2, w: 1.0};
+                let c = Vector2{ x: start.x + char_width * 5.5, y: start.y + char_height * 0.5 };
+                draw_list.add_circle(c, char_width / 2.0, r).build();
+            }
 
-                    let max_bp_count = breakpoints.len() - 1;
-                    matching_bp = Some(&mut breakpoints[max_bp_count]);
-                }
+            // Set from the disassembly (or a column) somewhere else in the line, smaller dot
+            if !exists && other_bp_lines.contains(&(line_num + 1)) {
+                let r = Vector4{ x: 1.0, y: 0.2, z: 0.2, w: 1.0};
+                let c = Vector2{ x: start.x + char_width * 5.5, y: start.y + char_height * 0.5 };
+                draw_list.add_circle(c, char_width / 4.0, r).filled(true).build();
             }
         }
 
@@ -643,6 +701,41 @@ fn code_windoww(ui: &imgui::Ui, inputs: &UserInputs, file: &SrcFile, state: &Opt
             let c = Vector2{ x: start.x + char_width * 5.5, y: start.y + char_height * 0.5 };
             draw_list.add_circle(c, char_width / 2.0, r).filled(true).build();
         }
+
+        // Column BPs, underlines under every column with code on lines with more than one. Same
+        // clicks as the gutter
+        let columns = debug_info.columns_for_line(hash, line_num + 1);
+        if columns.len() > 1 {
+            let text_x = start.x + ui.calc_text_size(&line_num_str[line_num])[0];
+            for (col, addr) in columns {
+                // 1 based
+                let prefix: String = line.chars().take(col - 1).collect();
+                let s = Vector2{ x: text_x + ui.calc_text_size(&prefix)[0], y: start.y };
+                let e = Vector2{ x: s.x + char_width, y: start.y + char_height };
+
+                let matching_bp = breakpoints.iter().position(|bp| bp.point.addr == addr);
+                if ui.is_mouse_hovering_rect(s, e) {
+                    if ui.is_mouse_clicked(imgui::MouseButton::Left) {
+                        match matching_bp {
+                            Some(i) => breakpoints[i].point.enabled = !breakpoints[i].point.enabled,
+                            None => breakpoints.push(BreakPoint::new(Point::new_at_column(addr, (line_num + 1) as u64, col as u64))),
+                        }
+                    } else if ui.is_mouse_clicked(imgui::MouseButton::Right) {
+                        if let Some(i) = matching_bp {
+                            breakpoints.remove(i);
+                        }
+                    }
+                    ui.tooltip_text(format!("Column {} at 0x{:x}", col, addr));
+                }
+
+                let c = match breakpoints.iter().find(|bp| bp.point.addr == addr) {
+                    Some(bp) if bp.point.enabled => Vector4{ x: 1.0, y: 0.2, z: 0.2, w: 1.0 },
+                    Some(_) => Vector4{ x: 1.0, y: 0.2, z: 0.2, w: 0.4 },
+                    None => Vector4{ x: 0.6, y: 0.6, z: 0.6, w: 0.5 },
+                };
+                draw_list.add_rect(Vector2{ x: s.x, y: e.y - char_height * 0.15 }, e, c).filled(true).build();
+            }
+        }
         line_num += 1;
     }
 }
@@ -783,17 +876,29 @@ fn main_menu(ui: &imgui::Ui, ctx: &mut DebuggerContext, redock: &mut bool) {
                 .build();
         });
 
+        let width = std::cmp::max(ctx.debug_dirs_input.len(), 10) + 4;
+        ui.set_next_item_width(char_width * width as f32);
+        ui.input_text("Debug info dirs", &mut ctx.debug_dirs_input)
+            .hint("dir1:dir2")
+            .build();
+
         if new_input || load_pressed {
             let mut src_root = None;
             if ctx.filter_irrelevant_src {
                 src_root = Some(ctx.relevant_src_input.clone());
             }
-            ctx.session = Session::new(ctx.path_input.clone(), src_root);
+            let debug_dirs = ctx.debug_dirs_input.split(':').filter(|d| !d.is_empty()).map(PathBuf::from).collect();
+            ctx.session = Session::new(ctx.path_input.clone(), src_root, debug_dirs);
         }
 
         file_menu_token.end();
     }
 
+    if let Some(view_menu_token) = ui.begin_menu("View") {
+        ui.checkbox("Mangled names", &mut ctx.show_mangled);
+        view_menu_token.end();
+    }
+
     if ctx.session.is_err() {
         return;
     }
@@ -804,22 +909,46 @@ fn main_menu(ui: &imgui::Ui, ctx: &mut DebuggerContext, redock: &mut bool) {
 
     let mut session = ctx.session.as_mut().unwrap();
 
+    // Nothing useful to say until the exec's been read
+    if session.debug_info.debug_info.decompiled_src.is_some() {
+        ui.text_disabled(format!("Debug info: {}", session.debug_info.debug_info.dwarf_source));
+
+        let missing_dwo = &session.debug_info.debug_info.dwarf.missing_dwo;
+        if !missing_dwo.is_empty() {
+            ui.text_colored(Vector4{ x: 1.0, y: 0.6, z: 0.0, w: 1.0 }, format!("{} .dwo missing", missing_dwo.len()));
+            if ui.is_item_hovered() {
+                let paths: Vec<String> = missing_dwo.iter().map(|p| p.display().to_string()).collect();
+                ui.tooltip_text(format!("No functions or variables from these, only line info:\n{}", paths.join("\n")));
+            }
+        }
+    }
+
     match &mut session.active_run {
         Some(r) => {
             let stop = ui.button("Stop");
+            let detach = ui.button("Detach");
             let restart = ui.button("Restart");
 
             if stop || restart {
                 r.kill();
-                session.active_run = None;
+            } else if detach {
+                r.detach();
             }
+            session.retire_finished_run();
             if restart {
-                session.start_run();
+                if let Err(e) = session.start_run() {
+                    println!("Failed to start: {}", e);
+                }
             }
         },
         None => {
             if ui.button("Run") {
-                session.start_run();
+                if let Err(e) = session.start_run() {
+                    println!("Failed to start: {}", e);
+                }
+            }
+            if let Some(description) = session.finished_run.as_ref().and_then(|r| r.exit_description()) {
+                ui.text_disabled(description);
             }
         }
     }
@@ -829,39 +958,61 @@ fn main_menu(ui: &imgui::Ui, ctx: &mut DebuggerContext, redock: &mut bool) {
     }
     let run = session.active_run.as_mut().unwrap();
 
-    ui.disabled(run.running(), || {
+    let running = run.running();
+    ui.disabled(running, || {
         if ui.button("Continue") || ctx.user_inputs.cont {
             let s = ctx.session.as_mut().unwrap();
             if let Some(r) = s.active_run.as_mut() {
-                let regs = ptrace::getregs(r.debugee_pid).unwrap();
-
-                for bp in s.breakpoints.iter() {
-                    let addr = bp.point.addr + 0x555555555040 - 0x1040;
-                    if addr == (regs.rip - 1) {
-                        r.debugee_patcher.cont(addr);
-                        break;
-                    }
-                }
                 r.cont();
             }
         }
     });
+    ui.disabled(!running, || {
+        if ui.button("Pause") || ctx.user_inputs.pause {
+            let s = ctx.session.as_mut().unwrap();
+            if let Some(r) = s.active_run.as_mut() {
+                r.pause();
+            }
+        }
+    });
 
     main_menu_token.end();
 }
 
-fn reg_row(ui: &imgui::Ui, hex_values: bool, reg: &str, value: nix::libc::c_ulonglong) {
+fn reg_row(ui: &imgui::Ui, hex_values: bool, reg: &str, value: nix::libc::c_ulonglong, prev_value: Option<nix::libc::c_ulonglong>) {
+    let text = if hex_values {
+        format!("0x{:x}", value)
+    } else {
+        format!("{}", value)
+    };
+    reg_row_str(ui, reg, &text, prev_value.map_or(false, |p| p != value));
+}
+
+fn reg_row_str(ui: &imgui::Ui, reg: &str, value: &str, changed: bool) {
     ui.table_next_column();
     ui.text(reg);
     ui.table_next_column();
-    if hex_values {
-        ui.text(format!("0x{:x}", value));
+    if changed {
+        ui.text_colored(Vector4{ x: 1.0, y: 0.8, z: 0.0, w: 1.0 }, value);
     } else {
-        ui.text(format!("{}", value));
+        ui.text(value);
     }
 }
 
-fn reg_window(ui: &imgui::Ui, hex_values: &mut bool, state: &DebugeeState) {
+fn reg_table(ui: &imgui::Ui, id: &str, contents: impl FnOnce()) {
+    let col_setup = [ imgui::TableColumnSetup::new("Register"), imgui::TableColumnSetup::new("Value") ];
+    let table_token = ui.begin_table_header_with_sizing(id, col_setup, imgui::TableFlags::ROW_BG | imgui::TableFlags::BORDERS | imgui::TableFlags::SCROLL_Y, [ 0.0, 0.0 ], 100.0 );
+    if table_token.is_none() {
+        return;
+    }
+    let table_token = table_token.unwrap();
+
+    contents();
+
+    table_token.end();
+}
+
+fn reg_window(ui: &imgui::Ui, hex_values: &mut bool, vector_lane: &mut usize, state: &DebugeeState, prev_state: Option<&DebugeeState>) {
     let w = ui.window("Regs")
         .position([0.0, 300.0], imgui::Condition::FirstUseEver)
         .size([300.0, 100.0], imgui::Condition::FirstUseEver)
@@ -872,43 +1023,114 @@ fn reg_window(ui: &imgui::Ui, hex_values: &mut bool, state: &DebugeeState) {
     let w = w.unwrap();
 
     ui.checkbox("Hex", hex_values);
+    ui.same_line();
+    ui.set_next_item_width(ui.calc_text_size("0000000")[0]);
+    ui.combo("Lanes", vector_lane, &VectorLane::ALL, |l| std::borrow::Cow::Borrowed(l.name()));
+    let lane = VectorLane::ALL[*vector_lane];
 
-    let col_setup = [ imgui::TableColumnSetup::new("Register"), imgui::TableColumnSetup::new("Value") ];
-    let table_token = ui.begin_table_header_with_sizing("##", col_setup, imgui::TableFlags::ROW_BG | imgui::TableFlags::BORDERS | imgui::TableFlags::SCROLL_Y, [ 0.0, 0.0 ], 100.0 );
-    if table_token.is_none() {
+    let t = ui.tab_bar("Reg kinds");
+    if t.is_none() {
+        w.end();
         return;
     }
-    let table_token = table_token.unwrap();
+    let t = t.unwrap();
 
-    reg_row(ui, *hex_values, "r15", state.regs.r15);
-    reg_row(ui, *hex_values, "r14", state.regs.r14);
-    reg_row(ui, *hex_values, "r13", state.regs.r13);
-    reg_row(ui, *hex_values, "r12", state.regs.r12);
-    reg_row(ui, *hex_values, "rbp", state.regs.rbp);
-    reg_row(ui, *hex_values, "rbx", state.regs.rbx);
-    reg_row(ui, *hex_values, "r11", state.regs.r11);
-    reg_row(ui, *hex_values, "r10", state.regs.r10);
-    reg_row(ui, *hex_values, "r9", state.regs.r9);
-    reg_row(ui, *hex_values, "r8", state.regs.r8);
-    reg_row(ui, *hex_values, "rax", state.regs.rax);
-    reg_row(ui, *hex_values, "rcx", state.regs.rcx);
-    reg_row(ui, *hex_values, "rdx", state.regs.rdx);
-    reg_row(ui, *hex_values, "rsi", state.regs.rsi);
-    reg_row(ui, *hex_values, "rdi", state.regs.rdi);
-    reg_row(ui, *hex_values, "orig_rax", state.regs.orig_rax);
-    reg_row(ui, *hex_values, "rip", state.regs.rip);
-    reg_row(ui, *hex_values, "cs", state.regs.cs);
-    reg_row(ui, *hex_values, "eflags", state.regs.eflags);
-    reg_row(ui, *hex_values, "rsp", state.regs.rsp);
-    reg_row(ui, *hex_values, "ss", state.regs.ss);
-    reg_row(ui, *hex_values, "fs_base", state.regs.fs_base);
-    reg_row(ui, *hex_values, "gs_base", state.regs.gs_base);
-    reg_row(ui, *hex_values, "ds", state.regs.ds);
-    reg_row(ui, *hex_values, "es", state.regs.es);
-    reg_row(ui, *hex_values, "fs", state.regs.fs);
-    reg_row(ui, *hex_values, "gs", state.regs.gs);
+    let prev = prev_state.map(|p| &p.regs);
+    let hex = *hex_values;
+
+    if let Some(tab_item) = ui.tab_item("General") {
+        reg_table(ui, "##general", || {
+            reg_row(ui, hex, "r15", state.regs.r15, prev.map(|p| p.r15));
+            reg_row(ui, hex, "r14", state.regs.r14, prev.map(|p| p.r14));
+            reg_row(ui, hex, "r13", state.regs.r13, prev.map(|p| p.r13));
+            reg_row(ui, hex, "r12", state.regs.r12, prev.map(|p| p.r12));
+            reg_row(ui, hex, "rbp", state.regs.rbp, prev.map(|p| p.rbp));
+            reg_row(ui, hex, "rbx", state.regs.rbx, prev.map(|p| p.rbx));
+            reg_row(ui, hex, "r11", state.regs.r11, prev.map(|p| p.r11));
+            reg_row(ui, hex, "r10", state.regs.r10, prev.map(|p| p.r10));
+            reg_row(ui, hex, "r9", state.regs.r9, prev.map(|p| p.r9));
+            reg_row(ui, hex, "r8", state.regs.r8, prev.map(|p| p.r8));
+            reg_row(ui, hex, "rax", state.regs.rax, prev.map(|p| p.rax));
+            reg_row(ui, hex, "rcx", state.regs.rcx, prev.map(|p| p.rcx));
+            reg_row(ui, hex, "rdx", state.regs.rdx, prev.map(|p| p.rdx));
+            reg_row(ui, hex, "rsi", state.regs.rsi, prev.map(|p| p.rsi));
+            reg_row(ui, hex, "rdi", state.regs.rdi, prev.map(|p| p.rdi));
+            reg_row(ui, hex, "orig_rax", state.regs.orig_rax, prev.map(|p| p.orig_rax));
+            reg_row(ui, hex, "rip", state.regs.rip, prev.map(|p| p.rip));
+            reg_row(ui, hex, "cs", state.regs.cs, prev.map(|p| p.cs));
+            reg_row(ui, hex, "eflags", state.regs.eflags, prev.map(|p| p.eflags));
+            reg_row(ui, hex, "rsp", state.regs.rsp, prev.map(|p| p.rsp));
+            reg_row(ui, hex, "ss", state.regs.ss, prev.map(|p| p.ss));
+            reg_row(ui, hex, "fs_base", state.regs.fs_base, prev.map(|p| p.fs_base));
+            reg_row(ui, hex, "gs_base", state.regs.gs_base, prev.map(|p| p.gs_base));
+            reg_row(ui, hex, "ds", state.regs.ds, prev.map(|p| p.ds));
+            reg_row(ui, hex, "es", state.regs.es, prev.map(|p| p.es));
+            reg_row(ui, hex, "fs", state.regs.fs, prev.map(|p| p.fs));
+            reg_row(ui, hex, "gs", state.regs.gs, prev.map(|p| p.gs));
+        });
+        tab_item.end();
+    }
 
-    table_token.end();
+    if let Some(tab_item) = ui.tab_item("Flags") {
+        ui.text(format!("eflags: 0x{:x} [ {} ]", state.regs.eflags, decode_eflags(state.regs.eflags)));
+        reg_table(ui, "##flags", || {
+            for (name, mask) in EFLAGS {
+                let set = state.regs.eflags & mask != 0;
+                let changed = prev.map_or(false, |p| (p.eflags & mask != 0) != set);
+                reg_row_str(ui, name, if set { "1" } else { "0" }, changed);
+            }
+        });
+        tab_item.end();
+    }
+
+    let fp_regs = state.fp_regs.as_ref();
+    let prev_fp_regs = prev_state.and_then(|p| p.fp_regs.as_ref());
+
+    if let Some(tab_item) = ui.tab_item("x87") {
+        match fp_regs {
+            Some(fp) => reg_table(ui, "##x87", || {
+                let prev = prev_fp_regs.map(|p| &p.fpregs);
+                reg_row(ui, hex, "fctrl", fp.fpregs.cwd as u64, prev.map(|p| p.cwd as u64));
+                reg_row(ui, hex, "fstat", fp.fpregs.swd as u64, prev.map(|p| p.swd as u64));
+                reg_row(ui, hex, "ftag", fp.fpregs.ftw as u64, prev.map(|p| p.ftw as u64));
+                reg_row(ui, hex, "fop", fp.fpregs.fop as u64, prev.map(|p| p.fop as u64));
+                reg_row(ui, hex, "fip", fp.fpregs.rip, prev.map(|p| p.rip));
+                reg_row(ui, hex, "fdp", fp.fpregs.rdp, prev.map(|p| p.rdp));
+                for i in 0..8 {
+                    let st = fp.st(i);
+                    let changed = prev_fp_regs.map_or(false, |p| p.st(i) != st);
+                    reg_row_str(ui, &format!("st{}", i), &format!("{}", f80_to_f64(&st)), changed);
+                }
+            }),
+            None => ui.text("No x87 state"),
+        }
+        tab_item.end();
+    }
+
+    if let Some(tab_item) = ui.tab_item("SSE/AVX") {
+        match fp_regs {
+            Some(fp) => reg_table(ui, "##sse", || {
+                reg_row(ui, hex, "mxcsr", fp.fpregs.mxcsr as u64, prev_fp_regs.map(|p| p.fpregs.mxcsr as u64));
+                for i in 0..16 {
+                    let xmm = fp.xmm(i);
+                    let changed = prev_fp_regs.map_or(false, |p| p.xmm(i) != xmm);
+                    reg_row_str(ui, &format!("xmm{}", i), &lane.format(&xmm, hex), changed);
+                }
+                if fp.ymm_hi.is_none() {
+                    return;
+                }
+                for i in 0..16 {
+                    let ymm = fp.ymm(i).unwrap();
+                    let changed = prev_fp_regs.map_or(false, |p| p.ymm(i) != Some(ymm));
+                    reg_row_str(ui, &format!("ymm{}", i), &lane.format(&ymm, hex), changed);
+                }
+            }),
+            None => ui.text("No SSE/AVX state"),
+        }
+        tab_item.end();
+    }
+
+    t.end();
     w.end();
 }
 
@@ -921,7 +1143,7 @@ fn stack_row(ui: &imgui::Ui, col0: &str, col1: &str, col2: &str) {
     ui.table_next_column();
 }
 
-fn stack_window(ui: &imgui::Ui, pid: Pid, state: &DebugeeState, debug_info: &ThinOfflineDebugInfo, stack: &Vec<StackNode>) {
+fn stack_window(ui: &imgui::Ui, pid: Pid, state: &DebugeeState, debug_info: &ThinOfflineDebugInfo, stack: &Vec<StackNode>, show_mangled: bool) {
     let w = ui.window("Stack trace")
         .position([0.0, 300.0], imgui::Condition::FirstUseEver)
         .size([300.0, 100.0], imgui::Condition::FirstUseEver)
@@ -950,10 +1172,13 @@ fn stack_window(ui: &imgui::Ui, pid: Pid, state: &DebugeeState, debug_info: &Thi
 
     for (i, node) in stack.iter().enumerate() {
         //stack_row(ui, &format!("Frame #{}", frame_counter - i - 1), &format!("{} at 0x{:x}", names[i], ret_addresses[i]));
-        let function = match &node.location {
-            Some(l) => format!("{}:{}:{}", &node.subprogram.name, l.src_line, l.src_col),
-            None => node.subprogram.name.clone(),
+        let mut function = match &node.location {
+            Some(l) => format!("{}:{}:{}", node.subprogram.display_name(show_mangled), l.src_line, l.src_col),
+            None => node.subprogram.display_name(show_mangled).to_owned(),
         };
+        if node.inlined {
+            function.push_str(" [inlined]");
+        }
         let mut c = node.color;
         c.w = match node.selected {
             true => 0.3,
@@ -978,6 +1203,435 @@ fn stack_window(ui: &imgui::Ui, pid: Pid, state: &DebugeeState, debug_info: &Thi
     w.end();
 }
 
+fn events_window(ui: &imgui::Ui, run: &Run) {
+    let w = ui.window("Events")
+        .position([0.0, 300.0], imgui::Condition::FirstUseEver)
+        .size([300.0, 100.0], imgui::Condition::FirstUseEver)
+        .begin();
+    if w.is_none() {
+        return;
+    }
+    let w = w.unwrap();
+
+    let col_setup = [ imgui::TableColumnSetup::new("Time"), imgui::TableColumnSetup::new("Event") ];
+    let table_token = ui.begin_table_header_with_sizing("##", col_setup, imgui::TableFlags::ROW_BG | imgui::TableFlags::BORDERS | imgui::TableFlags::SCROLL_Y, [ 0.0, 0.0 ], 100.0 );
+    if table_token.is_none() {
+        w.end();
+        return;
+    }
+    let table_token = table_token.unwrap();
+
+    let start = run.event_history.first().map(|(t, _)| *t);
+    for (time, event) in &run.event_history {
+        ui.table_next_column();
+        ui.text(format!("+{:.3}s", time.duration_since(start.unwrap()).as_secs_f32()));
+        ui.table_next_column();
+        ui.text(format!("{:?}", event));
+    }
+
+    table_token.end();
+    w.end();
+}
+
+fn recording_window(ui: &imgui::Ui, run: &mut Run, addr_input: &mut String, size: &mut i32, result: &mut Option<String>) {
+    let w = ui.window("Recording")
+        .position([0.0, 300.0], imgui::Condition::FirstUseEver)
+        .size([300.0, 100.0], imgui::Condition::FirstUseEver)
+        .begin();
+    if w.is_none() {
+        return;
+    }
+    let w = w.unwrap();
+
+    let mut recording = run.recording;
+    ui.disabled(run.running(), || {
+        if ui.checkbox("Record", &mut recording) {
+            run.set_recording(recording);
+        }
+    });
+    ui.same_line();
+    ui.text_disabled("single steps everything, slow. Single threaded programs only");
+
+    if !run.recording {
+        w.end();
+        return;
+    }
+
+    ui.text(format!("{} steps recorded", run.recorded_steps()));
+    ui.disabled(!run.can_reverse(), || {
+        if ui.button("Step back") {
+            run.reverse_step();
+        }
+        ui.same_line();
+        if ui.button("Reverse continue") {
+            run.reverse_cont();
+        }
+    });
+
+    ui.separator();
+    ui.text("Last change of");
+    ui.set_next_item_width(ui.calc_text_size("0x0000000000000000")[0]);
+    ui.input_text("Address", addr_input).build();
+    ui.same_line();
+    ui.set_next_item_width(ui.calc_text_size("000000")[0]);
+    ui.input_int("Size", size).build();
+
+    let addr = u64::from_str_radix(addr_input.trim_start_matches("0x"), 16).ok();
+    let size = (*size).max(1) as usize;
+    ui.disabled(addr.is_none() || !run.can_reverse(), || {
+        if ui.button("Find") {
+            *result = Some(match run.last_change(addr.unwrap(), size) {
+                Some(c) => format!("{} steps ago at 0x{:x}: {:02x?} -> {:02x?}", c.steps_ago, c.addr, c.old, c.new),
+                None => "Not changed since recording started".to_owned(),
+            });
+        }
+        ui.same_line();
+        if ui.button("Run back to it") {
+            run.reverse_until_change(addr.unwrap(), size);
+            *result = None;
+        }
+    });
+    if let Some(result) = result {
+        ui.text(&result);
+    }
+
+    w.end();
+}
+
+fn snapshot_label(run: &Run, i: usize) -> String {
+    let snapshots = &run.timeline.snapshots;
+    let start = snapshots[0].time;
+    format!("#{} +{:.3}s {:?}", i, snapshots[i].time.duration_since(start).as_secs_f32(), snapshots[i].event)
+}
+
+fn timeline_window(ui: &imgui::Ui, run: &mut Run, view: &mut TimelineView, debug_info: &ThinOfflineDebugInfo, show_mangled: bool) {
+    let w = ui.window("Timeline")
+        .position([0.0, 300.0], imgui::Condition::FirstUseEver)
+        .size([300.0, 100.0], imgui::Condition::FirstUseEver)
+        .begin();
+    if w.is_none() {
+        return;
+    }
+    let w = w.unwrap();
+
+    let count = run.timeline.snapshots.len();
+    if count == 0 {
+        ui.text_disabled("Debugee hasn't stopped yet");
+        w.end();
+        return;
+    }
+
+    // Scrubber
+    let mut selected = view.selected.unwrap_or(count - 1).min(count - 1) as i32;
+    ui.set_next_item_width(ui.content_region_avail()[0] - ui.calc_text_size("Live")[0] * 3.0);
+    if ui.slider("##Stop", 0, count as i32 - 1, &mut selected) {
+        view.selected = Some(selected as usize);
+    }
+    ui.same_line();
+    ui.disabled(view.selected.is_none(), || {
+        if ui.button("Live") {
+            view.selected = None;
+        }
+    });
+
+    ui.text(snapshot_label(run, selected as usize));
+    if view.selected.is_some() {
+        let yellow = Vector4{ x: 1.0, y: 1.0, z: 0.2, w: 1.0 };
+        ui.text_colored(yellow, "Looking at a past stop, everything is read-only");
+    }
+
+    if ui.collapsing_header("Locals", imgui::TreeNodeFlags::empty()) {
+        let locals = &run.timeline.snapshots[selected as usize].locals;
+        if locals.is_empty() {
+            ui.text_disabled("Nothing in scope");
+        }
+        for (name, value) in locals {
+            ui.text(format!("{} = {}", name, value));
+        }
+    }
+
+    if ui.collapsing_header("Watched memory", imgui::TreeNodeFlags::empty()) {
+        ui.text_disabled("Saved in every snapshot from now on, on top of the stack");
+        let mut remove = None;
+        for (i, (addr, len)) in run.watched_ranges.iter().enumerate() {
+            ui.text(format!("0x{:x} ({} bytes)", addr, len));
+            ui.same_line();
+            if ui.small_button(format!("Remove##watch{}", i)) {
+                remove = Some(i);
+            }
+        }
+        if let Some(i) = remove {
+            run.watched_ranges.remove(i);
+        }
+
+        ui.set_next_item_width(ui.calc_text_size("0x0000000000000000")[0]);
+        ui.input_text("Address##watch", &mut view.watch_addr).build();
+        ui.same_line();
+        ui.set_next_item_width(ui.calc_text_size("000000")[0]);
+        ui.input_int("Size##watch", &mut view.watch_size).build();
+        ui.same_line();
+        let addr = u64::from_str_radix(view.watch_addr.trim_start_matches("0x"), 16).ok();
+        ui.disabled(addr.is_none(), || {
+            if ui.button("Watch") {
+                run.watched_ranges.push((addr.unwrap(), view.watch_size.max(1) as usize));
+            }
+        });
+    }
+
+    if ui.collapsing_header("Diff", imgui::TreeNodeFlags::empty()) {
+        ui.slider("A", 0, count as i32 - 1, &mut view.diff_a);
+        ui.slider("B", 0, count as i32 - 1, &mut view.diff_b);
+        let a = &run.timeline.snapshots[view.diff_a.clamp(0, count as i32 - 1) as usize];
+        let b = &run.timeline.snapshots[view.diff_b.clamp(0, count as i32 - 1) as usize];
+        let diff = degrugger::timeline::diff(a, b);
+
+        ui.text(format!("{} registers changed", diff.regs.len()));
+        for (name, old, new) in &diff.regs {
+            ui.text(format!("{:>8} 0x{:x} -> 0x{:x}", name, old, new));
+        }
+
+        ui.separator();
+        ui.text(format!("{} locals changed", diff.locals.len()));
+        for (name, old, new) in &diff.locals {
+            // Out of scope on that side
+            let old = old.as_deref().unwrap_or("-");
+            let new = new.as_deref().unwrap_or("-");
+            ui.text(format!("{:>8} {} -> {}", name, old, new));
+        }
+
+        ui.separator();
+        ui.text(format!("{} memory ranges changed", diff.memory.len()));
+        ui.text_disabled("Only memory saved in both snapshots");
+        for (addr, old, new) in &diff.memory {
+            ui.text(format!("0x{:x}: {:02x?} -> {:02x?}", addr, old, new));
+        }
+
+        ui.separator();
+        // Stacks are stored oldest frame first, same as the stack window
+        let stack_a = generate_stack(|addr, len| a.read_memory(addr, len), &a.state, debug_info);
+        let stack_b = generate_stack(|addr, len| b.read_memory(addr, len), &b.state, debug_info);
+        ui.columns(2, "##stacks", true);
+        ui.text("Stack A");
+        for node in &stack_a {
+            ui.text(node.subprogram.display_name(show_mangled));
+        }
+        ui.next_column();
+        ui.text("Stack B");
+        for node in &stack_b {
+            ui.text(node.subprogram.display_name(show_mangled));
+        }
+        ui.columns(1, "##stacks", false);
+    }
+
+    w.end();
+}
+
+fn checkpoints_window(ui: &imgui::Ui, run: &mut Run) {
+    let w = ui.window("Checkpoints")
+        .position([0.0, 300.0], imgui::Condition::FirstUseEver)
+        .size([300.0, 100.0], imgui::Condition::FirstUseEver)
+        .begin();
+    if w.is_none() {
+        return;
+    }
+    let w = w.unwrap();
+
+    let stopped = !run.running() && !run.finished();
+    ui.disabled(!stopped, || {
+        if ui.button("Checkpoint") {
+            if let Err(e) = run.checkpoint() {
+                println!("Failed to take a checkpoint: {}", e);
+            }
+        }
+    });
+    ui.same_line();
+    ui.text_disabled("forks the debugee, restarting a checkpoint kills the current one");
+
+    let col_setup = [ imgui::TableColumnSetup::new("Id"), imgui::TableColumnSetup::new("Time"), imgui::TableColumnSetup::new("Stop"), imgui::TableColumnSetup::new("##actions") ];
+    let table_token = ui.begin_table_header_with_sizing("##", col_setup, imgui::TableFlags::ROW_BG | imgui::TableFlags::BORDERS | imgui::TableFlags::SCROLL_Y, [ 0.0, 0.0 ], 100.0 );
+    if table_token.is_none() {
+        w.end();
+        return;
+    }
+    let table_token = table_token.unwrap();
+
+    let mut restore = None;
+    let mut drop = None;
+    let start = run.event_history.first().map(|(t, _)| *t);
+    for checkpoint in &run.checkpoints {
+        ui.table_next_column();
+        ui.text(format!("#{}", checkpoint.id));
+        ui.table_next_column();
+        ui.text(format!("+{:.3}s", start.map_or(0.0, |s| checkpoint.time.duration_since(s).as_secs_f32())));
+        ui.table_next_column();
+        ui.text(format!("0x{:x} {:?}", checkpoint.addr, checkpoint.event));
+        ui.table_next_column();
+        ui.disabled(!stopped, || {
+            if ui.small_button(format!("Restart##{}", checkpoint.id)) {
+                restore = Some(checkpoint.id);
+            }
+        });
+        ui.same_line();
+        if ui.small_button(format!("Delete##{}", checkpoint.id)) {
+            drop = Some(checkpoint.id);
+        }
+    }
+
+    table_token.end();
+    w.end();
+
+    if let Some(id) = restore {
+        if let Err(e) = run.restore_checkpoint(id) {
+            println!("Failed to restore checkpoint #{}: {}", id, e);
+        }
+    }
+    if let Some(id) = drop {
+        run.drop_checkpoint(id);
+    }
+}
+
+fn output_window(ui: &imgui::Ui, run: &Run) {
+    let w = ui.window("Output")
+        .position([0.0, 300.0], imgui::Condition::FirstUseEver)
+        .size([300.0, 100.0], imgui::Condition::FirstUseEver)
+        .begin();
+    if w.is_none() {
+        return;
+    }
+    let w = w.unwrap();
+
+    if let Some(description) = run.exit_description() {
+        ui.text_disabled(description);
+    }
+
+    // TODO: don't copy the whole thing every frame
+    let output = String::from_utf8_lossy(&run.output.lock().unwrap()).into_owned();
+    let at_bottom = ui.scroll_y() >= ui.scroll_max_y();
+    ui.text(&output);
+    // Stick to the bottom while new output comes in, unless the user scrolled up
+    if at_bottom {
+        ui.set_scroll_here_y_with_ratio(1.0);
+    }
+
+    w.end();
+}
+
+fn function_break_window(ui: &imgui::Ui, breakpoints: &mut Vec<BreakPoint>, debug_info: &ThinOfflineDebugInfo, view: &mut FunctionBreakView, show_mangled: bool) {
+    let w = ui.window("Function breakpoint")
+        .position([0.0, 300.0], imgui::Condition::FirstUseEver)
+        .size([300.0, 100.0], imgui::Condition::FirstUseEver)
+        .begin();
+    if w.is_none() {
+        return;
+    }
+    let w = w.unwrap();
+
+    let entered = ui.input_text("##function", &mut view.input)
+        .hint(match view.regex {
+            true => "regex",
+            false => "foo, ns::Class::foo or a linkage name",
+        })
+        .flags(InputTextFlags::ENTER_RETURNS_TRUE)
+        .build();
+    ui.same_line();
+    ui.checkbox("Regex", &mut view.regex);
+    ui.same_line();
+    if (ui.button("Break") || entered) && !view.input.is_empty() {
+        view.result = Some(match debug_info.find_functions(&view.input, view.regex) {
+            Ok(functions) if functions.is_empty() => format!("No function matching \"{}\" in the executable, shared libraries aren't searched", view.input),
+            Ok(functions) => {
+                let mut names = vec![];
+                for function in functions {
+                    let addr = debug_info.breakpoint_addr(function);
+                    let line = debug_info.location_at(addr).map_or(0, |(_, l)| l.src_line);
+                    breakpoints.push(BreakPoint::new(Point::new(addr, line as u64)));
+                    names.push(format!("0x{:x} {}", addr, function.display_name(show_mangled)));
+                }
+                format!("{} breakpoints:\n{}", names.len(), names.join("\n"))
+            },
+            Err(e) => e,
+        });
+    }
+
+    if let Some(result) = &view.result {
+        ui.text(result);
+    }
+
+    w.end();
+}
+
+fn signals_window(ui: &imgui::Ui, session: &mut Session, inject_signal: &mut usize) {
+    let w = ui.window("Signals")
+        .position([0.0, 300.0], imgui::Condition::FirstUseEver)
+        .size([300.0, 100.0], imgui::Condition::FirstUseEver)
+        .begin();
+    if w.is_none() {
+        return;
+    }
+    let w = w.unwrap();
+
+    if let Some(r) = &mut session.active_run {
+        if let Some(state) = &r.debugee_state {
+            if let Some(sig) = state.signal {
+                let desc = match &state.siginfo {
+                    Some(info) => signals::describe_siginfo(sig, info),
+                    None => format!("{}", sig),
+                };
+                ui.text_colored(Vector4{ x: 1.0, y: 0.2, z: 0.2, w: 1.0 }, format!("Stopped by {}", desc));
+            }
+        }
+
+        match r.pending_signal {
+            Some(sig) => {
+                ui.text(format!("{} will be delivered on continue", sig));
+                ui.same_line();
+                if ui.button("Discard") {
+                    r.pending_signal = None;
+                }
+            },
+            None => ui.text("No signal will be delivered on continue"),
+        }
+
+        let all_signals: Vec<Signal> = Signal::iterator().collect();
+        ui.set_next_item_width(ui.calc_text_size("SIGVTALRM0000")[0]);
+        ui.combo("##inject", inject_signal, &all_signals, |s| std::borrow::Cow::Owned(format!("{}", s)));
+        ui.same_line();
+        if ui.button("Inject") {
+            r.inject_signal(all_signals[*inject_signal]);
+        }
+
+        if let Some(log_token) = ui.tree_node("Signal log") {
+            for entry in &r.signal_log {
+                ui.text(entry);
+            }
+            log_token.end();
+        }
+    }
+
+    let col_setup = [ imgui::TableColumnSetup::new("Signal"), imgui::TableColumnSetup::new("Stop"), imgui::TableColumnSetup::new("Print"), imgui::TableColumnSetup::new("Pass") ];
+    let table_token = ui.begin_table_header_with_sizing("##", col_setup, imgui::TableFlags::ROW_BG | imgui::TableFlags::BORDERS | imgui::TableFlags::SCROLL_Y, [ 0.0, 0.0 ], 100.0 );
+    if table_token.is_none() {
+        w.end();
+        return;
+    }
+    let table_token = table_token.unwrap();
+
+    let mut policies = session.signal_policies.lock().unwrap();
+    for (sig, policy) in policies.iter_mut() {
+        ui.table_next_column();
+        ui.text(format!("{}", sig));
+        ui.table_next_column();
+        ui.checkbox(format!("##stop{}", sig), &mut policy.stop);
+        ui.table_next_column();
+        ui.checkbox(format!("##print{}", sig), &mut policy.print);
+        ui.table_next_column();
+        ui.checkbox(format!("##pass{}", sig), &mut policy.pass);
+    }
+
+    table_token.end();
+    w.end();
+}
+
 use std::sync::Arc;
 use std::path::PathBuf;
 
@@ -989,10 +1643,11 @@ fn main() {
 
     let mut system = support::init(file!());
 
-    let mut ctx = DebuggerContext { path_input: String::new(), relevant_src_input: String::new(), filter_irrelevant_src: false, session: Err(()), hex_values: true, user_inputs: UserInputs{ cont: false, focus_bp: false } };
+    let mut ctx = DebuggerContext { path_input: String::new(), relevant_src_input: String::new(), filter_irrelevant_src: false, debug_dirs_input: String::new(), session: Err(()), hex_values: true, show_mangled: false, vector_lane: 0, inject_signal: 0, last_change_addr: String::new(), last_change_size: 8, last_change: None, timeline: TimelineView{ selected: None, diff_a: 0, diff_b: 0, watch_addr: String::new(), watch_size: 8 }, function_break: FunctionBreakView{ input: String::new(), regex: false, result: None }, disassembly: DisassemblyView{ jump_input: String::new(), jump_to: None, highlighted: None, show_source: true, show_bytes: false }, user_inputs: UserInputs{ cont: false, pause: false, focus_bp: false } };
     ctx.path_input = "/home/savas/Projects/degrugger/test_code/stack_test.out".to_owned();
     ctx.path_input.reserve(512);
     ctx.relevant_src_input.reserve(512);
+    ctx.debug_dirs_input.reserve(512);
 
     let line_num_str: Vec<String> = (1..1000000).map(|x| format!("{: >4}   ", x)).collect();
 
@@ -1062,6 +1717,13 @@ fn main() {
                 let mut buf3 = imgui::UiBuffer::new(16);
                 let mut buf4 = imgui::UiBuffer::new(16);
                 let mut buf5 = imgui::UiBuffer::new(16);
+                let mut buf6 = imgui::UiBuffer::new(16);
+                let mut buf7 = imgui::UiBuffer::new(16);
+                let mut buf8 = imgui::UiBuffer::new(16);
+                let mut buf9 = imgui::UiBuffer::new(16);
+                let mut buf10 = imgui::UiBuffer::new(16);
+                let mut buf11 = imgui::UiBuffer::new(16);
+                let mut buf12 = imgui::UiBuffer::new(32);
 
                 buf.scratch_txt("Src code");
                 sys::igDockBuilderDockWindow(buf.buffer.as_ptr() as *const i8, dockspace_id);
@@ -1075,10 +1737,31 @@ fn main() {
                 buf2.scratch_txt("Regs");
                 sys::igDockBuilderDockWindow(buf2.buffer.as_ptr() as *const i8, dock_id_down);
 
+                buf6.scratch_txt("Signals");
+                sys::igDockBuilderDockWindow(buf6.buffer.as_ptr() as *const i8, dock_id_down);
+
                 let left_to_regs = sys::igDockBuilderSplitNode(dock_id_down, sys::ImGuiDir_Right, 0.5, std::ptr::null::<u32>() as *mut u32, &mut dock_id_down as *mut u32);
                 buf3.scratch_txt("Stack trace");
                 sys::igDockBuilderDockWindow(buf3.buffer.as_ptr() as *const i8, left_to_regs);
 
+                buf7.scratch_txt("Events");
+                sys::igDockBuilderDockWindow(buf7.buffer.as_ptr() as *const i8, left_to_regs);
+
+                buf8.scratch_txt("Output");
+                sys::igDockBuilderDockWindow(buf8.buffer.as_ptr() as *const i8, left_to_regs);
+
+                buf9.scratch_txt("Recording");
+                sys::igDockBuilderDockWindow(buf9.buffer.as_ptr() as *const i8, dock_id_down);
+
+                buf10.scratch_txt("Timeline");
+                sys::igDockBuilderDockWindow(buf10.buffer.as_ptr() as *const i8, dock_id_down);
+
+                buf11.scratch_txt("Checkpoints");
+                sys::igDockBuilderDockWindow(buf11.buffer.as_ptr() as *const i8, dock_id_down);
+
+                buf12.scratch_txt("Function breakpoint");
+                sys::igDockBuilderDockWindow(buf12.buffer.as_ptr() as *const i8, dock_id_down);
+
                 sys::igDockBuilderFinish(dockspace_id);
             }
             sys::igEnd();
@@ -1087,6 +1770,7 @@ fn main() {
         let c_pressed = ui.is_key_pressed_no_repeat(imgui::Key::C);
         ctx.user_inputs = UserInputs {
             cont: c_pressed,
+            pause: ui.is_key_pressed_no_repeat(imgui::Key::P),
             focus_bp: c_pressed || ui.is_key_pressed_no_repeat(imgui::Key::Period),
         };
 
@@ -1095,13 +1779,8 @@ fn main() {
             s.sync_workers();
             if let Some(r) = &mut s.active_run {
                 r.poll_debugee_state(false);
-
-                // TODO: this is some atrocious shit
-                if let Some(Err(nix::errno::Errno::EOWNERDEAD)) = r.debugee_event {
-                    r.kill();
-                    s.active_run = None;
-                }
             }
+            s.retire_finished_run();
         }
 
         //let mut t = true;
@@ -1110,24 +1789,58 @@ fn main() {
         main_menu(ui, &mut ctx, &mut first_time);
 
         if let Ok(s) = &mut ctx.session {
-            let mut maybe_state = &None;
+            signals_window(ui, s, &mut ctx.inject_signal);
+            function_break_window(ui, &mut s.breakpoints, &s.debug_info.debug_info, &mut ctx.function_break, ctx.show_mangled);
+            if let Some(r) = &mut s.active_run {
+                recording_window(ui, r, &mut ctx.last_change_addr, &mut ctx.last_change_size, &mut ctx.last_change);
+                checkpoints_window(ui, r);
+            }
 
-            if let Some(r) = &s.active_run {
-                if let Some(state) = &r.debugee_state {
+            if let Some(r) = s.active_run.as_mut().or(s.finished_run.as_mut()) {
+                timeline_window(ui, r, &mut ctx.timeline, &s.debug_info.debug_info, ctx.show_mangled);
+            }
+
+            let mut maybe_state = None;
+
+            // Finished runs are only good for looking at what happened
+            if let Some(r) = s.active_run.as_ref().or(s.finished_run.as_ref()) {
+                events_window(ui, r);
+                output_window(ui, r);
 
-                    reg_window(ui, &mut ctx.hex_values, &state);
-                    let stack = generate_stack(r.debugee_pid, &state, &s.debug_info.debug_info);
-                    stack_window(ui, r.debugee_pid, &state, &s.debug_info.debug_info, &stack);
+                // Can't read a finished run's memory anymore, show its last stop instead
+                let snapshots = &r.timeline.snapshots;
+                let selected = match ctx.timeline.selected {
+                    Some(i) if i < snapshots.len() => Some(i),
+                    _ if r.finished() => snapshots.len().checked_sub(1),
+                    _ => None,
+                };
+
+                if let Some(i) = selected {
+                    let snapshot = &snapshots[i];
+                    let prev = if i > 0 { Some(&snapshots[i - 1].state) } else { None };
+                    reg_window(ui, &mut ctx.hex_values, &mut ctx.vector_lane, &snapshot.state, prev);
+                    let stack = generate_stack(|addr, len| snapshot.read_memory(addr, len), &snapshot.state, &s.debug_info.debug_info);
+                    stack_window(ui, r.debugee_pid, &snapshot.state, &s.debug_info.debug_info, &stack, ctx.show_mangled);
+                    inlined_stack_window(ui, &snapshot.state, &line_num_str, &mut s.breakpoints, &s.debug_info.debug_info, &stack, &s.debug_info.src_files);
+                    maybe_state = Some(&snapshot.state);
+                } else if let Some(state) = &r.debugee_state {
+
+                    reg_window(ui, &mut ctx.hex_values, &mut ctx.vector_lane, &state, r.prev_debugee_state.as_ref());
+                    let stack = generate_stack(|addr, len| r.read_memory(addr, len).ok(), &state, &s.debug_info.debug_info);
+                    stack_window(ui, r.debugee_pid, &state, &s.debug_info.debug_info, &stack, ctx.show_mangled);
                     //stack_window(ui, r.debugee_pid, &state, &s.function_ranges, &s.debug_info.debug_info);
 
                     inlined_stack_window(ui, &state, &line_num_str, &mut s.breakpoints, &s.debug_info.debug_info, &stack, &s.debug_info.src_files);
-                maybe_state = &r.debugee_state;
+                maybe_state = Some(state);
                 }
             }
 
             //code_windows(ui, &s.open_files, maybe_state, &line_num_str, &mut s.breakpoints);
             code_windows(ui, &ctx.user_inputs, &s.debug_info.src_files, maybe_state, &line_num_str, &mut s.breakpoints, &s.debug_info.debug_info);
-            disassembly_window(ui, &ctx.user_inputs, maybe_state, &line_num_str, &mut s.breakpoints, &s.debug_info.debug_info);
+            disassembly_window(ui, &ctx.user_inputs, maybe_state, &line_num_str, &mut s.breakpoints, &s.debug_info.debug_info, &s.debug_info.src_files, &mut ctx.disassembly, ctx.show_mangled);
+
+            // Gutter clicks only touch the session's list, push them into the run
+            s.reconcile_bp_state_with_run();
             //inlined_stack_window(ui, stopped_state);
             //stack_window(ui, stopped_state);
         }
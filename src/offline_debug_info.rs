@@ -1,4 +1,4 @@
-use std::collections::{ HashMap, HashSet };
+use std::collections::HashMap;
 use std::marker::Send;
 use std::io::Result;
 use std::path::PathBuf;
@@ -7,9 +7,14 @@ use std::sync::atomic::{ AtomicBool, Ordering };
 use std::sync::mpsc::{ channel, Receiver, Sender, TryRecvError };
 use std::thread::{ Builder, JoinHandle };
 
-use iced_x86::{ Decoder, DecoderOptions, Formatter, Instruction, NasmFormatter, FastFormatter };
+use iced_x86::{ Decoder, DecoderOptions, Formatter, Instruction, NasmFormatter };
 
 use crate::SrcFile;
+use crate::src_file::path_hash;
+use crate::dwarf_model::{ DwarfModel, InlinedFrame };
+use crate::debug_file::{ find_debug_file, build_id, DebugInfoSource };
+use crate::debuginfod::Debuginfod;
+use crate::symbols::{ symbol_subprograms, merge_symbols, strip_params, SymbolMap };
 
 type OfflineAddr = u64;
 
@@ -17,33 +22,354 @@ type OfflineAddr = u64;
 pub struct ThinOfflineDebugInfo {
     pub decompiled_src: Option<Arc<DecompiledSrc>>,
     pub src_file_info: HashMap<u64, Arc<SrcFileDebugInfo>>,
+    // DWARF ones from every unit, then symbols for whatever they don't cover
     pub all_subprograms: Arc<Vec<Subprogram>>,
+    // From .symtab/.dynsym and the PLT
+    pub symbols: Arc<Vec<Subprogram>>,
+    // Parsed once when the exec is read, everything above is derived from it
+    pub dwarf: Arc<DwarfModel>,
+    pub dwarf_source: DebugInfoSource,
+    // Sorted lookups over all_subprograms and the line tables, built once along with them
+    pub index: Arc<AddrIndex>,
+}
+
+#[derive(Debug, Default)]
+pub struct AddrIndex {
+    // (low, high, index into all_subprograms) of every range, sorted by low
+    subprograms: Vec<(OfflineAddr, OfflineAddr, usize)>,
+    // Highest high of subprograms[..=i], nothing at or before i can contain anything above it
+    max_high: Vec<OfflineAddr>,
+    // (addr, src file hash, location), sorted by addr
+    locations: Vec<(OfflineAddr, u64, BreakableSrcLocation)>,
+    // src file hash -> (line, addr), sorted
+    lines: HashMap<u64, Vec<(usize, OfflineAddr)>>,
+    // src file hash -> (line, column, addr), sorted
+    columns: HashMap<u64, Vec<(usize, usize, OfflineAddr)>>,
+    // Rows marked as the end of a function's prologue, sorted
+    prologue_ends: Vec<OfflineAddr>,
+}
+
+impl AddrIndex {
+    // locations is every line table row, see line_rows
+    fn new(all_subprograms: &Vec<Subprogram>, locations: Vec<(OfflineAddr, u64, BreakableSrcLocation)>) -> Self {
+        let mut index = AddrIndex::default();
+
+        for (i, subprogram) in all_subprograms.iter().enumerate() {
+            for (low, high) in &subprogram.ranges {
+                index.subprograms.push((*low, *high, i));
+            }
+        }
+        index.subprograms.sort_by_key(|(low, _, _)| *low);
+        let mut max_high = 0;
+        for (_, high, _) in &index.subprograms {
+            max_high = max_high.max(*high);
+            index.max_high.push(max_high);
+        }
+
+        for (_, hash, location) in &locations {
+            index.lines.entry(*hash).or_insert(vec![]).push((location.src_line, location.addr));
+            index.columns.entry(*hash).or_insert(vec![]).push((location.src_line, location.src_col, location.addr));
+        }
+        for lines in index.lines.values_mut() {
+            lines.sort();
+            lines.dedup();
+        }
+        for columns in index.columns.values_mut() {
+            columns.sort();
+            columns.dedup();
+        }
+        index.locations = locations;
+
+        index
+    }
+}
+
+// (addr, src file hash, location) of every row of every unit, sorted by addr. Stable, so rows
+// sharing an address keep the line table order
+fn line_rows(dwarf: &DwarfModel) -> Vec<(OfflineAddr, u64, BreakableSrcLocation)> {
+    let mut locations = vec![];
+    for unit in &dwarf.units {
+        let hashes: Vec<Option<u64>> = unit.files.iter().map(|f| f.as_deref().map(path_hash)).collect();
+        for row in unit.rows.iter().filter(|r| !r.end_sequence) {
+            if let Some(Some(hash)) = hashes.get(row.file as usize) {
+                locations.push((row.addr, *hash, BreakableSrcLocation{ addr: row.addr, src_line: row.line, src_col: row.col.max(1) }));
+            }
+        }
+    }
+    locations.sort_by_key(|(addr, _, _)| *addr);
+    locations
+}
+
+// Functions with code, lines from the rows of the file they're declared in. locations as from
+// line_rows
+fn dwarf_subprograms(dwarf: &DwarfModel, locations: &Vec<(OfflineAddr, u64, BreakableSrcLocation)>) -> Vec<Subprogram> {
+    let mut subprograms = vec![];
+    for unit in &dwarf.units {
+        for dwarf_subprogram in unit.subprograms.iter().filter(|s| !s.inline && !s.ranges.is_empty()) {
+            let src_file_hash = match &dwarf_subprogram.decl_file {
+                Some(path) => path_hash(path),
+                None => continue,
+            };
+
+            let mut start_line = 0;
+            let mut end_line = 0;
+            for (low, high) in &dwarf_subprogram.ranges {
+                let from = locations.partition_point(|(a, _, _)| a < low);
+                for (addr, hash, location) in locations[from..].iter().take_while(|(a, _, _)| a < high) {
+                    if *hash != src_file_hash {
+                        continue;
+                    }
+                    match *addr == dwarf_subprogram.low_addr {
+                        true => start_line = location.src_line,
+                        false => end_line = end_line.max(location.src_line),
+                    }
+                }
+            }
+
+            // TODO: well if there's a single line function...
+            if start_line == end_line {
+                continue;
+            }
+
+            subprograms.push(Subprogram{ name: dwarf_subprogram.name.clone(), linkage_name: dwarf_subprogram.linkage_name.clone(), low_addr: dwarf_subprogram.low_addr, high_addr: dwarf_subprogram.high_addr, ranges: dwarf_subprogram.ranges.clone(), src_file_hash: src_file_hash, start_line: start_line, end_line: end_line });
+        }
+    }
+    subprograms
 }
 
 impl ThinOfflineDebugInfo {
     fn empty() -> ThinOfflineDebugInfo {
-        ThinOfflineDebugInfo{ decompiled_src: None, src_file_info: HashMap::new(), all_subprograms: Arc::new(vec![])}
+        ThinOfflineDebugInfo{ decompiled_src: None, src_file_info: HashMap::new(), all_subprograms: Arc::new(vec![]), symbols: Arc::new(vec![]), dwarf: Arc::new(DwarfModel::empty()), dwarf_source: DebugInfoSource::Missing, index: Arc::new(AddrIndex::default()) }
+    }
+
+    // Once the DWARF and the symbols are in. Covers every file, whether its source got loaded or not
+    fn build_index(&mut self) {
+        let locations = line_rows(&self.dwarf);
+        let dwarf_subprograms = dwarf_subprograms(&self.dwarf, &locations);
+        self.all_subprograms = Arc::new(merge_symbols(dwarf_subprograms, &self.symbols));
+        let mut index = AddrIndex::new(&self.all_subprograms, locations);
+        index.prologue_ends = self.dwarf.units.iter()
+            .flat_map(|unit| unit.rows.iter())
+            .filter(|row| row.prologue_end && !row.end_sequence)
+            .map(|row| row.addr)
+            .collect();
+        index.prologue_ends.sort();
+        index.prologue_ends.dedup();
+        self.index = Arc::new(index);
+    }
+
+    // Subprogram containing the offline addr and its index into all_subprograms
+    pub fn subprogram_at(&self, addr: OfflineAddr) -> Option<(usize, &Subprogram)> {
+        let candidates = self.index.subprograms.partition_point(|(low, _, _)| *low <= addr);
+        // Concrete functions don't overlap, so this almost always stops at the first one
+        for j in (0..candidates).rev() {
+            if self.index.max_high[j] < addr {
+                break;
+            }
+            let (_, high, i) = self.index.subprograms[j];
+            if addr <= high {
+                return Some((i, &self.all_subprograms[i]));
+            }
+        }
+
+        None
+    }
+
+    // Line table row covering the offline addr, (src file hash, location)
+    // NOTE: doesn't know where sequences end, so anything past the last row of a file still maps to it
+    pub fn location_at(&self, addr: OfflineAddr) -> Option<(u64, &BreakableSrcLocation)> {
+        let i = self.index.locations.partition_point(|(a, _, _)| *a <= addr);
+        if i == 0 {
+            return None;
+        }
+
+        let (_, hash, location) = &self.index.locations[i - 1];
+        Some((*hash, location))
+    }
+
+    // Functions inlined at the offline addr, innermost first, as subprograms of their own so they can
+    // be shown like any other frame. The lines are the ones of the inlined function's body
+    pub fn inlined_at(&self, addr: OfflineAddr) -> Vec<(Subprogram, InlinedFrame)> {
+        self.dwarf.inlined_at(addr).into_iter().map(|inlined| {
+            let src_file_hash = inlined.decl_file.as_deref().map_or(0, path_hash);
+            let lines: Vec<usize> = self.index.locations.iter()
+                .skip(self.index.locations.partition_point(|(a, _, _)| *a < inlined.low_addr))
+                .take_while(|(a, _, _)| *a < inlined.high_addr)
+                .filter(|(_, hash, _)| *hash == src_file_hash)
+                .map(|(_, _, location)| location.src_line)
+                .collect();
+            let end_line = lines.iter().max().cloned().unwrap_or(inlined.decl_line);
+            let start_line = match inlined.decl_line {
+                0 => lines.iter().min().cloned().unwrap_or(0),
+                l => l,
+            };
+
+            let subprogram = Subprogram{ name: inlined.name.clone(), linkage_name: inlined.linkage_name.clone(), low_addr: inlined.low_addr, high_addr: inlined.high_addr, ranges: inlined.ranges.clone(), src_file_hash: src_file_hash, start_line: start_line, end_line: end_line };
+            (subprogram, inlined)
+        }).collect()
+    }
+
+    // Address of the instruction right before addr in .text
+    pub fn prev_instruction(&self, addr: OfflineAddr) -> Option<OfflineAddr> {
+        let addresses = &self.decompiled_src.as_ref()?.addresses;
+        let i = addresses.partition_point(|a| *a < addr);
+        if i == 0 {
+            return None;
+        }
+
+        Some(addresses[i - 1])
+    }
+
+    // Every address the line has code at, lowest first
+    pub fn addresses_for_line(&self, src_file_hash: u64, line: usize) -> Vec<OfflineAddr> {
+        let lines = match self.index.lines.get(&src_file_hash) {
+            Some(l) => l,
+            None => return vec![],
+        };
+        let start = lines.partition_point(|(l, _)| *l < line);
+        let end = lines.partition_point(|(l, _)| *l <= line);
+
+        lines[start..end].iter().map(|(_, addr)| *addr).collect()
+    }
+
+    // (column, lowest addr) of every column of the line that has code, leftmost first
+    pub fn columns_for_line(&self, src_file_hash: u64, line: usize) -> Vec<(usize, OfflineAddr)> {
+        let columns = match self.index.columns.get(&src_file_hash) {
+            Some(c) => c,
+            None => return vec![],
+        };
+        let start = columns.partition_point(|(l, _, _)| *l < line);
+        let end = columns.partition_point(|(l, _, _)| *l <= line);
+
+        let mut result: Vec<(usize, OfflineAddr)> = columns[start..end].iter().map(|(_, col, addr)| (*col, *addr)).collect();
+        // Sorted by column then address, so the first one of each column is the lowest
+        result.dedup_by_key(|(col, _)| *col);
+        result
+    }
+
+    // (line, lowest addr) of the first line at or after this one that has any code
+    pub fn line_with_code(&self, src_file_hash: u64, line: usize) -> Option<(usize, OfflineAddr)> {
+        let lines = self.index.lines.get(&src_file_hash)?;
+        let i = lines.partition_point(|(l, _)| *l < line);
+
+        lines.get(i).cloned()
+    }
+
+    // Functions called pattern (foo matches ns::Foo::foo too, or the linkage name), or whose name
+    // matches pattern as a regex. One per entry address.
+    // NOTE: only the exec's functions. Nothing reads the shared libraries the debugee maps, so for
+    // library functions there's just the PLT stub (puts matches puts@plt)
+    pub fn find_functions(&self, pattern: &str, is_regex: bool) -> std::result::Result<Vec<&Subprogram>, String> {
+        let regex = match is_regex {
+            true => Some(regex::Regex::new(pattern).map_err(|e| e.to_string())?),
+            false => None,
+        };
+        let qualified_suffix = format!("::{}", pattern);
+
+        let mut functions: Vec<&Subprogram> = self.all_subprograms.iter()
+            .filter(|s| s.high_addr > s.low_addr)
+            .filter(|s| match &regex {
+                Some(regex) => regex.is_match(&s.name) || s.linkage_name.as_ref().map_or(false, |l| regex.is_match(l)),
+                None => {
+                    // puts also means puts@plt, that's all there is until libc is loaded
+                    let name = strip_params(s.name.trim_end_matches("@plt"));
+                    name == pattern || name.ends_with(&qualified_suffix) || s.linkage_name.as_deref() == Some(pattern)
+                },
+            })
+            .collect();
+        functions.sort_by_key(|s| s.low_addr);
+        functions.dedup_by_key(|s| s.low_addr);
+
+        Ok(functions)
+    }
+
+    // Where a breakpoint on the function should go: past the prologue, so the arguments are where
+    // the DWARF says they are. The row the compiler marked prologue_end, otherwise the second row
+    // like gdb does, otherwise the entry for functions without lines
+    pub fn breakpoint_addr(&self, subprogram: &Subprogram) -> OfflineAddr {
+        let i = self.index.prologue_ends.partition_point(|a| *a < subprogram.low_addr);
+        match self.index.prologue_ends.get(i) {
+            Some(addr) if *addr < subprogram.high_addr => return *addr,
+            _ => {},
+        }
+
+        let i = self.index.locations.partition_point(|(a, _, _)| *a <= subprogram.low_addr);
+        match self.index.locations.get(i) {
+            Some((addr, _, _)) if *addr < subprogram.high_addr && i > 0 && self.index.locations[i - 1].0 == subprogram.low_addr => *addr,
+            _ => subprogram.low_addr,
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct DecompiledSrc {
     pub instructions: Vec<Instruction>,
+    // Formatted with symbols, without the address
     pub decompiled_src: Vec<String>,
+    // Hex of the instruction bytes
+    pub bytes: Vec<String>,
     pub addresses: Vec<OfflineAddr>,
+    // Function headers and instructions
+    pub rows: Vec<DisassemblyRow>,
+    // Same with the source lines mixed in
+    pub interleaved_rows: Vec<DisassemblyRow>,
+}
+
+impl DecompiledSrc {
+    // Row showing the instruction, headers and source lines come before it
+    pub fn row_of(rows: &[DisassemblyRow], instruction: usize) -> usize {
+        rows.partition_point(|r| r.instruction < instruction || (r.instruction == instruction && r.kind != DisassemblyRowKind::Instruction))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisassemblyRowKind {
+    // Header at the start of every function range
+    Function,
+    // Source line the following instructions are for
+    Source{ file_hash: u64, line: usize },
+    Instruction,
+}
+
+// instruction is the index of the instruction the row is for, or the one right after a header
+#[derive(Debug, Clone, Copy)]
+pub struct DisassemblyRow {
+    pub kind: DisassemblyRowKind,
+    pub instruction: usize,
 }
 
 #[derive(Debug)]
 #[derive(Clone)]
 pub struct Subprogram {
+    // Demangled, qualified with its namespaces and classes
     pub name: String,
+    pub linkage_name: Option<String>,
+    // Entry range
     pub low_addr: OfflineAddr,
     pub high_addr: OfflineAddr,
+    // Every range including the entry one, split functions have more than one
+    pub ranges: Vec<(OfflineAddr, OfflineAddr)>,
+    // 0 for functions that only come from the symbol table, those have no lines either
     pub src_file_hash: u64,
     pub start_line: usize,
     pub end_line: usize,
 }
 
+impl Subprogram {
+    // The mangled name is only there for C++ and Rust
+    pub fn display_name(&self, mangled: bool) -> &str {
+        match (&self.linkage_name, mangled) {
+            (Some(linkage_name), true) => linkage_name,
+            _ => &self.name,
+        }
+    }
+
+    // Inclusive at the top like subprogram_at, a call at the very end returns to high
+    pub fn contains(&self, addr: OfflineAddr) -> bool {
+        self.ranges.iter().any(|(low, high)| *low <= addr && addr <= *high)
+    }
+}
+
 #[derive(Debug)]
 #[derive(Clone)]
 pub struct BreakableSrcLocation {
@@ -89,135 +415,67 @@ struct OfflineDebugInfoWorker {
     // NOTE: this is a super hacky solution to not auto-loading all of the files...
     // No clue how to solve this atm
     auto_load_src_root_path: Option<String>,
+    // Searched for separate debug info before /usr/lib/debug
+    debug_dirs: Vec<PathBuf>,
+    // For fetching debug info and sources that aren't on disk
+    debuginfod: Debuginfod,
+    build_id: Option<Vec<u8>>,
     bin_data: Vec<u8>,
 }
 
-// TEMP
-use gimli::*;
 use object::Object;
 use object::ObjectSection;
 
 impl OfflineDebugInfoWorker {
-    pub fn new(exec_path: PathBuf, auto_load_src_root_path: Option<String>) -> (Self, Sender<DebugInfoRequest>, Receiver<DebugInfoResponse>) {
+    pub fn new(exec_path: PathBuf, auto_load_src_root_path: Option<String>, debug_dirs: Vec<PathBuf>) -> (Self, Sender<DebugInfoRequest>, Receiver<DebugInfoResponse>) {
         let (request_sender, request_receiver) = channel();
         let (response_sender, response_receiver) = channel();
 
-        (Self{ request_receiver: request_receiver, request_sender: request_sender.clone(), response_sender: response_sender, debug_info: ThinOfflineDebugInfo::empty(), exec_path: exec_path, auto_load_src_root_path: auto_load_src_root_path, bin_data: vec![] }, request_sender, response_receiver)
+        (Self{ request_receiver: request_receiver, request_sender: request_sender.clone(), response_sender: response_sender, debug_info: ThinOfflineDebugInfo::empty(), exec_path: exec_path, auto_load_src_root_path: auto_load_src_root_path, debug_dirs: debug_dirs, debuginfod: Debuginfod::from_env(), build_id: None, bin_data: vec![] }, request_sender, response_receiver)
     }
 
     fn gather_dwarf_info(&mut self, queue_files: bool) {
         println!("Analysing dwarf...");
-        // TODO: move more of the parsing code from generate_breakable_src_locations to here 
-        // once I figure out what black magic are lifetimes
         self.bin_data = std::fs::read(self.exec_path.clone()).unwrap();
 
+        // Stripped execs keep .text here but the DWARF lives in a separate file
+        self.build_id = build_id(&self.bin_data);
+        let (source, debug_data) = find_debug_file(&self.exec_path, &self.bin_data, &self.debug_dirs, Some(&self.debuginfod));
+        println!("Debug info: {}", source);
+        let dwarf = match (&source, debug_data) {
+            (DebugInfoSource::Missing, _) => DwarfModel::empty(),
+            (_, Some(data)) => DwarfModel::parse(&data, &self.exec_path).unwrap_or(DwarfModel::empty()),
+            (_, None) => DwarfModel::parse(&self.bin_data, &self.exec_path).unwrap_or(DwarfModel::empty()),
+        };
+        self.debug_info.dwarf = Arc::new(dwarf);
+        self.debug_info.dwarf_source = source;
+
         if !queue_files {
             return;
         }
 
-        let endian = gimli::RunTimeEndian::Little;
-        //let file_kind = object::read::FileKind::parse(&*self.bin_data);
-        let object_owned = object::File::parse(&*self.bin_data).unwrap();
-        let object = &object_owned;
-
-        let load_section = |id: gimli::SectionId| -> std::result::Result<std::borrow::Cow<[u8]>, gimli::Error> {
-            match object.section_by_name(id.name()) {
-                Some(ref section) => Ok(section
-                                        .uncompressed_data()
-                                        .unwrap_or(std::borrow::Cow::Borrowed(&[][..]))),
-                None => Ok(std::borrow::Cow::Borrowed(&[][..])),
-            }
-        };
-
-        // Load all of the sections.
-        let dwarf_cow = gimli::Dwarf::load(&load_section).unwrap();
-
-        // Borrow a `Cow<[u8]>` to create an `EndianSlice`.
-        let borrow_section: &dyn for<'b> Fn(
-            &'b std::borrow::Cow<[u8]>,
-            ) -> gimli::EndianSlice<'b, gimli::RunTimeEndian> =
-            &|section| gimli::EndianSlice::new(&*section, endian);
-
-        // Create `EndianSlice`s for all of the sections.
-        let dwarf = dwarf_cow.borrow(&borrow_section);
-
-        // Iterate over the compilation units.
-        let mut iter = dwarf.units();
-        let mut filenames = HashSet::new();
-        let exec_path = self.exec_path.display().to_string();
-        while let Some(header) = iter.next().unwrap() {
-            let unit = dwarf.unit(header).unwrap();
-
-            // Get the line program for the compilation unit.
-            if let Some(program) = unit.line_program.clone() {
-                let comp_dir = if let Some(ref dir) = unit.comp_dir {
-                    let dir_str = dir.to_string_lossy().into_owned();
-                    std::path::PathBuf::from(dir_str)
-                } else {
-                    std::path::PathBuf::new()
-                };
-
-
-                // Iterate over the line program rows.
-                let mut rows = program.rows();
-                while let Some((header, row)) = rows.next_row().unwrap() {
-                    if row.end_sequence() {
-                        // End of sequence indicates a possible gap in addresses.
-                        //println!("{:x} end-sequence", row.address());
-                    } else {
-                        // Determine the path. Real applications should cache this for performance.
-                        let mut path = std::path::PathBuf::new();
-                        if let Some(file) = row.file(header) {
-                            path = comp_dir.clone();
-
-                            // The directory index 0 is defined to correspond to the compilation unit directory.
-                            if file.directory_index() != 0 {
-                                if let Some(dir) = file.directory(header) {
-                                    path.push(
-                                        dwarf.attr_string(&unit, dir).unwrap().to_string_lossy().as_ref(),
-                                        );
-                                }
-                            }
-
-                            path.push(
-                                dwarf
-                                .attr_string(&unit, file.path_name()).unwrap()
-                                .to_string_lossy()
-                                .as_ref(),
-                                );
-
-                            let mut filter_out = false;
-                            if let Some(src_root) = &self.auto_load_src_root_path {
-                                filter_out = !path.starts_with(src_root);
-                            }
-
-                            if !filter_out {
-                                filenames.insert(path);
-                            }
-                        }
-                    }
+        for path in self.debug_info.dwarf.files.keys() {
+            if let Some(src_root) = &self.auto_load_src_root_path {
+                if !path.starts_with(src_root) {
+                    continue;
                 }
             }
-        }
 
-        for filename in &filenames {
-            //if file.starts_with("/home/savas/Projects/degrugger/src") {
-            //if file.starts_with("/home/savas/Projects/rayzigger/src") {
-                self.request_sender.send(DebugInfoRequest::ReadSrc{ path: std::path::PathBuf::from(filename), queue_debug_info: true });
-            //}
+            self.request_sender.send(DebugInfoRequest::ReadSrc{ path: path.clone(), queue_debug_info: true });
         }
-
     }
 
-    fn decompile_src(bin_data: &Vec<u8>) -> Arc<DecompiledSrc> {
+    fn decompile_src(bin_data: &Vec<u8>, dwarf: &DwarfModel, symbols: &Vec<Subprogram>) -> Arc<DecompiledSrc> {
         let obj_file = object::File::parse(&**bin_data).unwrap();
         let text_section = obj_file.section_by_name(".text").unwrap();
+        let text_data = text_section.data().unwrap();
 
-        let mut decompiled_src = DecompiledSrc{ instructions: vec![], decompiled_src: vec![], addresses: vec![]/*, src_to_decompiled_mapping: HashMap::new()*/ };
+        let mut decompiled_src = DecompiledSrc{ instructions: vec![], decompiled_src: vec![], bytes: vec![], addresses: vec![], rows: vec![], interleaved_rows: vec![] };
 
-        let mut decoder = Decoder::new(64, text_section.data().unwrap(), DecoderOptions::NONE);
+        let mut decoder = Decoder::new(64, text_data, DecoderOptions::NONE);
         decoder.set_ip(text_section.address());
-        let mut formatter = FastFormatter::new();
+        // Fast formatter can't take a symbol resolver
+        let mut formatter = NasmFormatter::with_options(Some(Box::new(SymbolMap::new(bin_data, dwarf, symbols))), None);
 
         let mut output = String::new();
         let mut instruction = Instruction::default();
@@ -227,304 +485,60 @@ impl OfflineDebugInfoWorker {
             output.clear();
             formatter.format(&instruction, &mut output);
 
-            let mut hex_instruction = "".to_owned();
-            let start_index = (instruction.ip() - 0) as usize;
-            //for b in &bin_data[start_index..start_index + instruction.len()] {
-            //    hex_instruction = format!("{}{:02x}", hex_instruction, b);
-            //}
-            // TODO: remove the address from here
-            //let decompiled_asm = format!("{:016x} {:<20} {}", instruction.ip(), hex_instruction, output);
-            let decompiled_asm = format!("{:016x} {}", instruction.ip(), output);
+            let start_index = (instruction.ip() - text_section.address()) as usize;
+            let hex_instruction: String = text_data[start_index..start_index + instruction.len()].iter().map(|b| format!("{:02x}", b)).collect();
             decompiled_src.instructions.push(instruction);
-            decompiled_src.decompiled_src.push(decompiled_asm);
+            decompiled_src.decompiled_src.push(output.clone());
+            decompiled_src.bytes.push(hex_instruction);
             decompiled_src.addresses.push(instruction.ip());
         }
 
-        Arc::new(decompiled_src)
-    }
-
-    // TODO: this is catastrophically bad -- we shouldn't be reparsing it for every file, etc.
-    // This should be done once and kept in memory while we need it. But lifetimes are an absolute PITA >:C
-    fn generate_breakable_src_locations_and_subprograms(&mut self, src_file: &SrcFile) -> (Vec<BreakableSrcLocation>, Vec<Subprogram>) {
-        let mut breakable_src_locs = vec![];
-        let mut subprograms = vec![];
-
-        let endian = gimli::RunTimeEndian::Little;
-        let object_owned = object::File::parse(&*self.bin_data).unwrap();
-        let object = &object_owned;
-
-        let load_section = |id: gimli::SectionId| -> std::result::Result<std::borrow::Cow<[u8]>, gimli::Error> {
-            match object.section_by_name(id.name()) {
-                Some(ref section) => Ok(section
-                                        .uncompressed_data()
-                                        .unwrap_or(std::borrow::Cow::Borrowed(&[][..]))),
-                None => Ok(std::borrow::Cow::Borrowed(&[][..])),
-            }
-        };
-
-        // Borrow a `Cow<[u8]>` to create an `EndianSlice`.
-        let borrow_section: &dyn for<'b> Fn(
-            &'b std::borrow::Cow<[u8]>,
-            ) -> gimli::EndianSlice<'b, gimli::RunTimeEndian> =
-            &|section| gimli::EndianSlice::new(&*section, endian);
-
-
-        // Create `EndianSlice`s for all of the sections.
-        let dwarf_cow = gimli::Dwarf::load(&load_section).unwrap();
-        let dwarf = dwarf_cow.borrow(&borrow_section);
-
-        // Iterate over the compilation units.
-        let mut iter = dwarf.units();
-        while let Some(header) = iter.next().unwrap() {
-            //println!(
-            //    "Line number info for unit at <.debug_info+0x{:x}>",
-            //    header.offset().as_debug_info_offset().unwrap().0
-            //    );
-            let unit = dwarf.unit(header).unwrap();
-
-            let comp_dir = if let Some(ref dir) = unit.comp_dir {
-                std::path::PathBuf::from(dir.to_string_lossy().into_owned())
-            } else {
-                std::path::PathBuf::new()
-            };
-            if !src_file.path.display().to_string().starts_with(&comp_dir.display().to_string()) {
-                continue;
-            }
-            //println!("CU dir: {}", comp_dir.display());
-
-            let program = unit.line_program.clone();
-            let program = match program {
-                Some(p) => p,
-                None => { continue; }
-            };
-
-            let (program, seqs) = program.sequences().unwrap();
-            let header = program.header();
-
-            for f in header.file_names() {
-                let filename = dwarf.attr_string(&unit, f.path_name()).unwrap().to_string().unwrap();
-                //println!("\t{}/{}", comp_dir.display(), filename);
-            }
-
-            let mut breakable_locs_in_unit = vec![];
-            for seq in &seqs {
-                let mut rows = program.resume_from(seq);
-                let mut path = std::path::PathBuf::new();
-                let mut cached_file_index = None;
-
-                // Iterate over the line program rows.
-                //let mut rows = program.rows();
-                while let Some((header, row)) = rows.next_row().unwrap() {
-                    if row.end_sequence() {
-                        // End of sequence indicates a possible gap in addresses.
-                        //println!("{:x} end-sequence", row.address());
-                        continue;
-                    }
-
-                    if cached_file_index.is_none() {
-                    //if true {
-                        if let Some(file) = row.file(header) {
-                            //if path.display().to_string().len() == 0 {
-                                path = comp_dir.clone();
-
-                                // The directory index 0 is defined to correspond to the compilation unit directory.
-                                if file.directory_index() != 0 {
-                                    if let Some(dir) = file.directory(header) {
-                                        path.push(dwarf.attr_string(&unit, dir).unwrap().to_string_lossy().as_ref());
-                                    }
-                                }
-                                path.push(dwarf.attr_string(&unit, file.path_name()).unwrap().to_string_lossy().as_ref());
-                            //}
-
-                        } else {
-                            //println!("Early out 0");
-                            continue;
-                        }
-
-                        if path != src_file.path {
-                            //println!("Early out 1 {} {}", path.display(), src_file.path.display());
-                            continue;
-                        }
-
-                        cached_file_index = Some(row.file_index());
-                    }
-            
-                    if cached_file_index.unwrap() != row.file_index() {
-                        //println!("Early out 2 {} {}", row.file_index(), cached_file_index.unwrap());
-                        continue;
-                    }
-
-                    // Determine line/column. DWARF line/column is never 0, so we use that
-                    // but other applications may want to display this differently.
-                    let line = match row.line() {
-                        Some(line) => line.get(),
-                        None => 0,
-                    };
-                    let column = match row.column() {
-                        gimli::ColumnType::LeftEdge => 1,
-                        gimli::ColumnType::Column(column) => column.get(),
-                    };
-
-                    // TODO: potentially exclude empty lines, lines that are out of scope of the file, columns that don't exist
-                    //if row.is_stmt() {
-                    //let mut invalid_bp = match &src_file.lines {
-                    //    Some(l) => {
-                    //        let no_line = line == 0;
-                    //        let mut line = line as usize;
-                    //        if line > 0 {
-                    //            line = line - 1;
-                    //        }
-
-                    //        // !row.is_stmt() || no_line || line > l.len() || column as usize > l[line].len()
-                    //        no_line || line > l.len() || column as usize > l[line].len()
-                    //    }
-                    //    None => false,
-                    //};
-                    let invalid_bp = false;
-                    if !invalid_bp { 
-                        //println!("{:x} {}:{}:{} is_stmt: {} basic_block: {} end_seq: {} prologue_end: {} epilogue_begin; {} isa: {} desc: {} file_index: {}", row.address(), path.display(), line, column, row.is_stmt(), row.basic_block(), row.end_sequence(), row.prologue_end(), row.epilogue_begin(), row.isa(), row.discriminator(), row.file_index());
-                        breakable_locs_in_unit.push(BreakableSrcLocation{ addr: row.address(), src_line: line as usize, src_col: column as usize });
-                    }
-                    //breakable_src_locs.push(BreakableSrcLocation{ addr: row.address(), src_line: line as usize, src_col: column as usize });
+        // Every part of every function gets a header
+        let mut function_starts: Vec<OfflineAddr> = dwarf.units.iter()
+            .flat_map(|u| u.subprograms.iter().filter(|s| !s.inline).flat_map(|s| s.ranges.iter().map(|(low, _)| *low)))
+            .chain(symbols.iter().map(|s| s.low_addr))
+            .collect();
+        function_starts.sort();
+        function_starts.dedup();
+
+        // (addr, file hash, line) of the line table rows, same order location_at picks them in
+        let mut line_starts = vec![];
+        for unit in &dwarf.units {
+            for row in unit.rows.iter().filter(|r| !r.end_sequence && r.line != 0) {
+                if let Some(Some(path)) = unit.files.get(row.file as usize) {
+                    line_starts.push((row.addr, path_hash(path), row.line));
                 }
             }
+        }
+        line_starts.sort_by_key(|(addr, _, _)| *addr);
+
+        // Like objdump -S, a source line whenever the line changes. Jumping back to a line
+        // shows it again
+        let mut last_line = None;
+        for (i, addr) in decompiled_src.addresses.iter().enumerate() {
+            if function_starts.binary_search(addr).is_ok() {
+                decompiled_src.rows.push(DisassemblyRow{ kind: DisassemblyRowKind::Function, instruction: i });
+                decompiled_src.interleaved_rows.push(DisassemblyRow{ kind: DisassemblyRowKind::Function, instruction: i });
+                last_line = None;
+            }
 
-
-            // Iterate over the Debugging Information Entries (DIEs) in the unit.
-            let mut depth = 0;
-            let mut entries = unit.entries();
-            while let Some((delta_depth, entry)) = entries.next_dfs().unwrap() {
-                //if entry.tag() != gimli::constants::DwTag(0x2e) {
-                if entry.tag() != DW_TAG_subprogram {
-                    continue;
-                }
-
-                depth += delta_depth;
-                //println!("<{}><{:x}> {}", depth, entry.offset().0, entry.tag());
-
-                // Iterate over the attributes in the DIE.
-                let mut attrs = entry.attrs();
-                let mut subprogram = Subprogram{ name: "".to_owned(), low_addr: 0, high_addr: 0, src_file_hash: src_file.simple_hash(), start_line: 0, end_line: 0 };
-                let mut is_inlined = false;
-                let mut decl_file = "".to_owned();
-                while let Some(attr) = attrs.next().unwrap() {
-                    match attr.name() {
-                        DW_AT_low_pc => {
-                            subprogram.low_addr = dwarf.attr_address(&unit, attr.value()).unwrap().unwrap();
-                            //println!("{:?}", attr.value());
-                            //subprogram.low_addr = dwarf.address(&unit, attr.value()).unwrap().unwrap();
-                        },
-                        DW_AT_high_pc => {
-                            subprogram.high_addr = attr.udata_value().unwrap();
-                        },
-                        DW_AT_name => {
-                            subprogram.name = dwarf.attr_string(&unit, attr.value()).unwrap().to_string().unwrap().to_string();
-                        },
-                        DW_AT_decl_file => {
-                            //let name = dwarf.attr_string(&unit, attr.value()).unwrap().to_string().unwrap();
-                            dump_file_index(&mut decl_file, attr.udata_value().unwrap(), &unit, &dwarf);
-                            //println!("file: {}", n);
-                        }, 
-                        DW_AT_inline => {
-                            //println!("inlined: {:?}", attr.value());
-                            is_inlined = true;
-                        },
-                        _ => {},
-                    }
-                }
-
-                if std::path::PathBuf::from(decl_file) != src_file.path {
-                    continue;
-                }
-
-                subprogram.high_addr += subprogram.low_addr;
-
-                if subprogram.high_addr == subprogram.low_addr || is_inlined {
-                    continue;
-                }
-
-
-                for breakable_location in &breakable_locs_in_unit {
-                    if breakable_location.addr == subprogram.low_addr {
-                        subprogram.start_line = breakable_location.src_line;
-                    }
-                }
-
-                let mut highest_end_addr = 0;
-                for breakable_location in &breakable_locs_in_unit {
-                    if breakable_location.addr > subprogram.low_addr && breakable_location.src_line >= subprogram.end_line && breakable_location.addr < subprogram.high_addr && breakable_location.addr >= highest_end_addr {
-                        highest_end_addr = breakable_location.addr;
-                        subprogram.end_line = breakable_location.src_line;
-                    }
-                }
-
-                //println!("{} {} - {} {:x}-{:x} (highest: {:x}) {} - {}", subprogram.name, subprogram.low_addr, subprogram.high_addr, subprogram.low_addr, subprogram.high_addr, highest_end_addr, subprogram.start_line, subprogram.end_line);
-
-                // TODO: well if there's a single line function...
-                if subprogram.start_line == subprogram.end_line {
-                    continue;
+            let j = line_starts.partition_point(|(a, _, _)| a <= addr);
+            if j > 0 && line_starts[j - 1].0 == *addr {
+                let (_, file_hash, line) = line_starts[j - 1];
+                if last_line != Some((file_hash, line)) {
+                    decompiled_src.interleaved_rows.push(DisassemblyRow{ kind: DisassemblyRowKind::Source{ file_hash: file_hash, line: line }, instruction: i });
+                    last_line = Some((file_hash, line));
                 }
-
-                subprograms.push(subprogram);
             }
 
-            breakable_src_locs.append(&mut breakable_locs_in_unit);
+            decompiled_src.rows.push(DisassemblyRow{ kind: DisassemblyRowKind::Instruction, instruction: i });
+            decompiled_src.interleaved_rows.push(DisassemblyRow{ kind: DisassemblyRowKind::Instruction, instruction: i });
         }
 
-        (breakable_src_locs, subprograms)
+        Arc::new(decompiled_src)
     }
 }
 
-fn dump_file_index<R: Reader>(
-        w: &mut String,
-        file_index: u64,
-        unit: &gimli::Unit<R>,
-        dwarf: &gimli::Dwarf<R>,
-        ) -> Result<()> {
-    if file_index == 0 && unit.header.version() <= 4 {
-        return Ok(());
-    }
-    let header = match unit.line_program {
-        Some(ref program) => program.header(),
-        None => return Ok(()),
-    };
-    let file = match header.file(file_index) {
-        Some(file) => file,
-            None => {
-                //writeln!(w, "Unable to get header for file {}", file_index)?;
-                return Ok(());
-            }
-    };
-    //write!(w, " ")?;
-    if let Some(directory) = file.directory(header) {
-        let directory = dwarf.attr_string(unit, directory).unwrap();
-        let directory = directory.to_string_lossy().unwrap();
-        if file.directory_index() != 0 && !directory.starts_with('/') {
-            if let Some(ref comp_dir) = unit.comp_dir {
-                //write!(w, "{}/", comp_dir.to_string_lossy()?,)?;
-                *w = format!("{}{}/", w, comp_dir.to_string_lossy().unwrap());
-            }
-        }
-        //write!(w, "{}/", directory)?;
-        *w = format!("{}{}/", w, &directory);
-    }
-    //write!(
-    //        w,
-    //        "{}",
-    //        dwarf
-    //        .attr_string(unit, file.path_name())?
-    //        .to_string_lossy()?
-    //      )?;
-    *w = format!(
-            "{}{}",
-            w,
-            dwarf
-            .attr_string(unit, file.path_name()).unwrap()
-            .to_string_lossy().unwrap()
-          );
-    Ok(())
-}
-
 impl Worker for OfflineDebugInfoWorker {
     fn work(&mut self) {
         println!("Receiving...");
@@ -540,7 +554,10 @@ impl Worker for OfflineDebugInfoWorker {
             DebugInfoRequest::ReadExec(path) => {
                 println!("Reading exec and queueing up src files");
                 self.gather_dwarf_info(true);
-                self.debug_info.decompiled_src = Some(Self::decompile_src(&self.bin_data));
+                // Symbols first, the disassembly is symbolized with them
+                self.debug_info.symbols = Arc::new(symbol_subprograms(&self.bin_data));
+                self.debug_info.decompiled_src = Some(Self::decompile_src(&self.bin_data, &self.debug_info.dwarf, &self.debug_info.symbols));
+                self.debug_info.build_index();
                 self.response_sender.send(DebugInfoResponse::ThinInfo(self.debug_info.clone()));
                 return;
             }
@@ -557,7 +574,16 @@ impl Worker for OfflineDebugInfoWorker {
             DebugInfoRequest::ReadSrc{ path, queue_debug_info } => {
                 println!("Reading src file {}", path.display());
                 
-                if let Ok(file) = SrcFile::new(path, true) {
+                // Not on disk, the debuginfod server might have it
+                let file = match SrcFile::new(path.clone(), true) {
+                    Err(e) => match self.build_id.as_ref().and_then(|id| self.debuginfod.source(id, &path)) {
+                        Some(cached) => SrcFile::new_from(path, &cached),
+                        None => Err(e),
+                    },
+                    file => file,
+                };
+
+                if let Ok(file) = file {
                     let file = Arc::new(file);
 
                     self.response_sender.send(DebugInfoResponse::Src(file.clone()));
@@ -575,18 +601,15 @@ impl Worker for OfflineDebugInfoWorker {
                     return;
                 }
 
-                let (breakable_locations, mut subprograms) = self.generate_breakable_src_locations_and_subprograms(&src);
-
                 let hash = src.simple_hash();
+                let (breakable_locations, subprograms) = self.debug_info.dwarf.file_view(&src.path, hash);
+
                 self.debug_info.src_file_info.insert(hash, 
                     Arc::new(SrcFileDebugInfo{ 
                         src_file_hash: hash,
                         breakable_locations: breakable_locations,
-                        subprograms: subprograms.clone(),
+                        subprograms: subprograms,
                     }));
-                // TODO: this is nauseating, why the copies!?!?
-                subprograms.append(&mut (*self.debug_info.all_subprograms).clone());
-                self.debug_info.all_subprograms = Arc::new(subprograms);
 
                 self.response_sender.send(DebugInfoResponse::DebugInfo(src));
                 self.response_sender.send(DebugInfoResponse::ThinInfo(self.debug_info.clone()));
@@ -662,8 +685,8 @@ impl Drop for OfflineDebugInfo {
 }
 
 impl OfflineDebugInfo {
-    pub fn new(exec_path: PathBuf, auto_load_src_root_path: Option<String>) -> Result<Self> {
-        let (worker, request_sender, response_receiver) = OfflineDebugInfoWorker::new(exec_path, auto_load_src_root_path);
+    pub fn new(exec_path: PathBuf, auto_load_src_root_path: Option<String>, debug_dirs: Vec<PathBuf>) -> Result<Self> {
+        let (worker, request_sender, response_receiver) = OfflineDebugInfoWorker::new(exec_path, auto_load_src_root_path, debug_dirs);
 
         Ok(Self { 
             thread: WorkerThread::new("OfflineDebugInfoThread".to_owned(), Box::new(worker))?,
@@ -780,3 +803,102 @@ impl WorkerThread {
         !self.join_handle.is_finished()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subprogram(name: &str, ranges: Vec<(u64, u64)>, src_file_hash: u64) -> Subprogram {
+        Subprogram{ name: name.to_owned(), linkage_name: None, low_addr: ranges[0].0, high_addr: ranges[0].1, ranges: ranges, src_file_hash: src_file_hash, start_line: 0, end_line: 0 }
+    }
+
+    fn location(addr: u64, src_line: usize, src_col: usize) -> BreakableSrcLocation {
+        BreakableSrcLocation{ addr: addr, src_line: src_line, src_col: src_col }
+    }
+
+    // main at 0x1000 with a cold part at 0x3000, foo at 0x1100 and a symbol only bar after it
+    fn debug_info() -> ThinOfflineDebugInfo {
+        let mut info = ThinOfflineDebugInfo::empty();
+        info.all_subprograms = Arc::new(vec![
+            subprogram("main", vec![(0x1000, 0x1100), (0x3000, 0x3010)], 1),
+            subprogram("foo", vec![(0x1100, 0x1180)], 1),
+            subprogram("bar", vec![(0x1180, 0x1200)], 0),
+        ]);
+        let locations = vec![
+            location(0x1000, 10, 1), location(0x1008, 11, 5), location(0x1010, 11, 12), location(0x1020, 12, 1),
+            location(0x1030, 11, 5), location(0x1100, 20, 1), location(0x1110, 22, 3),
+        ];
+        let locations = locations.into_iter().map(|l| (l.addr, 1, l)).collect();
+        info.index = Arc::new(AddrIndex::new(&info.all_subprograms, locations));
+        info
+    }
+
+    #[test]
+    fn subprograms_by_addr() {
+        let info = debug_info();
+        let name_at = |addr| info.subprogram_at(addr).map(|(_, s)| s.name.as_str());
+        assert_eq!(name_at(0xfff), None);
+        assert_eq!(name_at(0x1000), Some("main"));
+        assert_eq!(name_at(0x10ff), Some("main"));
+        assert_eq!(name_at(0x1100), Some("foo"));
+        assert_eq!(name_at(0x1190), Some("bar"));
+        assert_eq!(name_at(0x2000), None);
+        assert_eq!(name_at(0x3008), Some("main"));
+        assert_eq!(info.subprogram_at(0x3008).map(|(i, _)| i), Some(0));
+    }
+
+    #[test]
+    fn breakpoint_past_prologue() {
+        let mut info = debug_info();
+        let subprograms = info.all_subprograms.clone();
+        // No prologue_end rows, the second row like gdb
+        assert_eq!(info.breakpoint_addr(&subprograms[0]), 0x1008);
+        // Symbol only, no rows past the entry
+        assert_eq!(info.breakpoint_addr(&subprograms[2]), 0x1180);
+
+        let mut index = AddrIndex::new(&info.all_subprograms, vec![]);
+        index.prologue_ends = vec![0x1010, 0x1110];
+        info.index = Arc::new(index);
+        assert_eq!(info.breakpoint_addr(&subprograms[0]), 0x1010);
+        assert_eq!(info.breakpoint_addr(&subprograms[1]), 0x1110);
+    }
+
+    #[test]
+    fn subprograms_past_a_shorter_one() {
+        // outer contains inner, past inner's end only the prefix max finds outer again
+        let mut info = ThinOfflineDebugInfo::empty();
+        info.all_subprograms = Arc::new(vec![
+            subprogram("outer", vec![(0x1000, 0x2000)], 1),
+            subprogram("inner", vec![(0x1100, 0x1200)], 1),
+            subprogram("after", vec![(0x3000, 0x3100)], 1),
+        ]);
+        info.index = Arc::new(AddrIndex::new(&info.all_subprograms, vec![]));
+        let name_at = |addr| info.subprogram_at(addr).map(|(_, s)| s.name.as_str());
+        assert_eq!(name_at(0x1150), Some("inner"));
+        assert_eq!(name_at(0x1800), Some("outer"));
+        assert_eq!(name_at(0x2800), None);
+        assert_eq!(name_at(0x3050), Some("after"));
+    }
+
+    #[test]
+    fn locations_by_addr() {
+        let info = debug_info();
+        let line_at = |addr| info.location_at(addr).map(|(_, l)| l.src_line);
+        assert_eq!(line_at(0xfff), None);
+        assert_eq!(line_at(0x1000), Some(10));
+        assert_eq!(line_at(0x100f), Some(11));
+        assert_eq!(line_at(0x1030), Some(11));
+        assert_eq!(line_at(0x1115), Some(22));
+    }
+
+    #[test]
+    fn addrs_by_line() {
+        let info = debug_info();
+        assert_eq!(info.addresses_for_line(1, 11), vec![0x1008, 0x1010, 0x1030]);
+        assert_eq!(info.addresses_for_line(1, 13), vec![]);
+        assert_eq!(info.addresses_for_line(2, 11), vec![]);
+        assert_eq!(info.columns_for_line(1, 11), vec![(5, 0x1008), (12, 0x1010)]);
+        assert_eq!(info.line_with_code(1, 13), Some((20, 0x1100)));
+        assert_eq!(info.line_with_code(1, 23), None);
+    }
+}
@@ -1,117 +1,228 @@
-use nix::sys::{ ptrace, wait::waitpid };
-use nix::unistd::Pid;
-use core::ffi::c_void;
+use std::collections::HashMap;
+
+use nix::errno::Errno;
+
+use crate::tracer::{ TracerHandle, TracerCommand, AfterStepOver };
 
 // For now just bps from addresses
 pub trait Patcher {
     fn inject_breakpoints(&mut self, breakpoints: &Vec<u64>) -> Result<(), ()>;
+    fn remove_breakpoints(&mut self, breakpoints: &Vec<u64>) -> Result<(), ()>;
     fn disable_breakpoints(&mut self, breakpoints: &Vec<u64>) -> Result<(), ()>;
     fn enable_breakpoints(&mut self, breakpoints: &Vec<u64>) -> Result<(), ()>;
 
-    fn cont(&mut self, addr: u64) -> Result<(), ()>;
+    // Gets the debugee past the int3 at addr it just hit, then does whatever then says. Doesn't
+    // wait for it, the tracer takes care of it before doing anything else
+    fn step_over(&mut self, addr: u64, then: AfterStepOver) -> Result<(), ()>;
+
+    // Debugee memory as it would look without any of our patches
+    fn read_memory(&self, addr: u64, len: usize) -> Result<Vec<u8>, Errno>;
+
+    // Addresses that currently have an int3 in, with the byte that was there before
+    fn active_breakpoints(&self) -> HashMap<u64, u8>;
+
+    // Writes every active int3 again, for when the debugee got swapped out for a process that
+    // doesn't have them (restored checkpoint)
+    fn reapply_breakpoints(&mut self) -> Result<(), ()>;
 }
 
 struct Patch {
-    addr: u64, 
-    original_instruction: i64, // TEMP: change to u8
-    new_instruction: i64,
-    active: bool,
+    addr: u64,
+    original_byte: u8,
+    // How many insert points share this address
+    refs: usize,
+    // How many of those want the int3 in. The byte is patched as long as this is > 0
+    enabled: usize,
 }
 
-const x86_sigtrap: i64 = 0xCC; // TEMP: change to u8
+impl Patch {
+    fn active(&self) -> bool {
+        self.enabled > 0
+    }
+}
+
+const X86_INT3: u8 = 0xCC;
 pub struct LocalPatcher {
-    pid: Pid,
+    tracer: TracerHandle,
 
     patches: Vec<Patch>,
 }
 
 impl LocalPatcher {
-    pub fn new(pid: Pid) -> impl Patcher {
-        LocalPatcher{ pid: pid, patches: vec![] }
+    pub fn new(tracer: TracerHandle) -> impl Patcher {
+        LocalPatcher{ tracer: tracer, patches: vec![] }
+    }
+
+    fn find(&mut self, addr: u64) -> Option<&mut Patch> {
+        self.patches.iter_mut().find(|p| p.addr == addr)
+    }
+
+    fn read_byte(&self, addr: u64) -> Result<u8, ()> {
+        let bytes = self.tracer.read_memory(addr, 1).map_err(|_| ())?;
+        bytes.first().copied().ok_or(())
+    }
+
+    fn write_byte(&self, addr: u64, byte: u8) -> Result<(), ()> {
+        self.tracer.write_memory(addr, &[byte]).map_err(|_| ())
     }
+
 }
 
 impl Patcher for LocalPatcher {
     fn inject_breakpoints(&mut self, breakpoints: &Vec<u64>) -> Result<(), ()> {
-        // TODO: check if we already have such BP
+        let mut res = Ok(());
         for addr in breakpoints {
-            let mut patch = Patch { addr: *addr, original_instruction: 0, new_instruction: 0, active: false };
-            unsafe {
-                println!("Adding BP -- pid in patcher: {}, addr: {:#04x} ({:x})", self.pid, *addr, *addr - (0x555555555040 - 0x1040));
-                patch.original_instruction = ptrace::read(self.pid, *addr as *mut c_void).expect("Should not fail");
-                patch.new_instruction = (patch.original_instruction & !(0xFF as i64)) | x86_sigtrap;
-                patch.active = true;
-
-                ptrace::write(self.pid, *addr as *mut c_void, patch.new_instruction as *mut c_void);
+            if let Some(patch) = self.find(*addr) {
+                patch.refs += 1;
+                patch.enabled += 1;
+                if patch.enabled > 1 {
+                    continue;
+                }
+
+                // Was there, but every insert point had it disabled
+                if self.write_byte(*addr, X86_INT3).is_err() {
+                    res = Err(());
+                }
+                continue;
             }
-            
-            self.patches.push(patch);
-        }
 
-        Ok(())
-    }
+            let original_byte = match self.read_byte(*addr) {
+                Ok(b) => b,
+                Err(_) => {
+                    res = Err(());
+                    continue;
+                },
+            };
+            if self.write_byte(*addr, X86_INT3).is_err() {
+                res = Err(());
+                continue;
+            }
 
-    fn cont(&mut self, addr: u64) -> Result<(), ()> {
-        let mut regs = ptrace::getregs(self.pid).unwrap();
-        if regs.rip - 1 != addr {
-            return Err(());
+            self.patches.push(Patch{ addr: *addr, original_byte: original_byte, refs: 1, enabled: 1 });
         }
 
-        self.disable_breakpoints(&vec![addr]);
+        res
+    }
 
-        regs = ptrace::getregs(self.pid).unwrap();
-        regs.rip = addr;
-        ptrace::setregs(self.pid, regs);
+    // Drops one reference per address, the patch is forgotten once nobody is left. Disable the
+    // insert point first if it was enabled, we can't tell which one it was otherwise
+    fn remove_breakpoints(&mut self, breakpoints: &Vec<u64>) -> Result<(), ()> {
+        let mut res = Ok(());
+        for addr in breakpoints {
+            let i = match self.patches.iter().position(|p| p.addr == *addr) {
+                Some(i) => i,
+                None => {
+                    res = Err(());
+                    continue;
+                },
+            };
+
+            let patch = &mut self.patches[i];
+            patch.refs -= 1;
+            patch.enabled = patch.enabled.min(patch.refs);
+            if patch.refs > 0 {
+                continue;
+            }
 
-        ptrace::step(self.pid, None).unwrap();
-        waitpid(self.pid, None); // NOTE: Bad bad bad bad, will freeze the debugger, pass in a closure
+            let original_byte = patch.original_byte;
+            if self.write_byte(*addr, original_byte).is_err() {
+                res = Err(());
+            }
+            self.patches.remove(i);
+        }
 
-        self.enable_breakpoints(&vec![addr]);
+        res
+    }
 
-        Ok(())
+    // Also works if the breakpoint got disabled or removed since it was hit, rip still needs to go back
+    fn step_over(&mut self, addr: u64, then: AfterStepOver) -> Result<(), ()> {
+        let (original, patched) = match self.patches.iter().find(|p| p.addr == addr) {
+            Some(p) if p.active() => (vec![p.original_byte], vec![X86_INT3]),
+            Some(p) => (vec![p.original_byte], vec![p.original_byte]),
+            None => (vec![], vec![]),
+        };
+
+        self.tracer.try_send(TracerCommand::StepOverBreakpoint{
+            addr: addr,
+            original: original,
+            patched: patched,
+            then: then,
+        })
     }
 
     fn disable_breakpoints(&mut self, breakpoints: &Vec<u64>) -> Result<(), ()> {
+        let mut res = Ok(());
         for addr in breakpoints {
-            for patch in &mut self.patches {
-                if *addr != patch.addr {
+            let patch = match self.find(*addr) {
+                Some(p) if p.active() => p,
+                _ => {
+                    res = Err(());
                     continue;
-                }
+                },
+            };
 
-                if !patch.active {
-                    break;
-                }
+            patch.enabled -= 1;
+            if patch.active() {
+                continue;
+            }
 
-                unsafe {
-                    ptrace::write(self.pid, *addr as *mut c_void, patch.original_instruction as *mut c_void);
-                }
-                patch.active = false;
-                return Ok(());
+            let original_byte = patch.original_byte;
+            if self.write_byte(*addr, original_byte).is_err() {
+                res = Err(());
             }
         }
-                               
-        Err(())
+
+        res
     }
 
     fn enable_breakpoints(&mut self, breakpoints: &Vec<u64>) -> Result<(), ()> {
+        let mut res = Ok(());
         for addr in breakpoints {
-            for patch in &mut self.patches {
-                if *addr != patch.addr {
+            let patch = match self.find(*addr) {
+                Some(p) if p.enabled < p.refs => p,
+                _ => {
+                    res = Err(());
                     continue;
-                }
+                },
+            };
 
-                if patch.active {
-                    break;
-                }
+            patch.enabled += 1;
+            if patch.enabled > 1 {
+                continue;
+            }
 
-                unsafe {
-                    ptrace::write(self.pid, *addr as *mut c_void, patch.new_instruction as *mut c_void);
-                }
-                patch.active = true;
-                return Ok(());
+            if self.write_byte(*addr, X86_INT3).is_err() {
+                res = Err(());
             }
         }
-                               
-        Err(())
+
+        res
+    }
+
+    fn read_memory(&self, addr: u64, len: usize) -> Result<Vec<u8>, Errno> {
+        let mut data = self.tracer.read_memory(addr, len)?;
+        let end = addr + data.len() as u64;
+        for patch in &self.patches {
+            if patch.active() && patch.addr >= addr && patch.addr < end {
+                data[(patch.addr - addr) as usize] = patch.original_byte;
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn active_breakpoints(&self) -> HashMap<u64, u8> {
+        self.patches.iter().filter(|p| p.active()).map(|p| (p.addr, p.original_byte)).collect()
+    }
+
+    fn reapply_breakpoints(&mut self) -> Result<(), ()> {
+        let mut res = Ok(());
+        for patch in self.patches.iter().filter(|p| p.active()) {
+            if self.write_byte(patch.addr, X86_INT3).is_err() {
+                res = Err(());
+            }
+        }
+
+        res
     }
 }
@@ -0,0 +1,300 @@
+use std::collections::VecDeque;
+
+use iced_x86::{ Decoder, DecoderOptions, InstructionInfoFactory, OpAccess, Register };
+use nix::libc::user_regs_struct as UserRegsStruct;
+use nix::libc::user_fpregs_struct as UserFpRegsStruct;
+
+use crate::registers::FpRegs;
+
+// Oldest steps get dropped past this. Roughly a few hundred MB worst case
+const MAX_TRACE_LEN: usize = 1_000_000;
+
+const REG_COUNT: usize = std::mem::size_of::<UserRegsStruct>() / std::mem::size_of::<u64>();
+// fxsave area then the upper halves of ymm0-15, as u64s
+const FXSAVE_WORDS: usize = std::mem::size_of::<UserFpRegsStruct>() / std::mem::size_of::<u64>();
+const FP_WORD_COUNT: usize = FXSAVE_WORDS + 16 * 2;
+
+// Bytes some instruction overwrote
+#[derive(Debug, Clone)]
+pub struct MemoryDelta {
+    pub addr: u64,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+// Everything a single instruction changed, enough to undo it
+#[derive(Debug, Clone)]
+pub struct TraceEntry {
+    // Where the instruction was
+    pub addr: u64,
+    // (index into UserRegsStruct as [u64], old, new)
+    pub regs: Vec<(usize, u64, u64)>,
+    // Same for x87/SSE/AVX, see fp_regs_delta
+    pub fp_regs: Vec<(usize, u64, u64)>,
+    pub memory: Vec<MemoryDelta>,
+    // Not a real instruction, we made the debugee look like it ran into one of our int3s
+    pub breakpoint: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct LastChange {
+    // 0 is the most recent step
+    pub steps_ago: usize,
+    // Instruction that did it
+    pub addr: u64,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+// Undo log of everything the debugee did since recording started. Going backwards pops entries
+// off, going forwards again re-executes (so anything nondeterministic might turn out different)
+pub struct Trace {
+    entries: VecDeque<TraceEntry>,
+    // Lost the oldest entries to MAX_TRACE_LEN
+    pub truncated: bool,
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Trace{ entries: VecDeque::new(), truncated: false }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn last(&self) -> Option<&TraceEntry> {
+        self.entries.back()
+    }
+
+    pub fn last_mut(&mut self) -> Option<&mut TraceEntry> {
+        self.entries.back_mut()
+    }
+
+    pub fn push(&mut self, entry: TraceEntry) {
+        if self.entries.len() >= MAX_TRACE_LEN {
+            self.entries.pop_front();
+            self.truncated = true;
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn pop(&mut self) -> Option<TraceEntry> {
+        self.entries.pop_back()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.truncated = false;
+    }
+
+    // Most recent step that actually changed any of the bytes in [addr, addr + len). None for
+    // ranges running off the end of the address space too
+    pub fn last_change(&self, addr: u64, len: usize) -> Option<LastChange> {
+        let end = addr.checked_add(len as u64)?;
+        for (steps_ago, entry) in self.entries.iter().rev().enumerate() {
+            for delta in &entry.memory {
+                let delta_end = delta.addr + delta.old.len() as u64;
+                if delta_end <= addr || delta.addr >= end {
+                    continue;
+                }
+
+                let from = (addr.max(delta.addr) - delta.addr) as usize;
+                let to = (end.min(delta_end) - delta.addr) as usize;
+                if delta.old[from..to] != delta.new[from..to] {
+                    return Some(LastChange{ steps_ago: steps_ago, addr: entry.addr, old: delta.old.clone(), new: delta.new.clone() });
+                }
+            }
+        }
+
+        None
+    }
+}
+
+fn regs_as_array(regs: &UserRegsStruct) -> [u64; REG_COUNT] {
+    // All u64s, repr(C)
+    unsafe { std::mem::transmute_copy(regs) }
+}
+
+fn regs_from_array(array: &[u64; REG_COUNT]) -> UserRegsStruct {
+    unsafe { std::mem::transmute_copy(array) }
+}
+
+pub fn regs_delta(old: &UserRegsStruct, new: &UserRegsStruct) -> Vec<(usize, u64, u64)> {
+    let old = regs_as_array(old);
+    let new = regs_as_array(new);
+    (0..REG_COUNT).filter(|i| old[*i] != new[*i]).map(|i| (i, old[i], new[i])).collect()
+}
+
+fn fp_regs_as_array(fp_regs: &FpRegs) -> [u64; FP_WORD_COUNT] {
+    let mut array = [0u64; FP_WORD_COUNT];
+    // All u32s, repr(C)
+    let fxsave: [u64; FXSAVE_WORDS] = unsafe { std::mem::transmute_copy(&fp_regs.fpregs) };
+    array[..FXSAVE_WORDS].copy_from_slice(&fxsave);
+    if let Some(hi) = &fp_regs.ymm_hi {
+        for (i, bytes) in hi.iter().flat_map(|reg| reg.chunks(8)).enumerate() {
+            array[FXSAVE_WORDS + i] = u64::from_le_bytes(bytes.try_into().unwrap());
+        }
+    }
+    array
+}
+
+fn fp_regs_from_array(array: &[u64; FP_WORD_COUNT], had_ymm_hi: bool) -> FpRegs {
+    let mut fxsave = [0u64; FXSAVE_WORDS];
+    fxsave.copy_from_slice(&array[..FXSAVE_WORDS]);
+    let ymm_hi = match had_ymm_hi {
+        true => {
+            let mut hi = [[0u8; 16]; 16];
+            for (i, reg) in hi.iter_mut().enumerate() {
+                reg[..8].copy_from_slice(&array[FXSAVE_WORDS + i * 2].to_le_bytes());
+                reg[8..].copy_from_slice(&array[FXSAVE_WORDS + i * 2 + 1].to_le_bytes());
+            }
+            Some(hi)
+        },
+        false => None,
+    };
+    FpRegs{ fpregs: unsafe { std::mem::transmute_copy(&fxsave) }, ymm_hi: ymm_hi }
+}
+
+// Words of the fxsave area and ymm upper halves that changed
+pub fn fp_regs_delta(old: &FpRegs, new: &FpRegs) -> Vec<(usize, u64, u64)> {
+    let old = fp_regs_as_array(old);
+    let new = fp_regs_as_array(new);
+    (0..FP_WORD_COUNT).filter(|i| old[*i] != new[*i]).map(|i| (i, old[i], new[i])).collect()
+}
+
+// Same as undo_regs for the x87/SSE/AVX state
+pub fn undo_fp_regs(fp_regs: &FpRegs, entry: &TraceEntry) -> FpRegs {
+    let mut array = fp_regs_as_array(fp_regs);
+    for (i, old, _) in &entry.fp_regs {
+        array[*i] = *old;
+    }
+    fp_regs_from_array(&array, fp_regs.ymm_hi.is_some())
+}
+
+// Puts the registers back to what they were before the entry's instruction ran
+pub fn undo_regs(regs: &UserRegsStruct, entry: &TraceEntry) -> UserRegsStruct {
+    let mut array = regs_as_array(regs);
+    for (i, old, _) in &entry.regs {
+        array[*i] = *old;
+    }
+    regs_from_array(&array)
+}
+
+fn reg_value(regs: &UserRegsStruct, reg: Register) -> Option<u64> {
+    // Segment registers want the segment's base. Only fs/gs have one in 64bit mode
+    let value = match reg.full_register() {
+        Register::ES | Register::CS | Register::SS | Register::DS => return Some(0),
+        Register::FS => return Some(regs.fs_base),
+        Register::GS => return Some(regs.gs_base),
+        Register::RAX => regs.rax,
+        Register::RBX => regs.rbx,
+        Register::RCX => regs.rcx,
+        Register::RDX => regs.rdx,
+        Register::RSI => regs.rsi,
+        Register::RDI => regs.rdi,
+        Register::RBP => regs.rbp,
+        Register::RSP => regs.rsp,
+        Register::R8 => regs.r8,
+        Register::R9 => regs.r9,
+        Register::R10 => regs.r10,
+        Register::R11 => regs.r11,
+        Register::R12 => regs.r12,
+        Register::R13 => regs.r13,
+        Register::R14 => regs.r14,
+        Register::R15 => regs.r15,
+        Register::RIP => regs.rip,
+        // TODO: vsib (scatter) index registers
+        _ => return None,
+    };
+
+    match reg.size() {
+        1 => Some(value & 0xFF),
+        2 => Some(value & 0xFFFF),
+        4 => Some(value & 0xFFFF_FFFF),
+        _ => Some(value),
+    }
+}
+
+// (addr, len) of everything the instruction at regs.rip is going to write, as far as we can tell.
+// Doesn't know about syscalls writing into buffers, or anything without a fixed size (xsave etc)
+pub fn memory_writes(code: &[u8], regs: &UserRegsStruct) -> Vec<(u64, usize)> {
+    let mut decoder = Decoder::with_ip(64, code, regs.rip, DecoderOptions::NONE);
+    let instr = decoder.decode();
+    if instr.is_invalid() {
+        return vec![];
+    }
+
+    let mut factory = InstructionInfoFactory::new();
+    let info = factory.info(&instr);
+
+    let mut writes = vec![];
+    for mem in info.used_memory() {
+        let writes_to = matches!(mem.access(), OpAccess::Write | OpAccess::CondWrite | OpAccess::ReadWrite | OpAccess::ReadCondWrite);
+        let size = mem.memory_size().size();
+        if !writes_to || size == 0 {
+            continue;
+        }
+
+        if let Some(addr) = mem.virtual_address(0, |reg, _, _| reg_value(regs, reg)) {
+            writes.push((addr, size));
+        }
+    }
+
+    writes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(addr: u64, memory: Vec<(u64, Vec<u8>, Vec<u8>)>) -> TraceEntry {
+        let memory = memory.into_iter().map(|(addr, old, new)| MemoryDelta{ addr: addr, old: old, new: new }).collect();
+        TraceEntry{ addr: addr, regs: vec![], fp_regs: vec![], memory: memory, breakpoint: false }
+    }
+
+    #[test]
+    fn most_recent_change() {
+        let mut trace = Trace::new();
+        trace.push(entry(0x10, vec![(0x100, vec![0, 0, 0, 0], vec![1, 0, 0, 0])]));
+        trace.push(entry(0x20, vec![(0x104, vec![0; 4], vec![2; 4])]));
+        trace.push(entry(0x30, vec![]));
+
+        let change = trace.last_change(0x100, 4).unwrap();
+        assert_eq!((change.steps_ago, change.addr), (2, 0x10));
+        let change = trace.last_change(0x102, 4).unwrap();
+        assert_eq!((change.steps_ago, change.addr), (1, 0x20));
+        assert!(trace.last_change(0x108, 4).is_none());
+        assert!(trace.last_change(u64::MAX, 4).is_none());
+    }
+
+    #[test]
+    fn fp_regs_undone() {
+        let mut before = FpRegs{ fpregs: unsafe { std::mem::zeroed() }, ymm_hi: Some([[0u8; 16]; 16]) };
+        before.fpregs.xmm_space[4] = 0x1234;
+        let mut after = before.clone();
+        after.fpregs.xmm_space[4] = 0x5678;
+        after.fpregs.mxcsr = 0x1f80;
+        after.ymm_hi.as_mut().unwrap()[15][15] = 0xff;
+
+        let delta = fp_regs_delta(&before, &after);
+        assert_eq!(delta.len(), 3);
+        let entry = TraceEntry{ addr: 0, regs: vec![], fp_regs: delta, memory: vec![], breakpoint: false };
+        let undone = undo_fp_regs(&after, &entry);
+        assert_eq!(undone.xmm(1), before.xmm(1));
+        assert_eq!(undone.fpregs.mxcsr, 0);
+        assert_eq!(undone.ymm(15), before.ymm(15));
+    }
+
+    #[test]
+    fn rewrites_with_the_same_bytes_dont_count() {
+        let mut trace = Trace::new();
+        trace.push(entry(0x10, vec![(0x100, vec![0, 0], vec![5, 5])]));
+        // Only the second byte actually changed
+        trace.push(entry(0x20, vec![(0x100, vec![5, 5], vec![5, 6])]));
+
+        assert_eq!(trace.last_change(0x100, 1).unwrap().addr, 0x10);
+        assert_eq!(trace.last_change(0x101, 1).unwrap().addr, 0x20);
+        assert_eq!(trace.last_change(0x100, 2).unwrap().addr, 0x20);
+    }
+}
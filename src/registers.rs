@@ -0,0 +1,332 @@
+use nix::libc;
+use nix::libc::user_fpregs_struct as UserFpRegsStruct;
+use nix::errno::Errno;
+use nix::unistd::Pid;
+
+use core::ffi::c_void;
+
+// Not exposed by libc. See linux/elf.h
+const NT_X86_XSTATE: usize = 0x202;
+
+// Offsets into the standard (non-compacted) XSAVE area, which is what PTRACE_GETREGSET gives us
+const XSAVE_HEADER_OFFSET: usize = 512;
+const XSAVE_YMM_HI128_OFFSET: usize = 576;
+const XSAVE_AVX_STATE_BIT: u64 = 1 << 2;
+
+// Big enough for everything up to and including AMX tile data. Writing it back only works with
+// all of it
+const XSAVE_MAX_SIZE: usize = 16384;
+
+// DR6 bits
+pub const DR6_WATCHPOINT_MASK: u64 = 0b1111;
+pub const DR6_SINGLE_STEP: u64 = 1 << 14;
+
+fn debug_reg_offset(index: usize) -> usize {
+    std::mem::offset_of!(libc::user, u_debugreg) + index * std::mem::size_of::<u64>()
+}
+
+pub fn read_debug_reg(pid: Pid, index: usize) -> Result<u64, Errno> {
+    let value = nix::sys::ptrace::read_user(pid, debug_reg_offset(index) as *mut c_void)?;
+    Ok(value as u64)
+}
+
+pub fn write_debug_reg(pid: Pid, index: usize, value: u64) -> Result<(), Errno> {
+    let res = unsafe {
+        libc::ptrace(libc::PTRACE_POKEUSER, libc::pid_t::from(pid), debug_reg_offset(index) as *mut c_void, value as *mut c_void)
+    };
+    Errno::result(res).map(drop)
+}
+
+#[derive(Clone)]
+pub struct FpRegs {
+    // x87 + SSE state in fxsave layout
+    pub fpregs: UserFpRegsStruct,
+    // Upper 128 bits of ymm0-15. None if the cpu/kernel doesn't give us AVX state
+    pub ymm_hi: Option<[[u8; 16]; 16]>,
+}
+
+impl FpRegs {
+    // 80bit extended precision value. st_space keeps each one in a 16 byte slot, this is just the 10 that matter
+    pub fn st(&self, index: usize) -> [u8; 10] {
+        let mut bytes = [0u8; 10];
+        let words = &self.fpregs.st_space[index * 4..index * 4 + 4];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = words[i / 4].to_le_bytes()[i % 4];
+        }
+
+        bytes
+    }
+
+    pub fn xmm(&self, index: usize) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        for (i, word) in self.fpregs.xmm_space[index * 4..index * 4 + 4].iter().enumerate() {
+            bytes[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    pub fn ymm(&self, index: usize) -> Option<[u8; 32]> {
+        let hi = self.ymm_hi.as_ref()?;
+
+        let mut bytes = [0u8; 32];
+        bytes[..16].copy_from_slice(&self.xmm(index));
+        bytes[16..].copy_from_slice(&hi[index]);
+        Some(bytes)
+    }
+}
+
+pub fn read_fp_regs(pid: Pid) -> Result<FpRegs, Errno> {
+    let mut fpregs: UserFpRegsStruct = unsafe { std::mem::zeroed() };
+    let res = unsafe {
+        libc::ptrace(libc::PTRACE_GETFPREGS, libc::pid_t::from(pid), std::ptr::null_mut::<c_void>(), &mut fpregs as *mut UserFpRegsStruct as *mut c_void)
+    };
+    Errno::result(res)?;
+
+    Ok(FpRegs{ fpregs: fpregs, ymm_hi: read_ymm_hi(pid) })
+}
+
+// ymm_hi only goes back in if the kernel gave it to us in the first place
+pub fn write_fp_regs(pid: Pid, fp_regs: &FpRegs) -> Result<(), Errno> {
+    let mut fpregs = fp_regs.fpregs;
+    let res = unsafe {
+        libc::ptrace(libc::PTRACE_SETFPREGS, libc::pid_t::from(pid), std::ptr::null_mut::<c_void>(), &mut fpregs as *mut UserFpRegsStruct as *mut c_void)
+    };
+    Errno::result(res)?;
+
+    let ymm_hi = match &fp_regs.ymm_hi {
+        Some(hi) => hi,
+        None => return Ok(()),
+    };
+    // Most instructions don't touch them
+    if read_ymm_hi(pid).as_ref() == Some(ymm_hi) {
+        return Ok(());
+    }
+    // Read back after SETFPREGS, the legacy area at the start of it gets written too
+    let mut xsave = read_xsave(pid).ok_or(Errno::EIO)?;
+    for (i, reg) in ymm_hi.iter().enumerate() {
+        let offset = XSAVE_YMM_HI128_OFFSET + i * 16;
+        xsave[offset..offset + 16].copy_from_slice(reg);
+    }
+    let mut xstate_bv = [0u8; 8];
+    xstate_bv.copy_from_slice(&xsave[XSAVE_HEADER_OFFSET..XSAVE_HEADER_OFFSET + 8]);
+    let xstate_bv = u64::from_le_bytes(xstate_bv) | XSAVE_AVX_STATE_BIT;
+    xsave[XSAVE_HEADER_OFFSET..XSAVE_HEADER_OFFSET + 8].copy_from_slice(&xstate_bv.to_le_bytes());
+
+    let mut iov = libc::iovec{ iov_base: xsave.as_mut_ptr() as *mut c_void, iov_len: xsave.len() };
+    let res = unsafe {
+        libc::ptrace(libc::PTRACE_SETREGSET, libc::pid_t::from(pid), NT_X86_XSTATE as *mut c_void, &mut iov as *mut libc::iovec as *mut c_void)
+    };
+    Errno::result(res).map(drop)
+}
+
+// Whole XSAVE area, as long as the kernel gave us at least up to the AVX state
+fn read_xsave(pid: Pid) -> Option<Vec<u8>> {
+    let mut xsave = vec![0u8; XSAVE_MAX_SIZE];
+    let mut iov = libc::iovec{ iov_base: xsave.as_mut_ptr() as *mut c_void, iov_len: xsave.len() };
+    let res = unsafe {
+        libc::ptrace(libc::PTRACE_GETREGSET, libc::pid_t::from(pid), NT_X86_XSTATE as *mut c_void, &mut iov as *mut libc::iovec as *mut c_void)
+    };
+    if Errno::result(res).is_err() {
+        return None;
+    }
+
+    // Kernel shrinks iov_len to however much it actually wrote
+    if iov.iov_len < XSAVE_YMM_HI128_OFFSET + 16 * 16 {
+        return None;
+    }
+    xsave.truncate(iov.iov_len);
+    Some(xsave)
+}
+
+fn read_ymm_hi(pid: Pid) -> Option<[[u8; 16]; 16]> {
+    let xsave = read_xsave(pid)?;
+
+    let mut xstate_bv = [0u8; 8];
+    xstate_bv.copy_from_slice(&xsave[XSAVE_HEADER_OFFSET..XSAVE_HEADER_OFFSET + 8]);
+    // AVX state is in its init state (all zeroes) if the bit isn't set, the area itself might be garbage
+    let avx_in_use = u64::from_le_bytes(xstate_bv) & XSAVE_AVX_STATE_BIT != 0;
+
+    let mut ymm_hi = [[0u8; 16]; 16];
+    if avx_in_use {
+        for (i, reg) in ymm_hi.iter_mut().enumerate() {
+            let offset = XSAVE_YMM_HI128_OFFSET + i * 16;
+            reg.copy_from_slice(&xsave[offset..offset + 16]);
+        }
+    }
+
+    Some(ymm_hi)
+}
+
+// Good enough for displaying. Loses precision and doesn't care about pseudo-denormals etc.
+pub fn f80_to_f64(bytes: &[u8; 10]) -> f64 {
+    let mut mantissa_bytes = [0u8; 8];
+    mantissa_bytes.copy_from_slice(&bytes[..8]);
+    let mantissa = u64::from_le_bytes(mantissa_bytes);
+    let sign_exp = u16::from_le_bytes([bytes[8], bytes[9]]);
+
+    let sign = if sign_exp & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exp = (sign_exp & 0x7FFF) as i32;
+
+    if exp == 0 && mantissa == 0 {
+        return sign * 0.0;
+    }
+    if exp == 0x7FFF {
+        if mantissa << 1 == 0 {
+            return sign * f64::INFINITY;
+        }
+        return f64::NAN;
+    }
+
+    // Explicit integer bit, so the mantissa is 1.63 fixed point
+    sign * (mantissa as f64 / (1u64 << 63) as f64) * 2f64.powi(exp - 16383)
+}
+
+// Field order of user_regs_struct
+pub const USER_REGS_NAMES: [&str; 27] = [
+    "r15", "r14", "r13", "r12", "rbp", "rbx", "r11", "r10", "r9", "r8",
+    "rax", "rcx", "rdx", "rsi", "rdi", "orig_rax", "rip", "cs", "eflags", "rsp",
+    "ss", "fs_base", "gs_base", "ds", "es", "fs", "gs",
+];
+
+// Looks a general purpose register up by its USER_REGS_NAMES name
+pub fn user_reg(regs: &libc::user_regs_struct, name: &str) -> Option<u64> {
+    let index = USER_REGS_NAMES.iter().position(|n| *n == name)?;
+    // All u64s, repr(C)
+    let array: [u64; 27] = unsafe { std::mem::transmute_copy(regs) };
+    Some(array[index])
+}
+
+pub const EFLAGS: [(&str, u64); 17] = [
+    ("CF", 1 << 0),
+    ("PF", 1 << 2),
+    ("AF", 1 << 4),
+    ("ZF", 1 << 6),
+    ("SF", 1 << 7),
+    ("TF", 1 << 8),
+    ("IF", 1 << 9),
+    ("DF", 1 << 10),
+    ("OF", 1 << 11),
+    ("IOPL0", 1 << 12),
+    ("IOPL1", 1 << 13),
+    ("NT", 1 << 14),
+    ("RF", 1 << 16),
+    ("VM", 1 << 17),
+    ("AC", 1 << 18),
+    ("VIF", 1 << 19),
+    ("VIP", 1 << 20),
+];
+
+pub fn decode_eflags(eflags: u64) -> String {
+    EFLAGS.iter()
+        .filter(|(_, mask)| eflags & mask != 0)
+        .map(|(name, _)| *name)
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum VectorLane {
+    F32,
+    F64,
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl VectorLane {
+    pub const ALL: [VectorLane; 10] = [
+        VectorLane::F32, VectorLane::F64,
+        VectorLane::I8, VectorLane::I16, VectorLane::I32, VectorLane::I64,
+        VectorLane::U8, VectorLane::U16, VectorLane::U32, VectorLane::U64,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            VectorLane::F32 => "f32",
+            VectorLane::F64 => "f64",
+            VectorLane::I8 => "i8",
+            VectorLane::I16 => "i16",
+            VectorLane::I32 => "i32",
+            VectorLane::I64 => "i64",
+            VectorLane::U8 => "u8",
+            VectorLane::U16 => "u16",
+            VectorLane::U32 => "u32",
+            VectorLane::U64 => "u64",
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            VectorLane::I8 | VectorLane::U8 => 1,
+            VectorLane::I16 | VectorLane::U16 => 2,
+            VectorLane::F32 | VectorLane::I32 | VectorLane::U32 => 4,
+            VectorLane::F64 | VectorLane::I64 | VectorLane::U64 => 8,
+        }
+    }
+
+    // Highest lane first, same as gdb/intel manuals
+    pub fn format(&self, bytes: &[u8], hex: bool) -> String {
+        let mut lanes = vec![];
+        for chunk in bytes.chunks_exact(self.size()).rev() {
+            let mut raw = [0u8; 8];
+            raw[..chunk.len()].copy_from_slice(chunk);
+            let raw = u64::from_le_bytes(raw);
+
+            let lane = match self {
+                VectorLane::F32 => format!("{}", f32::from_bits(raw as u32)),
+                VectorLane::F64 => format!("{}", f64::from_bits(raw)),
+                _ if hex => format!("0x{:0width$x}", raw, width = self.size() * 2),
+                VectorLane::I8 => format!("{}", raw as u8 as i8),
+                VectorLane::I16 => format!("{}", raw as u16 as i16),
+                VectorLane::I32 => format!("{}", raw as u32 as i32),
+                VectorLane::I64 => format!("{}", raw as i64),
+                _ => format!("{}", raw),
+            };
+            lanes.push(lane);
+        }
+
+        format!("{{{}}}", lanes.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn f80(mantissa: u64, sign_exp: u16) -> [u8; 10] {
+        let mut bytes = [0u8; 10];
+        bytes[..8].copy_from_slice(&mantissa.to_le_bytes());
+        bytes[8..].copy_from_slice(&sign_exp.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn f80_values() {
+        assert_eq!(f80_to_f64(&f80(0x8000_0000_0000_0000, 0x3FFF)), 1.0);
+        assert_eq!(f80_to_f64(&f80(0xA000_0000_0000_0000, 0xC000)), -2.5);
+        assert_eq!(f80_to_f64(&f80(0xC000_0000_0000_0000, 0x3FFE)), 0.75);
+        assert_eq!(f80_to_f64(&f80(0, 0)), 0.0);
+        assert!(f80_to_f64(&f80(0, 0x8000)).is_sign_negative());
+    }
+
+    #[test]
+    fn f80_special() {
+        assert_eq!(f80_to_f64(&f80(0x8000_0000_0000_0000, 0x7FFF)), f64::INFINITY);
+        assert_eq!(f80_to_f64(&f80(0x8000_0000_0000_0000, 0xFFFF)), f64::NEG_INFINITY);
+        assert!(f80_to_f64(&f80(0xC000_0000_0000_0000, 0x7FFF)).is_nan());
+    }
+
+    #[test]
+    fn eflags_names() {
+        assert_eq!(decode_eflags(0), "");
+        assert_eq!(decode_eflags(0x246), "PF ZF IF");
+        // Bit 1 is always set and has no name
+        assert_eq!(decode_eflags(0x2 | 1 | 1 << 11), "CF OF");
+    }
+}
@@ -8,176 +8,668 @@ use crate::insertpoint::BreakPoint;
 
 use crate::src_file::SrcFile;
 use crate::patcher::{Patcher, LocalPatcher};
+use crate::tracer::{ Tracer, TracerHandle, TracerEvent, TracerCommand, AfterStepOver };
 
 use std::thread::JoinHandle;
 
-use std::sync::Arc;
-use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ Arc, Mutex };
 
 use std::sync::mpsc::{ channel, Sender, Receiver };
 
 use std::result::Result;
 use nix::sys::wait::WaitStatus;
+use std::time::Instant;
 
-use linux_personality::{personality, ADDR_NO_RANDOMIZE};
-use nix::sys::{ptrace, wait::waitpid};
-use nix::unistd::{fork, ForkResult, Pid};
+use nix::unistd::Pid;
 use nix::errno::Errno;
+use nix::sys::signal::Signal;
 
-use std::os::unix::process::CommandExt;
-use std::process::{ exit, Command };
-
-use std::collections::{ HashSet, HashMap };
+use std::collections::{ BTreeMap, HashSet, HashMap };
 
 use crate::OfflineDebugInfo;
-
-struct RunThread {
-    pub join_handle: JoinHandle<()>,
-    rx: Receiver<Result<WaitStatus, Errno>>,
+use crate::signals::{ SignalPolicies, describe_siginfo };
+use crate::recording::LastChange;
+use crate::timeline::{ Timeline, Snapshot, capture_memory, evaluate_locals };
+use crate::dwarf_model::DwarfModel;
+
+#[derive(Debug, Clone)]
+pub enum DebugeeEvent {
+    // SIGTRAP the tracer couldn't attribute to anything, e.g. an int3. Run matches these
+    // against its breakpoints
+    Trap,
+    BreakpointHit{ id: usize, addr: u64 },
+    StepComplete{ addr: u64 },
+    WatchpointTriggered{ index: usize, addr: u64 },
+    // User asked the debugee to stop wherever it is
+    Interrupted,
+    // We let go of the debugee, it's on its own now
+    Detached,
+    SignalReceived{ signal: Signal },
+    Exited{ code: i32 },
+    Killed{ signal: Signal, core_dumped: bool },
+    Exec,
+    // New thread in the debugee. None if the kernel wouldn't tell us its tid
+    Clone{ new_pid: Option<Pid> },
+    // Debugee got replaced by a fork of this checkpoint. The stop it was taken at follows
+    CheckpointRestored{ pid: Pid },
 }
 
-impl RunThread {
-    fn new(pid: Pid, parked: Arc<AtomicBool>, should_die: Arc<AtomicBool>) -> Self {
-        let (tx, rx) = channel();
-
-        let join_handle = std::thread::spawn(move || {
-            let mut res = waitpid(pid, None);
-            while res.is_ok() {
-                if let Ok(nix::sys::wait::WaitStatus::Exited(..)) = res {
-                    // TODO: place death signal in the channel
-                    println!("Child exited, run thread died!");
-                    break;
-                }
-
-                // Before sending off the event so that we don't get deadlocked once
-                // we pick this message up by setting parked to false
-                parked.store(true, Ordering::Relaxed);
-                tx.send(res).expect("Let's assume doesn't fail for now");
-
-                while parked.load(Ordering::Relaxed) {
-                    std::thread::park();
-                }
-
-                if should_die.load(Ordering::Relaxed) {
-                    println!("Seppuku by run thread!");
-                    return;
-                }
+impl DebugeeEvent {
+    pub(crate) fn from_wait_status(status: WaitStatus) -> Option<DebugeeEvent> {
+        match status {
+            WaitStatus::Exited(_, code) => Some(DebugeeEvent::Exited{ code: code }),
+            WaitStatus::Signaled(_, sig, core_dumped) => Some(DebugeeEvent::Killed{ signal: sig, core_dumped: core_dumped }),
+            WaitStatus::Stopped(_, Signal::SIGTRAP) => Some(DebugeeEvent::Trap),
+            WaitStatus::Stopped(_, sig) => Some(DebugeeEvent::SignalReceived{ signal: sig }),
+            WaitStatus::PtraceEvent(_, _, nix::libc::PTRACE_EVENT_EXEC) => Some(DebugeeEvent::Exec),
+            WaitStatus::PtraceEvent(_, _, nix::libc::PTRACE_EVENT_CLONE) => Some(DebugeeEvent::Clone{ new_pid: None }),
+            WaitStatus::PtraceEvent(..) => Some(DebugeeEvent::Trap),
+            // Not stops, nothing to report
+            WaitStatus::PtraceSyscall(_) | WaitStatus::Continued(_) | WaitStatus::StillAlive => None,
+        }
+    }
 
-                res = waitpid(pid, None);
-            }
-        });
+    pub fn is_exit(&self) -> bool {
+        matches!(self, DebugeeEvent::Exited{..} | DebugeeEvent::Killed{..})
+    }
 
-        RunThread { join_handle: join_handle, rx: rx }
+    // Nothing comes after this one
+    pub fn ends_run(&self) -> bool {
+        self.is_exit() || matches!(self, DebugeeEvent::Detached)
     }
 }
 
 use nix::libc::user_regs_struct as UserRegsStruct;
+use nix::libc::siginfo_t as SigInfo;
+use crate::registers::FpRegs;
 
+#[derive(Clone)]
 pub struct DebugeeState {
     pub regs: UserRegsStruct,
+    // None if the kernel refused to give us x87/SSE state
+    pub fp_regs: Option<FpRegs>,
+
+    // Signal that caused this stop, if it wasn't one of our SIGTRAPs
+    pub signal: Option<Signal>,
+    pub siginfo: Option<SigInfo>,
 
+    // Runtime address of the instruction we're stopped at. rip, except for breakpoint hits
     pub addr: u64,
     pub file: String,
     pub line: Option<usize>,
     pub col: Option<usize>,
 }
 
-pub struct RuntimeAddr(u64);
-pub struct RuntimeBreakpoint{}
+// TODO: fix this bullshit. Offline (file) address + this is where it ends up with ASLR off, for
+// PIEs whose .text starts at 0x1040. Should come from the debugee's mappings
+pub const LOAD_BIAS: u64 = 0x555555555040 - 0x1040;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RuntimeAddr(pub u64);
+
+#[derive(Debug)]
+pub struct RuntimeBreakpoint{
+    // BreakPoint::id -> enabled, for every session breakpoint sitting on this address
+    pub owners: BTreeMap<usize, bool>,
+}
+
+impl RuntimeBreakpoint {
+    // The one a hit gets attributed to
+    pub fn id(&self) -> Option<usize> {
+        self.owners.iter().find(|(_, enabled)| **enabled).map(|(id, _)| *id)
+    }
+}
+
+// Stopped fork of the debugee we can go back to, see Tracer::inject_fork
+pub struct Checkpoint {
+    pub id: usize,
+    pub pid: Pid,
+    pub time: Instant,
+    pub addr: u64,
+    // Stop the checkpoint was taken at, we're back at it after restoring
+    pub event: DebugeeEvent,
+    // int3s that were in at the time (addr -> original byte)
+    pub breakpoints: HashMap<u64, u8>,
+}
 
 pub struct Run {
     pub debugee_pid: Pid,
     pub debugee_patcher: Box<dyn Patcher>,
 
-    run_thread_parked: Arc<AtomicBool>,
-    pub run_thread_should_die: Arc<AtomicBool>,
-    run_thread: RunThread,
-
-    pub debugee_event: Option<Result<WaitStatus, Errno>>,
+    pub tracer: TracerHandle,
+    tracer_events: Receiver<TracerEvent>,
+    tracer_thread: JoinHandle<()>,
+    // Told the tracer to let the debugee go and haven't heard of it stopping since
+    running: bool,
+
+    pub debugee_event: Option<DebugeeEvent>,
+    pub event_history: Vec<(Instant, DebugeeEvent)>,
+    event_subscribers: Vec<Sender<DebugeeEvent>>,
+    // Tracer went away without telling us how the debugee died
+    lost: bool,
     pub debugee_state: Option<DebugeeState>,
+    // State at the previous stop, for highlighting what changed
+    pub prev_debugee_state: Option<DebugeeState>,
 
     pub breakpoints: HashMap<RuntimeAddr, RuntimeBreakpoint>,
+
+    pub signal_policies: Arc<Mutex<SignalPolicies>>,
+    // Delivered to the debugee on the next continue
+    pub pending_signal: Option<Signal>,
+    // Signals with `print` set, including the ones we didn't stop for
+    pub signal_log: Vec<String>,
+
+    // Everything the debugee wrote to stdout/stderr
+    pub output: Arc<Mutex<Vec<u8>>>,
+
+    // Single stepping everything so we can go backwards, see recording.rs
+    pub recording: bool,
+
+    // Snapshot of every stop, for looking back at them after the debugee moved on
+    pub timeline: Timeline,
+    // Off while a frontend steps through stops nobody gets to see (source level step, finish),
+    // it calls snapshot() once it's done
+    pub snapshot_stops: bool,
+    // Extra memory ranges to save in every snapshot
+    pub watched_ranges: Vec<(u64, usize)>,
+    // For evaluating the locals in snapshots
+    pub dwarf: Arc<DwarfModel>,
+
+    pub checkpoints: Vec<Checkpoint>,
+    next_checkpoint_id: usize,
 }
 
 impl Run {
-    pub fn new(pid: Pid) -> Self {
-        let run_thread_parked = Arc::new(AtomicBool::new(false));
-        let run_thread_should_die = Arc::new(AtomicBool::new(false));
-        Run { 
-            debugee_pid: pid,
-            debugee_patcher: Box::new(LocalPatcher::new(pid)),
-            run_thread_parked: Arc::clone(&run_thread_parked),
-            run_thread_should_die: Arc::clone(&run_thread_should_die),
-            run_thread: RunThread::new(pid, Arc::clone(&run_thread_parked), Arc::clone(&run_thread_should_die)),
+    pub fn launch(path: String, signal_policies: Arc<Mutex<SignalPolicies>>) -> Result<Self, Errno> {
+        let output = Arc::new(Mutex::new(vec![]));
+        let (tracer, tracer_events, tracer_thread) = Tracer::launch(path, Arc::clone(&signal_policies), Arc::clone(&output))?;
+
+        Ok(Run { 
+            debugee_pid: tracer.pid(),
+            debugee_patcher: Box::new(LocalPatcher::new(tracer.clone())),
+            tracer: tracer,
+            tracer_events: tracer_events,
+            tracer_thread: tracer_thread,
+            running: true,
             debugee_event: None,
+            event_history: vec![],
+            event_subscribers: vec![],
+            lost: false,
             debugee_state: None,
+            prev_debugee_state: None,
             breakpoints: HashMap::new(),
+            signal_policies: signal_policies,
+            pending_signal: None,
+            signal_log: vec![],
+            output: output,
+            recording: false,
+            timeline: Timeline::new(),
+            snapshot_stops: true,
+            watched_ranges: vec![],
+            dwarf: Arc::new(DwarfModel::empty()),
+            checkpoints: vec![],
+            next_checkpoint_id: 0,
+        })
+    }
+
+    // Makes the int3s in the debugee match the session's breakpoints. Interrupts the debugee for a
+    // bit if it's running
+    pub fn sync_bp_state(&mut self, bps: &Vec<BreakPoint<'_>>) {
+        if self.finished() {
+            return;
+        }
+
+        let mut wanted: HashMap<RuntimeAddr, BTreeMap<usize, bool>> = HashMap::new();
+        for bp in bps {
+            let addr = RuntimeAddr(bp.point.addr + LOAD_BIAS);
+            wanted.entry(addr).or_default().insert(bp.id, bp.point.enabled);
+        }
+
+        let mut inject = vec![];
+        let mut enable = vec![];
+        let mut disable = vec![];
+        let mut remove = vec![];
+        for (addr, owners) in &wanted {
+            let current = self.breakpoints.get(addr);
+            for (id, enabled) in owners {
+                match current.and_then(|c| c.owners.get(id)) {
+                    None => {
+                        // Patcher injects them enabled
+                        inject.push(addr.0);
+                        if !enabled {
+                            disable.push(addr.0);
+                        }
+                    },
+                    Some(was_enabled) if was_enabled != enabled => {
+                        if *enabled {
+                            enable.push(addr.0);
+                        } else {
+                            disable.push(addr.0);
+                        }
+                    },
+                    _ => {},
+                }
+            }
+        }
+        for (addr, current) in &self.breakpoints {
+            for (id, enabled) in &current.owners {
+                if wanted.get(addr).map_or(false, |w| w.contains_key(id)) {
+                    continue;
+                }
+
+                // Patcher wants them disabled before they go away
+                if *enabled {
+                    disable.push(addr.0);
+                }
+                remove.push(addr.0);
+            }
+        }
+
+        if inject.is_empty() && enable.is_empty() && disable.is_empty() && remove.is_empty() {
+            return;
+        }
+
+        // Order matters, see above
+        if !inject.is_empty() && self.debugee_patcher.inject_breakpoints(&inject).is_err() {
+            println!("Failed to inject some breakpoints: {:x?}", inject);
+        }
+        if !enable.is_empty() && self.debugee_patcher.enable_breakpoints(&enable).is_err() {
+            println!("Failed to enable some breakpoints: {:x?}", enable);
+        }
+        if !disable.is_empty() && self.debugee_patcher.disable_breakpoints(&disable).is_err() {
+            println!("Failed to disable some breakpoints: {:x?}", disable);
+        }
+        if !remove.is_empty() && self.debugee_patcher.remove_breakpoints(&remove).is_err() {
+            println!("Failed to remove some breakpoints: {:x?}", remove);
         }
+
+        self.breakpoints = wanted.into_iter().map(|(addr, owners)| (addr, RuntimeBreakpoint{ owners: owners })).collect();
     }
 
-    pub fn sync_bp_state(&mut self, bps: Vec<BreakPoint<'_>>) {
+    // Every event from here on, including ones that don't stop the debugee. Disconnects once the
+    // run is over
+    pub fn subscribe(&mut self) -> Receiver<DebugeeEvent> {
+        let (tx, rx) = channel();
+        self.event_subscribers.push(tx);
+        rx
+    }
+
+    fn publish_event(&mut self, event: &DebugeeEvent) {
+        self.event_history.push((Instant::now(), event.clone()));
+        self.event_subscribers.retain(|s| s.send(event.clone()).is_ok());
+    }
 
+    fn refine_event(&self, event: DebugeeEvent, regs: &UserRegsStruct) -> DebugeeEvent {
+        match event {
+            DebugeeEvent::Trap => {
+                // int3 has already executed, so rip is one past it
+                let bp_addr = regs.rip - 1;
+                match self.breakpoints.get(&RuntimeAddr(bp_addr)).and_then(|bp| bp.id()) {
+                    Some(id) => DebugeeEvent::BreakpointHit{ id: id, addr: bp_addr },
+                    None => DebugeeEvent::Trap,
+                }
+            },
+            e => e,
+        }
     }
 
     pub fn poll_debugee_state(&mut self, block: bool) {
         let msg = if block {
-            match self.run_thread.rx.recv() {
+            match self.tracer_events.recv() {
                 Ok(m) => Ok(m),
                 Err(_) => Err(std::sync::mpsc::TryRecvError::Disconnected),
             }
         } else {
-            self.run_thread.rx.try_recv()
+            self.tracer_events.try_recv()
         };
 
         match msg {
-            Ok(res) => {
-                //println!("Signal: {:?}", res);
-                self.debugee_event = Some(res);
+            Ok(TracerEvent{ event, stop, siginfo }) => {
+                if let DebugeeEvent::SignalReceived{ signal } = event {
+                    let policy = self.signal_policies.lock().unwrap().get(signal);
+                    if policy.print {
+                        let desc = match &siginfo {
+                            Some(info) => describe_siginfo(signal, info),
+                            None => format!("{}", signal),
+                        };
+                        println!("Debugee received {}", desc);
+                        self.signal_log.push(desc);
+                    }
+                    // Tracer has already passed it on if we're not stopping
+                    if stop.is_some() {
+                        self.pending_signal = if policy.pass { Some(signal) } else { None };
+                    }
+                }
 
-                let regs = ptrace::getregs(self.debugee_pid).expect("Getting registers failed");
+                let stop = match stop {
+                    Some(s) => s,
+                    None => {
+                        // Not a stop, the debugee is either still going or gone
+                        self.publish_event(&event);
+                        if event.ends_run() {
+                            self.running = false;
+                            self.debugee_event = Some(event);
+                        }
+                        return;
+                    },
+                };
+                self.running = false;
+
+                let event = self.refine_event(event, &stop.regs);
+                self.publish_event(&event);
+                let signal = match event {
+                    DebugeeEvent::SignalReceived{ signal } => Some(signal),
+                    _ => None,
+                };
+                // Only an int3 leaves rip one past where we stopped. Steps, pauses, signals etc
+                // are right at the next instruction
+                let addr = match event {
+                    DebugeeEvent::BreakpointHit{ addr, .. } => addr,
+                    _ => stop.regs.rip,
+                };
+                self.debugee_event = Some(event);
+
+                self.prev_debugee_state = self.debugee_state.take();
                 self.debugee_state = Some(DebugeeState{
-                    regs: regs,
-                    addr: regs.rip,
+                    regs: stop.regs,
+                    fp_regs: stop.fp_regs,
+                    signal: signal,
+                    siginfo: if signal.is_some() { siginfo } else { None },
+                    addr: addr,
                     file: "".to_owned(),
                     line: None,
                     col: None,
                 });
 
+                if self.snapshot_stops {
+                    self.snapshot();
+                }
+
                 // TODO: generate state here
             },
             Err(std::sync::mpsc::TryRecvError::Disconnected) => {
-                println!("Thread died! Killing run");
-                // TODO: fuck is this horrible. Return a custom event probably
-                //self.debugee_event = Some(Ok(nix::sys::wait::WaitStatus::Exited(self.debugee_pid, -1)));
-                self.debugee_event = Some(Err(Errno::EOWNERDEAD));
+                if !self.lost && !self.exited() {
+                    println!("Tracer died! Killing run");
+                }
+                self.lost = true;
+                self.running = false;
             },
             _ => {}, // Ignore empty channel issues
         }
     }
 
+    // Saves the current stop to the timeline
+    pub fn snapshot(&mut self) {
+        let (event, state) = match (&self.debugee_event, &self.debugee_state) {
+            (Some(e), Some(s)) if !self.running && !self.finished() => (e.clone(), s.clone()),
+            _ => return,
+        };
+
+        let read_memory = |addr, len| self.debugee_patcher.read_memory(addr, len);
+        let locals = evaluate_locals(read_memory, &state, &self.dwarf);
+        let memory = capture_memory(read_memory, &state.regs, &self.watched_ranges);
+        self.timeline.push(Snapshot{
+            time: Instant::now(),
+            event: event,
+            state: state,
+            locals: locals,
+            memory: memory,
+        });
+    }
+
+    pub fn exited(&self) -> bool {
+        self.debugee_event.as_ref().map_or(false, |e| e.is_exit())
+    }
+
+    // Nothing more will ever come out of this run
+    pub fn finished(&self) -> bool {
+        self.lost || self.debugee_event.as_ref().map_or(false, |e| e.ends_run())
+    }
+
+    // For showing how a finished run ended
+    pub fn exit_description(&self) -> Option<String> {
+        match &self.debugee_event {
+            Some(DebugeeEvent::Exited{ code }) => Some(format!("Exited with code {}", code)),
+            Some(DebugeeEvent::Killed{ signal, core_dumped: true }) => Some(format!("Killed by {} (core dumped)", signal)),
+            Some(DebugeeEvent::Killed{ signal, .. }) => Some(format!("Killed by {}", signal)),
+            Some(DebugeeEvent::Detached) => Some("Detached".to_owned()),
+            _ if self.lost => Some("Lost track of the debugee".to_owned()),
+            _ => None,
+        }
+    }
+
     pub fn cont(&mut self) {
-        if !self.run_thread_parked.load(Ordering::Relaxed) {
+        if self.running || self.finished() {
             return;
         }
 
-        self.run_thread_parked.store(false, Ordering::Relaxed);
-        self.run_thread.join_handle.thread().unpark();
-        
-        // TODO: This can get fucked by some timing issues
-        // ~~e.g. if this is called before the watcher thread parks itself~~
-        // potentially?
+        self.running = true;
+        let signal = self.pending_signal.take();
+        let hit = match self.debugee_event {
+            Some(DebugeeEvent::BreakpointHit{ addr, .. }) => Some(addr),
+            _ => None,
+        };
+        match (hit, self.recording) {
+            // Stopped on one of ours, the int3 has to get out of the way first
+            (Some(addr), true) => {
+                let breakpoints = self.debugee_patcher.active_breakpoints();
+                let res = self.debugee_patcher.step_over(addr, AfterStepOver::RecordedCont{ signal: signal, breakpoints: breakpoints });
+                self.step_over_sent(addr, res);
+            },
+            (Some(addr), false) => {
+                let res = self.debugee_patcher.step_over(addr, AfterStepOver::Cont(signal));
+                self.step_over_sent(addr, res);
+            },
+            (None, true) => self.tracer.send(TracerCommand::RecordedCont{ signal: signal, breakpoints: self.debugee_patcher.active_breakpoints() }),
+            (None, false) => self.tracer.cont(signal),
+        }
+    }
+
+    // Nothing's going to come back if the step over never made it to the tracer, the debugee is
+    // still sitting on the breakpoint
+    fn step_over_sent(&mut self, addr: u64, res: Result<(), ()>) {
+        if res.is_err() {
+            println!("Failed to step over the breakpoint at 0x{:x}", addr);
+            self.running = false;
+        }
+    }
 
-        ptrace::cont(self.debugee_pid, None);
+    // Blocks until the debugee stops or the run is over
+    pub fn wait_for_stop(&mut self) {
+        while self.running && !self.finished() {
+            self.poll_debugee_state(true);
+        }
+    }
+
+    // Single instruction. Shows up as StepComplete, or whatever else stopped the debugee on the way
+    pub fn step(&mut self) {
+        if self.running || self.finished() {
+            return;
+        }
+
+        self.running = true;
+        // Stepping over the int3 is the step
+        if let Some(DebugeeEvent::BreakpointHit{ addr, .. }) = self.debugee_event {
+            let res = self.debugee_patcher.step_over(addr, AfterStepOver::Report);
+            self.step_over_sent(addr, res);
+            return;
+        }
+        self.tracer.step(self.pending_signal.take());
+    }
+
+    // Debugee has to be stopped. Returns the new checkpoint's id
+    pub fn checkpoint(&mut self) -> Result<usize, Errno> {
+        if self.running() || self.finished() {
+            return Err(Errno::EBUSY);
+        }
+
+        let event = self.debugee_event.clone().ok_or(Errno::EINVAL)?;
+        let addr = self.debugee_state.as_ref().map_or(0, |s| s.regs.rip);
+        let pid = self.tracer.checkpoint()?;
+
+        let id = self.next_checkpoint_id;
+        self.next_checkpoint_id += 1;
+        self.checkpoints.push(Checkpoint{
+            id: id,
+            pid: pid,
+            time: Instant::now(),
+            addr: addr,
+            event: event,
+            breakpoints: self.debugee_patcher.active_breakpoints(),
+        });
+        Ok(id)
+    }
+
+    // Throws the current debugee away and carries on from where the checkpoint was taken, with
+    // the breakpoints we have now. The checkpoint stays around. Stop shows up through
+    // poll_debugee_state as usual
+    pub fn restore_checkpoint(&mut self, id: usize) -> Result<(), Errno> {
+        if self.running() || self.finished() {
+            return Err(Errno::EBUSY);
+        }
+
+        let checkpoint = self.checkpoints.iter().find(|c| c.id == id).ok_or(Errno::EINVAL)?;
+        let pid = self.tracer.restore_checkpoint(checkpoint.pid, checkpoint.breakpoints.clone(), checkpoint.event.clone())?;
+        self.debugee_pid = pid;
+        self.pending_signal = None;
+        if self.debugee_patcher.reapply_breakpoints().is_err() {
+            println!("Failed to put some breakpoints back into the restored checkpoint");
+        }
+        Ok(())
+    }
+
+    pub fn drop_checkpoint(&mut self, id: usize) {
+        if let Some(i) = self.checkpoints.iter().position(|c| c.id == id) {
+            let checkpoint = self.checkpoints.remove(i);
+            self.tracer.send(TracerCommand::DropCheckpoint(checkpoint.pid));
+        }
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        if recording == self.recording || self.finished() {
+            return;
+        }
+
+        self.recording = recording;
+        self.tracer.send(if recording { TracerCommand::StartRecording } else { TracerCommand::StopRecording });
+    }
+
+    pub fn recorded_steps(&self) -> usize {
+        self.tracer.recorded_steps()
+    }
+
+    pub fn reverse_step(&mut self) {
+        if self.can_reverse() {
+            self.running = true;
+            self.tracer.send(TracerCommand::ReverseStep);
+        }
+    }
+
+    pub fn reverse_cont(&mut self) {
+        if self.can_reverse() {
+            self.running = true;
+            self.tracer.send(TracerCommand::ReverseCont{ breakpoints: self.debugee_patcher.active_breakpoints() });
+        }
+    }
+
+    // Ends up right before the instruction that last wrote to [addr, addr + len)
+    pub fn reverse_until_change(&mut self, addr: u64, len: usize) {
+        if self.can_reverse() {
+            self.running = true;
+            self.tracer.send(TracerCommand::ReverseUntilChange{ addr: addr, len: len });
+        }
+    }
+
+    pub fn last_change(&self, addr: u64, len: usize) -> Option<LastChange> {
+        if !self.recording {
+            return None;
+        }
+
+        self.tracer.last_change(addr, len)
+    }
+
+    // Reverse execution only works on what got recorded, and only while stopped
+    pub fn can_reverse(&self) -> bool {
+        self.recording && !self.running && !self.finished()
+    }
+
+    // Stop event shows up through poll_debugee_state like any other. If the debugee happens to stop
+    // on its own on the way, that's what gets reported instead
+    pub fn pause(&mut self) {
+        if !self.running() {
+            return;
+        }
+
+        self.tracer.send(TracerCommand::Interrupt);
+    }
+
+    // Stopped: delivered on the next continue, same as gdb's `signal`. Running: sent right away
+    // and goes through the signal policies like any other signal would
+    pub fn inject_signal(&mut self, sig: Signal) {
+        if self.running() {
+            if let Err(e) = nix::sys::signal::kill(self.debugee_pid, sig) {
+                println!("Failed to send {} to debugee: {}", sig, e);
+            }
+            return;
+        }
+
+        self.pending_signal = Some(sig);
     }
 
     pub fn running(&self) -> bool {
-        !self.run_thread_should_die.load(Ordering::Relaxed) && !self.run_thread_parked.load(Ordering::Relaxed) && !self.run_thread.join_handle.is_finished()
+        self.running && !self.tracer_thread.is_finished()
     }
 
+    // Goes through the patcher so our int3s never show up
+    pub fn read_memory(&self, addr: u64, len: usize) -> Result<Vec<u8>, Errno> {
+        self.debugee_patcher.read_memory(addr, len)
+    }
+
+    // SIGKILLs the debugee and waits until the tracer has reaped it
     pub fn kill(&mut self) {
-        self.run_thread_should_die.store(true, Ordering::Relaxed);
-        self.run_thread_parked.store(false, Ordering::Relaxed);
-        self.run_thread.join_handle.thread().unpark();
+        if self.finished() {
+            return;
+        }
+
+        self.tracer.send(TracerCommand::Kill);
+        while !self.finished() {
+            self.poll_debugee_state(true);
+        }
+    }
+
+    // Takes our breakpoints out and lets the debugee carry on untraced
+    pub fn detach(&mut self) {
+        if self.finished() {
+            return;
+        }
+
+        // Need to know exactly where it is first, it might be sitting on a hit we haven't heard of yet
+        self.pause();
+        while self.running && !self.finished() {
+            self.poll_debugee_state(true);
+        }
+        if self.finished() {
+            return;
+        }
+
+        // Can't leave it one past an int3 that's about to disappear
+        if let Some(DebugeeEvent::BreakpointHit{ addr, .. }) = self.debugee_event {
+            let res = self.debugee_patcher.step_over(addr, AfterStepOver::Stay);
+            self.step_over_sent(addr, res);
+        }
+        self.sync_bp_state(&vec![]);
+
+        self.tracer.send(TracerCommand::Detach(self.pending_signal.take()));
+        while !self.finished() {
+            self.poll_debugee_state(true);
+        }
+    }
+}
+
+impl Drop for Run {
+    // Don't leave the debugee hanging around stopped forever
+    fn drop(&mut self) {
+        self.kill();
     }
 }
 
@@ -202,8 +694,12 @@ pub struct Session<'a> {
     //insertpoints: Vec<Box<dyn InsertPoint>>,
     //insertpoint_groups: Vec<InsertPointGroup>,
     pub breakpoints: Vec<BreakPoint<'a>>,
+    // Shared with the active run so changes apply immediately
+    pub signal_policies: Arc<Mutex<SignalPolicies>>,
 
     pub active_run: Option<Run>,
+    // Last run that ended, kept around for its output and events
+    pub finished_run: Option<Run>,
 }
 
 impl<'a> Session<'a> {
@@ -211,17 +707,29 @@ impl<'a> Session<'a> {
         self.debug_info.sync_debug_info();
     }
 
-    pub fn add_breakpoint(bp: BreakPoint<'_>) {
-        
+    pub fn add_breakpoint(&mut self, bp: BreakPoint<'a>) {
+        self.breakpoints.push(bp);
+        self.reconcile_bp_state_with_run();
     }
 
-    pub fn reconcile_bp_state_with_run() {
-        
+    // Moves the active run over to finished_run once it's over. Call after anything that might
+    // have ended it
+    pub fn retire_finished_run(&mut self) {
+        if self.active_run.as_ref().map_or(false, |r| r.finished()) {
+            self.finished_run = self.active_run.take();
+        }
     }
 
-    pub fn new(path_str: String, auto_load_src_root: Option<String>) -> std::result::Result<Session<'a>, ()> {
+    // Call after touching self.breakpoints. Does nothing if there's no run or nothing changed
+    pub fn reconcile_bp_state_with_run(&mut self) {
+        if let Some(run) = self.active_run.as_mut() {
+            run.sync_bp_state(&self.breakpoints);
+        }
+    }
+
+    pub fn new(path_str: String, auto_load_src_root: Option<String>, debug_dirs: Vec<PathBuf>) -> std::result::Result<Session<'a>, ()> {
         let exec_path = PathBuf::from(path_str);
-        let mut session = Session{ exec_path: exec_path.clone(), saved_on_disk: false, saved_path: None, debug_info: OfflineDebugInfo::new(exec_path.clone(), auto_load_src_root).unwrap(), breakpoints: vec![], active_run: None };
+        let mut session = Session{ exec_path: exec_path.clone(), saved_on_disk: false, saved_path: None, debug_info: OfflineDebugInfo::new(exec_path.clone(), auto_load_src_root, debug_dirs).unwrap(), breakpoints: vec![], signal_policies: Arc::new(Mutex::new(SignalPolicies::new())), active_run: None, finished_run: None };
 
         let path = session.exec_path.as_path();
         if !path.exists() || !path.is_file() {
@@ -232,61 +740,30 @@ impl<'a> Session<'a> {
         Ok(session)
     }
 
-    fn launch_child(path: &str) {
-        println!("Launching {path}");
-
-        ptrace::traceme().expect("Failed to TRACEME");
-
-        match personality(ADDR_NO_RANDOMIZE) {
-            Ok(p) => println!("Disabled ASLR. Previous personality: {:?}", p),
-            Err(e) => println!("Failed disabling ASLR: {:?}", e),
-        }
-
-        Command::new(path).exec();
-        exit(0);
-    }
-
     pub fn start_run(&mut self) -> std::result::Result<&Run, Errno> {
-        let fork_res = unsafe { fork() }?;
-        match fork_res {
-            ForkResult::Parent{ child, .. } => {
-                println!("Child pid: {child}");
-
-                {
-                    let mut run = Run::new(child);
-
-                    // At this point the debugee has launched and should have SIGTRAPped
-                    run.poll_debugee_state(true);
-                    match run.debugee_event {
-                        Some(Ok(nix::sys::wait::WaitStatus::Stopped(_, nix::sys::signal::Signal::SIGTRAP))) => {
-                            // TODO: fix this bullshit
-                            let addresses: Vec<u64> = self.breakpoints.iter().map(|bp| {
-                                println!("BP addr: {:x}, line: {}", bp.point.addr, bp.point.line_number);
-                                bp.point.addr + 0x555555555040 - 0x1040
-                            }).collect();
-                            run.debugee_patcher.inject_breakpoints(&addresses);
-                        },
-                        _ => { panic!("Errrm, something went wrong..."); }
-                    }
-                    run.cont();
-                    self.active_run = Some(run);
-                }
+        let mut run = Run::launch(self.exec_path.to_str().unwrap().to_owned(), Arc::clone(&self.signal_policies))?;
+        run.dwarf = Arc::clone(&self.debug_info.debug_info.dwarf);
+
+        // At this point the debugee has launched and should have SIGTRAPped
+        run.poll_debugee_state(true);
+        match run.debugee_event {
+            Some(DebugeeEvent::Trap) => {
+                run.sync_bp_state(&self.breakpoints);
             },
-            ForkResult::Child => {
-                Self::launch_child(self.exec_path.to_str().unwrap());
+            _ => {
+                // E.g. the exec failed. Whatever it is, it's not something we can debug
+                println!("Debugee didn't stop after launching: {:?}", run.debugee_event);
+                run.kill();
+                return Err(Errno::ECHILD);
             },
-        };
+        }
+        run.cont();
+        self.active_run = Some(run);
 
         Ok(&self.active_run.as_ref().unwrap())
     }
 }
 
-//enum DebugeeEvent {
-//    Status(WaitStatus),
-//    Error(Errno),
-//    None,
-//}
-//
 //pub struct RuntimeAddr(usize);
 //
 //struct DebugeeWatcherThread {
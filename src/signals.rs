@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+
+use nix::libc::siginfo_t as SigInfo;
+use nix::sys::signal::Signal;
+
+// Same semantics as gdb's `handle`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SignalPolicy {
+    // Stop the debugee and give control to the user
+    pub stop: bool,
+    // Log that the signal happened, even if we're not stopping
+    pub print: bool,
+    // Deliver the signal to the debugee once it continues
+    pub pass: bool,
+}
+
+pub struct SignalPolicies {
+    // BTreeMap just so the UI lists them in a stable order
+    policies: BTreeMap<i32, SignalPolicy>,
+}
+
+impl SignalPolicies {
+    pub fn new() -> Self {
+        let mut policies = BTreeMap::new();
+        for sig in Signal::iterator() {
+            policies.insert(sig as i32, Self::default_policy(sig));
+        }
+
+        SignalPolicies{ policies: policies }
+    }
+
+    // Mirrors gdb's defaults
+    fn default_policy(sig: Signal) -> SignalPolicy {
+        match sig {
+            // Ours -- the debugee never sees these unless asked to
+            Signal::SIGTRAP | Signal::SIGINT => SignalPolicy{ stop: true, print: true, pass: false },
+            // Noisy signals programs use for normal operation
+            Signal::SIGALRM | Signal::SIGURG | Signal::SIGCHLD | Signal::SIGWINCH |
+            Signal::SIGPROF | Signal::SIGIO | Signal::SIGVTALRM => SignalPolicy{ stop: false, print: false, pass: true },
+            _ => SignalPolicy{ stop: true, print: true, pass: true },
+        }
+    }
+
+    pub fn get(&self, sig: Signal) -> SignalPolicy {
+        match self.policies.get(&(sig as i32)) {
+            Some(p) => *p,
+            None => Self::default_policy(sig),
+        }
+    }
+
+    pub fn set(&mut self, sig: Signal, policy: SignalPolicy) {
+        self.policies.insert(sig as i32, policy);
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Signal, &mut SignalPolicy)> {
+        self.policies.iter_mut().filter_map(|(sig, policy)| Signal::try_from(*sig).ok().map(|s| (s, policy)))
+    }
+}
+
+pub fn describe_siginfo(sig: Signal, info: &SigInfo) -> String {
+    let mut desc = format!("{} (signo: {}, code: {}, errno: {})", sig, info.si_signo, info.si_code, info.si_errno);
+
+    match sig {
+        Signal::SIGSEGV | Signal::SIGBUS | Signal::SIGILL | Signal::SIGFPE => {
+            desc = format!("{}, fault addr: {:#x}", desc, unsafe { info.si_addr() } as u64);
+        },
+        _ => {
+            // SI_USER/SI_TKILL, i.e. sent by kill/raise
+            if info.si_code == 0 || info.si_code == -6 {
+                desc = format!("{}, sent by pid: {}", desc, unsafe { info.si_pid() });
+            }
+        },
+    }
+
+    desc
+}
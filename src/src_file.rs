@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{ Path, PathBuf };
 
 use std::io;
 
@@ -43,6 +43,14 @@ impl PartialEq for SrcFile {
     }
 }
 
+// Same as SrcFile::simple_hash, for paths that come out of the DWARF without a SrcFile
+pub fn path_hash(path: &Path) -> u64 {
+    let mut a = DefaultHasher::new();
+    path.hash(&mut a);
+
+    a.finish()
+}
+
 impl SrcFile {
     pub fn simple_hash(&self) -> u64 {
         let mut a = DefaultHasher::new();
@@ -52,7 +60,13 @@ impl SrcFile {
     }
 
     pub fn load_contents(&mut self) -> io::Result<bool> {
-        let file = fs::File::open(&self.path)?;
+        let path = self.path.clone();
+        self.load_contents_from(&path)
+    }
+
+    // Contents from somewhere other than the path the debug info knows it by
+    pub fn load_contents_from(&mut self, path: &Path) -> io::Result<bool> {
+        let file = fs::File::open(path)?;
         let reader = BufReader::new(file);
         let lines: Vec<String> = reader.lines().filter_map(io::Result::ok).collect();
         self.lines = Some(lines);
@@ -69,6 +83,13 @@ impl SrcFile {
 
         Ok(src_file)
     }
+
+    pub fn new_from(path: PathBuf, contents_path: &Path) -> io::Result<SrcFile> {
+        let mut src_file = SrcFile{ path: path, lines: None, line_to_addr: HashMap::new(), addr_to_line: HashMap::new() };
+        src_file.load_contents_from(contents_path)?;
+
+        Ok(src_file)
+    }
 }
 
 pub struct BinaryFile {
@@ -0,0 +1,63 @@
+use crate::offline_debug_info::{ ThinOfflineDebugInfo, Subprogram, BreakableSrcLocation };
+use crate::session::{ DebugeeState, LOAD_BIAS };
+use crate::src_file::path_hash;
+
+#[derive(Debug)]
+pub struct Frame {
+    pub subprogram: Subprogram,
+    // Index into all_subprograms
+    pub subprogram_index: usize,
+    pub location: Option<BreakableSrcLocation>,
+    pub file_hash: Option<u64>,
+    pub addr: u64,
+    // Virtual frame of a function inlined into the next one, shares its addr and registers
+    pub inlined: bool,
+}
+
+// Walks the rbp chain, innermost frame first. Inlined calls get a frame of their own, placed before
+// the function they got inlined into. read_memory is either the live debugee or a snapshot of it
+pub fn walk_stack(read_memory: impl Fn(u64, usize) -> Option<Vec<u8>>, state: &DebugeeState, debug_info: &ThinOfflineDebugInfo) -> Vec<Frame> {
+    let mut stack = vec![];
+
+    let mut frame_base = state.regs.rbp;
+    let mut ret_addr = state.addr;
+    loop {
+        if frame_base == 0 {
+            break;
+        }
+
+        ret_addr -= LOAD_BIAS;
+        // First hit has the correct addr, the rest point past the call
+        let call_addr = match stack.len() {
+            0 => ret_addr,
+            _ => debug_info.prev_instruction(ret_addr).unwrap_or(ret_addr),
+        };
+
+        // Both have to be in the same function, otherwise we've walked out of the debugee's code
+        let (subprogram_index, subprogram) = match debug_info.subprogram_at(call_addr) {
+            Some(s) if s.1.contains(ret_addr) => s,
+            _ => break,
+        };
+        let (mut file_hash, mut bp_location) = match debug_info.location_at(call_addr) {
+            Some((hash, location)) => (Some(hash), Some(location.clone())),
+            None => (None, None),
+        };
+        // The line table has the innermost inlined function's line, every frame out from there is
+        // at the call site of the one before it
+        for (inlined_subprogram, inlined) in debug_info.inlined_at(call_addr) {
+            stack.push(Frame{ subprogram: inlined_subprogram, subprogram_index: subprogram_index, location: bp_location, file_hash: file_hash, addr: call_addr, inlined: true });
+            file_hash = inlined.call_file.as_deref().map(path_hash);
+            bp_location = Some(BreakableSrcLocation{ addr: call_addr, src_line: inlined.call_line, src_col: inlined.call_col.max(1) });
+        }
+        stack.push(Frame{ subprogram: subprogram.clone(), subprogram_index: subprogram_index, location: bp_location, file_hash: file_hash, addr: call_addr, inlined: false });
+
+        let frame = match read_memory(frame_base, 16) {
+            Some(f) if f.len() == 16 => f,
+            _ => break,
+        };
+        ret_addr = u64::from_le_bytes(frame[8..16].try_into().unwrap());
+        frame_base = u64::from_le_bytes(frame[0..8].try_into().unwrap());
+    }
+
+    stack
+}
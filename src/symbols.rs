@@ -0,0 +1,300 @@
+use std::collections::{ HashMap, HashSet };
+
+use iced_x86::{ Decoder, DecoderOptions, Instruction, Mnemonic, OpKind, SymbolResolver, SymbolResult };
+use object::{ Object, ObjectSection, ObjectSymbol, ObjectSymbolTable, RelocationTarget, SymbolKind };
+
+use crate::dwarf_model::{ DwarfModel, VariableLocation, variable_location };
+use crate::offline_debug_info::Subprogram;
+
+// Functions from .symtab/.dynsym and PLT stubs, for code the DWARF doesn't cover (or binaries
+// without any). src_file_hash is 0 and there are no lines
+pub fn symbol_subprograms(bin_data: &[u8]) -> Vec<Subprogram> {
+    let object = match object::File::parse(bin_data) {
+        Ok(o) => o,
+        Err(_) => return vec![],
+    };
+
+    let mut subprograms = vec![];
+    let mut seen = HashSet::new();
+    // .symtab first, it also has the local functions. Aliases share an address, first one wins
+    for symbol in object.symbols().chain(object.dynamic_symbols()) {
+        if symbol.kind() != SymbolKind::Text || !symbol.is_definition() || symbol.size() == 0 {
+            continue;
+        }
+        let name = match symbol.name() {
+            Ok(n) if !n.is_empty() => n,
+            _ => continue,
+        };
+        if !seen.insert(symbol.address()) {
+            continue;
+        }
+
+        subprograms.push(symbol_subprogram(name, symbol.address(), symbol.size()));
+    }
+
+    subprograms.extend(plt_stubs(&object));
+    subprograms
+}
+
+fn symbol_subprogram(raw_name: &str, addr: u64, size: u64) -> Subprogram {
+    let (name, linkage_name) = match demangle(raw_name) {
+        Some(demangled) => (demangled, Some(raw_name.to_owned())),
+        None => (raw_name.to_owned(), None),
+    };
+    Subprogram{ name: name, linkage_name: linkage_name, low_addr: addr, high_addr: addr + size, ranges: vec![(addr, addr + size)], src_file_hash: 0, start_line: 0, end_line: 0 }
+}
+
+// Rust (legacy and v0) or Itanium C++. None if it isn't mangled, or we can't make sense of it
+pub fn demangle(name: &str) -> Option<String> {
+    // Legacy Rust names are valid Itanium too, but the C++ demangler keeps the hash around
+    if let Ok(demangled) = rustc_demangle::try_demangle(name) {
+        // {:#} drops the hash
+        return Some(format!("{:#}", demangled));
+    }
+
+    if !name.starts_with("_Z") {
+        return None;
+    }
+    let symbol = cpp_demangle::Symbol::new(name).ok()?;
+    symbol.demangle(&cpp_demangle::DemangleOptions::default()).ok()
+}
+
+// "ns::foo(int) const" -> "ns::foo". Demangled C++ symbol names have the parameters, the DWARF
+// names don't
+pub fn strip_params(name: &str) -> &str {
+    let trimmed = name.trim_end_matches(" const");
+    if !trimmed.ends_with(')') {
+        return name;
+    }
+
+    let mut depth = 0;
+    for (i, c) in trimmed.char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' => {
+                depth -= 1;
+                if depth == 0 {
+                    return &name[..i];
+                }
+            },
+            _ => {},
+        }
+    }
+    name
+}
+
+// PLT stubs have no symbols. Every stub jumps through a GOT slot and the dynamic relocation for
+// that slot says which function it's for
+fn plt_stubs(object: &object::File) -> Vec<Subprogram> {
+    let mut stubs = vec![];
+
+    let dynsym = match object.dynamic_symbol_table() {
+        Some(t) => t,
+        None => return stubs,
+    };
+    let mut got_names = HashMap::new();
+    if let Some(relocations) = object.dynamic_relocations() {
+        for (got_addr, relocation) in relocations {
+            if let RelocationTarget::Symbol(index) = relocation.target() {
+                if let Some(name) = dynsym.symbol_by_index(index).ok().and_then(|s| s.name().ok().map(|n| n.to_owned())) {
+                    if !name.is_empty() {
+                        got_names.insert(got_addr, name);
+                    }
+                }
+            }
+        }
+    }
+
+    // .plt.sec is where the stubs are with IBT, .plt then only has the lazy binding halves
+    for section_name in [".plt", ".plt.sec", ".plt.got"] {
+        let section = match object.section_by_name(section_name) {
+            Some(s) => s,
+            None => continue,
+        };
+        let data = match section.data() {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+
+        // .plt.got stubs are 8 bytes unless they start with endbr64
+        let entry_size = match section_name == ".plt.got" && !data.starts_with(&[0xf3, 0x0f, 0x1e, 0xfa]) {
+            true => 8,
+            false => 16,
+        };
+
+        let mut decoder = Decoder::with_ip(64, data, section.address(), DecoderOptions::NONE);
+        while decoder.can_decode() {
+            let instruction = decoder.decode();
+            if instruction.mnemonic() != Mnemonic::Jmp || !instruction.is_ip_rel_memory_operand() {
+                continue;
+            }
+            let name = match got_names.get(&instruction.ip_rel_memory_address()) {
+                Some(n) => n,
+                None => continue,
+            };
+
+            let entry = section.address() + (instruction.ip() - section.address()) / entry_size * entry_size;
+            let mut stub = symbol_subprogram(name, entry, entry_size);
+            stub.name.push_str("@plt");
+            if let Some(linkage_name) = &mut stub.linkage_name {
+                linkage_name.push_str("@plt");
+            }
+            stubs.push(stub);
+        }
+    }
+
+    stubs
+}
+
+// DWARF subprograms, then the symbols that start outside of all of them
+pub fn merge_symbols(dwarf_subprograms: Vec<Subprogram>, symbols: &Vec<Subprogram>) -> Vec<Subprogram> {
+    // foo.cold symbols are covered by the second range of foo
+    let mut ranges: Vec<(u64, u64)> = dwarf_subprograms.iter().flat_map(|s| s.ranges.iter().cloned()).collect();
+    ranges.sort();
+
+    let mut merged = dwarf_subprograms;
+    for symbol in symbols {
+        let i = ranges.partition_point(|(low, _)| *low <= symbol.low_addr);
+        let covered = i > 0 && symbol.low_addr < ranges[i - 1].1;
+        if !covered {
+            merged.push(symbol.clone());
+        }
+    }
+
+    merged
+}
+
+// Address -> name for the disassembly. Functions and globals, from the DWARF first and then the
+// symbol tables for whatever it doesn't have
+pub struct SymbolMap {
+    // (start, size, name), sorted by start
+    entries: Vec<(u64, u64, String)>,
+}
+
+impl SymbolMap {
+    pub fn new(bin_data: &[u8], dwarf: &DwarfModel, symbols: &Vec<Subprogram>) -> Self {
+        let mut entries = vec![];
+        for unit in &dwarf.units {
+            for subprogram in unit.subprograms.iter().filter(|s| !s.inline) {
+                for (low, high) in &subprogram.ranges {
+                    entries.push((*low, high - low, subprogram.name.clone()));
+                }
+            }
+
+            for variable in unit.variables.iter().filter(|v| v.subprogram.is_none()) {
+                // Only a plain DW_OP_addr, split units use DW_OP_addrx and we don't follow those here
+                let addr = match variable.location.as_deref().and_then(|expr| variable_location(expr, None)) {
+                    Some(VariableLocation::Static(addr)) => addr,
+                    _ => continue,
+                };
+                // Through typedefs and qualifiers to something with a size
+                let mut type_offset = variable.type_offset;
+                let mut size = None;
+                for _ in 0..8 {
+                    let t = match type_offset.and_then(|o| unit.types.get(&o)) {
+                        Some(t) => t,
+                        None => break,
+                    };
+                    if t.byte_size.is_some() {
+                        size = t.byte_size;
+                        break;
+                    }
+                    type_offset = t.inner;
+                }
+                entries.push((addr, size.unwrap_or(1), variable.name.clone()));
+            }
+        }
+
+        // Symbols starting where the DWARF already has something are aliases of it
+        let mut seen: HashSet<u64> = entries.iter().map(|(addr, _, _)| *addr).collect();
+        for symbol in symbols {
+            if seen.insert(symbol.low_addr) {
+                let name = strip_params(&symbol.name).to_owned();
+                entries.push((symbol.low_addr, symbol.high_addr - symbol.low_addr, name));
+            }
+        }
+        if let Ok(object) = object::File::parse(bin_data) {
+            for symbol in object.symbols().chain(object.dynamic_symbols()) {
+                if symbol.kind() != SymbolKind::Data || !symbol.is_definition() || symbol.size() == 0 {
+                    continue;
+                }
+                let name = match symbol.name() {
+                    Ok(n) if !n.is_empty() => n,
+                    _ => continue,
+                };
+                if seen.insert(symbol.address()) {
+                    let name = demangle(name).unwrap_or(name.to_owned());
+                    entries.push((symbol.address(), symbol.size(), name));
+                }
+            }
+        }
+
+        entries.sort_by_key(|(addr, _, _)| *addr);
+        SymbolMap{ entries: entries }
+    }
+
+    // Start and name of whatever addr falls in
+    pub fn lookup(&self, addr: u64) -> Option<(u64, &str)> {
+        let i = self.entries.partition_point(|(start, _, _)| *start <= addr);
+        // Nested or overlapping entries, the closest start that still covers addr
+        for (start, size, name) in self.entries[..i].iter().rev().take(4) {
+            if addr < start + std::cmp::max(*size, 1) {
+                return Some((*start, name));
+            }
+        }
+        None
+    }
+}
+
+impl SymbolResolver for SymbolMap {
+    fn symbol(&mut self, instruction: &Instruction, _operand: u32, instruction_operand: Option<u32>, address: u64, _address_size: u32) -> Option<SymbolResult<'_>> {
+        // Immediates are mostly just numbers, only branch targets and rip relative operands are
+        // addresses for sure
+        match instruction.op_kind(instruction_operand?) {
+            OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64 => {},
+            OpKind::Memory if instruction.is_ip_rel_memory_operand() => {},
+            _ => return None,
+        }
+
+        // The formatter adds the +offset itself
+        let (start, name) = self.lookup(address)?;
+        Some(SymbolResult::with_str(start, name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dwarf_subprogram(name: &str, ranges: Vec<(u64, u64)>) -> Subprogram {
+        Subprogram{ name: name.to_owned(), linkage_name: None, low_addr: ranges[0].0, high_addr: ranges[0].1, ranges: ranges, src_file_hash: 1, start_line: 1, end_line: 2 }
+    }
+
+    #[test]
+    fn params_stripped() {
+        assert_eq!(strip_params("foo"), "foo");
+        assert_eq!(strip_params("foo(int)"), "foo");
+        assert_eq!(strip_params("ns::Class::foo(int, char*) const"), "ns::Class::foo");
+        assert_eq!(strip_params("foo(void (*)(int))"), "foo");
+        assert_eq!(strip_params("operator()(int)"), "operator()");
+        // Unbalanced, leave it alone
+        assert_eq!(strip_params("foo)"), "foo)");
+    }
+
+    #[test]
+    fn symbols_merged() {
+        let dwarf = vec![dwarf_subprogram("foo", vec![(0x1000, 0x1040), (0x2000, 0x2010)])];
+        let symbols = vec![
+            symbol_subprogram("foo", 0x1000, 0x40),
+            symbol_subprogram("foo.cold", 0x2000, 0x10),
+            symbol_subprogram("_ZN2ns3barEv", 0x1040, 0x20),
+            symbol_subprogram("printf@plt", 0x800, 0x10),
+        ];
+        let merged = merge_symbols(dwarf, &symbols);
+
+        let names: Vec<&str> = merged.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["foo", "ns::bar()", "printf@plt"]);
+        assert_eq!(merged[1].linkage_name.as_deref(), Some("_ZN2ns3barEv"));
+        assert_eq!(merged[1].src_file_hash, 0);
+    }
+}
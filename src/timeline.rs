@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use nix::errno::Errno;
+use nix::libc::user_regs_struct as UserRegsStruct;
+
+use crate::dwarf_model::{ DwarfModel, format_value, value_size };
+use crate::recording::regs_delta;
+use crate::registers::USER_REGS_NAMES;
+use crate::session::{ DebugeeEvent, DebugeeState, LOAD_BIAS };
+
+// Oldest ones get dropped past this
+const MAX_SNAPSHOTS: usize = 10_000;
+// How far up the rbp chain we save frame records
+const MAX_FRAMES: usize = 64;
+// Don't save the top frame if it's unreasonably big, rbp is probably not a frame pointer
+const MAX_FRAME_SIZE: u64 = 64 * 1024;
+
+// Everything we could grab about the debugee at one stop
+#[derive(Clone)]
+pub struct Snapshot {
+    pub time: Instant,
+    pub event: DebugeeEvent,
+    pub state: DebugeeState,
+    // (name, value) of the locals in scope, evaluated at the time
+    pub locals: Vec<(String, String)>,
+    // Debugee memory as it was at the time, sorted by address. The top frame, the frame records up
+    // the stack and whatever the user asked us to watch
+    pub memory: Vec<(u64, Vec<u8>)>,
+}
+
+impl Snapshot {
+    // None if we didn't save all of it
+    pub fn read_memory(&self, addr: u64, len: usize) -> Option<Vec<u8>> {
+        let end = addr.checked_add(len as u64)?;
+        for (start, bytes) in &self.memory {
+            if *start <= addr && end <= *start + bytes.len() as u64 {
+                let from = (addr - start) as usize;
+                return Some(bytes[from..from + len].to_vec());
+            }
+        }
+
+        None
+    }
+}
+
+pub struct Timeline {
+    pub snapshots: Vec<Snapshot>,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Timeline{ snapshots: vec![] }
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if self.snapshots.len() >= MAX_SNAPSHOTS {
+            self.snapshots.remove(0);
+        }
+        self.snapshots.push(snapshot);
+    }
+}
+
+pub fn capture_memory(read_memory: impl Fn(u64, usize) -> Result<Vec<u8>, Errno>, regs: &UserRegsStruct, watched: &Vec<(u64, usize)>) -> Vec<(u64, Vec<u8>)> {
+    let mut memory = vec![];
+
+    // Top frame, including the saved rbp and return address
+    if regs.rbp > regs.rsp && regs.rbp - regs.rsp < MAX_FRAME_SIZE {
+        if let Ok(bytes) = read_memory(regs.rsp, (regs.rbp - regs.rsp) as usize + 16) {
+            memory.push((regs.rsp, bytes));
+        }
+    }
+
+    // Enough to walk the stack again later, same way generate_stack does
+    let mut frame_base = regs.rbp;
+    for _ in 0..MAX_FRAMES {
+        if frame_base == 0 {
+            break;
+        }
+        let frame = match read_memory(frame_base, 16) {
+            Ok(f) if f.len() == 16 => f,
+            _ => break,
+        };
+
+        let next = u64::from_le_bytes(frame[0..8].try_into().unwrap());
+        memory.push((frame_base, frame));
+        // Stack grows down, anything else means we've walked off into garbage
+        if next <= frame_base {
+            break;
+        }
+        frame_base = next;
+    }
+
+    for (addr, len) in watched {
+        if let Ok(bytes) = read_memory(*addr, *len) {
+            memory.push((*addr, bytes));
+        }
+    }
+
+    memory.sort_by_key(|(addr, _)| *addr);
+    memory
+}
+
+// Same values print_variable would show for them
+pub fn evaluate_locals(read_memory: impl Fn(u64, usize) -> Result<Vec<u8>, Errno>, state: &DebugeeState, dwarf: &DwarfModel) -> Vec<(String, String)> {
+    dwarf.locals_at(state.addr.wrapping_sub(LOAD_BIAS)).into_iter()
+        .map(|(unit, variable, location)| {
+            let value = match location {
+                Some(location) => {
+                    let var_type = unit.resolve_type(variable.type_offset);
+                    let size = value_size(var_type);
+                    match read_memory(location.runtime_addr(state.regs.rbp, LOAD_BIAS), size) {
+                        Ok(bytes) if bytes.len() == size => format_value(var_type, &bytes),
+                        _ => "<unreadable>".to_owned(),
+                    }
+                },
+                None => "<optimized out>".to_owned(),
+            };
+            (variable.name.clone(), value)
+        })
+        .collect()
+}
+
+pub struct SnapshotDiff {
+    // (name, before, after)
+    pub regs: Vec<(&'static str, u64, u64)>,
+    // (name, before, after), None on the side where it wasn't in scope
+    pub locals: Vec<(String, Option<String>, Option<String>)>,
+    // (addr, before, after) for every run of changed bytes we have both sides of
+    pub memory: Vec<(u64, Vec<u8>, Vec<u8>)>,
+}
+
+pub fn diff(before: &Snapshot, after: &Snapshot) -> SnapshotDiff {
+    let regs = regs_delta(&before.state.regs, &after.state.regs).into_iter()
+        .map(|(i, old, new)| (USER_REGS_NAMES[i], old, new))
+        .collect();
+
+    // Ranges can overlap (top frame and its frame record), so flatten them first
+    let old_bytes = flatten(&before.memory);
+    let new_bytes = flatten(&after.memory);
+
+    let mut memory: Vec<(u64, Vec<u8>, Vec<u8>)> = vec![];
+    for (addr, new) in &new_bytes {
+        let old = match old_bytes.get(addr) {
+            Some(old) if old != new => *old,
+            _ => continue,
+        };
+
+        // Extend the previous run if it ends right here
+        match memory.last_mut() {
+            Some((start, olds, news)) if *start + olds.len() as u64 == *addr => {
+                olds.push(old);
+                news.push(*new);
+            },
+            _ => memory.push((*addr, vec![old], vec![*new])),
+        }
+    }
+
+    SnapshotDiff{ regs: regs, locals: diff_locals(&before.locals, &after.locals), memory: memory }
+}
+
+// Changed ones in the order they're in after, then the ones that went out of scope
+fn diff_locals(before: &Vec<(String, String)>, after: &Vec<(String, String)>) -> Vec<(String, Option<String>, Option<String>)> {
+    let value_in = |locals: &Vec<(String, String)>, name: &str| locals.iter().find(|(n, _)| n == name).map(|(_, v)| v.clone());
+
+    let mut locals = vec![];
+    for (name, new) in after {
+        let old = value_in(before, name);
+        if old.as_ref() != Some(new) {
+            locals.push((name.clone(), old, Some(new.clone())));
+        }
+    }
+    for (name, old) in before {
+        if value_in(after, name).is_none() {
+            locals.push((name.clone(), Some(old.clone()), None));
+        }
+    }
+    locals
+}
+
+fn flatten(memory: &Vec<(u64, Vec<u8>)>) -> BTreeMap<u64, u8> {
+    let mut bytes = BTreeMap::new();
+    for (addr, range) in memory {
+        for (i, b) in range.iter().enumerate() {
+            bytes.insert(addr + i as u64, *b);
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(rax: u64, rsp: u64, memory: Vec<(u64, Vec<u8>)>) -> Snapshot {
+        with_locals(rax, rsp, memory, vec![])
+    }
+
+    fn with_locals(rax: u64, rsp: u64, memory: Vec<(u64, Vec<u8>)>, locals: Vec<(&str, &str)>) -> Snapshot {
+        let mut regs: UserRegsStruct = unsafe { std::mem::zeroed() };
+        regs.rax = rax;
+        regs.rsp = rsp;
+        let state = DebugeeState{ regs: regs, fp_regs: None, signal: None, siginfo: None, addr: 0, file: String::new(), line: None, col: None };
+        let locals = locals.into_iter().map(|(name, value)| (name.to_owned(), value.to_owned())).collect();
+        Snapshot{ time: Instant::now(), event: DebugeeEvent::Interrupted, state: state, locals: locals, memory: memory }
+    }
+
+    #[test]
+    fn regs_that_changed() {
+        let d = diff(&snapshot(1, 0x100, vec![]), &snapshot(2, 0x100, vec![]));
+        assert_eq!(d.regs, vec![("rax", 1, 2)]);
+        assert!(d.locals.is_empty());
+        assert!(d.memory.is_empty());
+    }
+
+    #[test]
+    fn locals_that_changed() {
+        let before = with_locals(0, 0, vec![], vec![("n", "1"), ("i", "0"), ("old", "7")]);
+        let after = with_locals(0, 0, vec![], vec![("n", "1"), ("i", "1"), ("new", "2")]);
+        let some = |s: &str| Some(s.to_owned());
+        assert_eq!(diff(&before, &after).locals, vec![
+            ("i".to_owned(), some("0"), some("1")),
+            ("new".to_owned(), None, some("2")),
+            ("old".to_owned(), some("7"), None),
+        ]);
+    }
+
+    #[test]
+    fn reads_past_the_end_of_memory() {
+        let s = snapshot(0, 0, vec![(0x10, vec![1, 2, 3, 4])]);
+        assert_eq!(s.read_memory(0x11, 2), Some(vec![2, 3]));
+        assert_eq!(s.read_memory(0x12, 4), None);
+        assert_eq!(s.read_memory(u64::MAX, 2), None);
+    }
+
+    #[test]
+    fn memory_runs() {
+        let before = snapshot(0, 0, vec![(0x10, vec![1, 2, 3, 4, 5, 6])]);
+        let after = snapshot(0, 0, vec![(0x10, vec![1, 9, 9, 4, 9, 6])]);
+        let d = diff(&before, &after);
+        assert_eq!(d.memory, vec![(0x11, vec![2, 3], vec![9, 9]), (0x14, vec![5], vec![9])]);
+    }
+
+    #[test]
+    fn only_bytes_on_both_sides() {
+        // Frame moved, only the overlap can be compared. Overlapping ranges within one snapshot
+        // count once
+        let before = snapshot(0, 0, vec![(0x10, vec![1, 2, 3, 4]), (0x12, vec![3, 4])]);
+        let after = snapshot(0, 0, vec![(0x12, vec![7, 4, 5, 6])]);
+        let d = diff(&before, &after);
+        assert_eq!(d.memory, vec![(0x12, vec![3], vec![7])]);
+    }
+}
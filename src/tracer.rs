@@ -0,0 +1,1225 @@
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicI32, AtomicUsize, Ordering };
+use std::sync::mpsc::{ channel, Sender, Receiver, RecvTimeoutError };
+use std::thread::{ Builder, JoinHandle };
+use std::time::Duration;
+
+use std::os::unix::process::CommandExt;
+use std::process::{ exit, Command, Stdio };
+use std::fs::File;
+use std::io::{ Read, Write };
+use std::os::unix::io::{ FromRawFd, RawFd };
+
+use core::ffi::c_void;
+
+use linux_personality::{ personality, ADDR_NO_RANDOMIZE };
+use nix::errno::Errno;
+use nix::libc;
+use nix::libc::user_regs_struct as UserRegsStruct;
+use nix::libc::siginfo_t as SigInfo;
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::sys::uio::{ process_vm_readv, RemoteIoVec };
+use nix::sys::wait::{ waitpid, WaitPidFlag, WaitStatus };
+use nix::fcntl::OFlag;
+use nix::unistd::{ close, dup, fork, pipe2, ForkResult, Pid };
+
+use crate::registers::{ FpRegs, read_fp_regs, write_fp_regs, read_debug_reg, write_debug_reg, DR6_SINGLE_STEP, DR6_WATCHPOINT_MASK };
+use crate::recording::{ Trace, TraceEntry, MemoryDelta, LastChange, memory_writes, regs_delta, undo_regs, fp_regs_delta, undo_fp_regs };
+use crate::session::DebugeeEvent;
+use crate::signals::SignalPolicies;
+
+const WORD_SIZE: u64 = std::mem::size_of::<i64>() as u64;
+
+// How long to wait for commands before checking on a running debugee again
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+// How many recorded steps before checking for commands again
+const RECORDING_COMMAND_INTERVAL: usize = 1024;
+
+const X86_INT3: u8 = 0xCC;
+const X86_SYSCALL: [u8; 2] = [0x0F, 0x05];
+
+// EXITKILL so the debugee doesn't outlive us if we crash
+const PTRACE_OPTIONS: ptrace::Options = ptrace::Options::PTRACE_O_TRACEEXEC
+    .union(ptrace::Options::PTRACE_O_TRACECLONE)
+    .union(ptrace::Options::PTRACE_O_EXITKILL);
+
+enum StepOutcome {
+    Stepped,
+    // Something else happened instead (signal etc), already taken care of
+    Stopped,
+    Dead,
+}
+
+// What to do once a breakpoint has been stepped over. Only happens if the step went through, if
+// the debugee stopped for anything else on the way that stop gets reported instead
+pub enum AfterStepOver {
+    // Stay stopped without telling anyone, e.g. we're about to detach
+    Stay,
+    // Send a StepComplete, for when the step over is all the user asked for
+    Report,
+    Cont(Option<Signal>),
+    RecordedCont{ signal: Option<Signal>, breakpoints: HashMap<u64, u8> },
+}
+
+pub enum TracerCommand {
+    Cont(Option<Signal>),
+    Step(Option<Signal>),
+    // Put the original instruction back, execute it and put the breakpoint back in again.
+    // Debugee has to be stopped right after hitting the breakpoint at addr. It's one command
+    // with whatever comes next, so a stop on the way can't be followed by a stale Cont
+    StepOverBreakpoint{ addr: u64, original: Vec<u8>, patched: Vec<u8>, then: AfterStepOver },
+    ReadMemory{ addr: u64, len: usize, reply: Sender<Result<Vec<u8>, Errno>> },
+    WriteMemory{ addr: u64, data: Vec<u8>, reply: Sender<Result<(), Errno>> },
+    // Stop a running debugee and report it as Interrupted
+    Interrupt,
+    // Recording single steps the debugee from here on, see recording.rs
+    StartRecording,
+    StopRecording,
+    // Cont while recording. Needs our breakpoints (addr -> original byte) since we step over
+    // them by hand instead of letting the int3s fire
+    RecordedCont{ signal: Option<Signal>, breakpoints: HashMap<u64, u8> },
+    // Undo the last instruction
+    ReverseStep,
+    // Back to the previous hit of any of the given breakpoints, or the start of the recording
+    ReverseCont{ breakpoints: HashMap<u64, u8> },
+    // Back to right before the last write to [addr, addr + len)
+    ReverseUntilChange{ addr: u64, len: usize },
+    LastChange{ addr: u64, len: usize, reply: Sender<Option<LastChange>> },
+    // SIGKILL the debugee and reap it. Tracer thread dies afterwards
+    Kill,
+    // Let the debugee go on without us, delivering the given signal. Breakpoints have to be out
+    // already. Tracer thread only sticks around to reap it
+    Detach(Option<Signal>),
+    // Fork the stopped debugee and keep the child around, stopped, as a checkpoint. Replies with
+    // the child's pid
+    Checkpoint{ reply: Sender<Result<Pid, Errno>> },
+    // Kill the debugee and carry on from a fresh fork of the checkpoint, so the checkpoint can be
+    // used again. strip has the int3s that were in when the checkpoint was taken (addr -> original
+    // byte), they get taken out so the patcher can put its current ones in. event is reported again
+    // as the stop we're at. Replies with the new debugee's pid
+    RestoreCheckpoint{ pid: Pid, strip: HashMap<u64, u8>, event: DebugeeEvent, reply: Sender<Result<Pid, Errno>> },
+    DropCheckpoint(Pid),
+}
+
+// Whatever we could gather about the debugee while it was stopped
+pub struct StopState {
+    pub regs: UserRegsStruct,
+    pub fp_regs: Option<FpRegs>,
+}
+
+pub struct TracerEvent {
+    pub event: DebugeeEvent,
+    // None if the debugee isn't stopped anymore, e.g. it's gone, or it got a signal we let through
+    pub stop: Option<StopState>,
+    // Only for SignalReceived
+    pub siginfo: Option<SigInfo>,
+}
+
+// siginfo_t has a pointer in it (si_addr), but it's just a number from the debugee's address space
+unsafe impl Send for TracerEvent {}
+
+// The only way anyone gets to touch the debugee. Cheap to clone and hand out
+#[derive(Clone)]
+pub struct TracerHandle {
+    // Changes when a checkpoint gets restored
+    pid: Arc<AtomicI32>,
+    commands: Sender<TracerCommand>,
+    // Length of the recorded trace, kept up to date by the tracer
+    recorded_steps: Arc<AtomicUsize>,
+}
+
+impl TracerHandle {
+    pub fn pid(&self) -> Pid {
+        Pid::from_raw(self.pid.load(Ordering::Relaxed))
+    }
+
+    pub fn send(&self, command: TracerCommand) {
+        self.try_send(command).ok();
+    }
+
+    // For the callers that have to know the command never made it
+    pub fn try_send(&self, command: TracerCommand) -> Result<(), ()> {
+        self.commands.send(command).map_err(|_| println!("Tracer thread is gone, dropping command"))
+    }
+
+    pub fn cont(&self, signal: Option<Signal>) {
+        self.send(TracerCommand::Cont(signal));
+    }
+
+    pub fn step(&self, signal: Option<Signal>) {
+        self.send(TracerCommand::Step(signal));
+    }
+
+    pub fn recorded_steps(&self) -> usize {
+        self.recorded_steps.load(Ordering::Relaxed)
+    }
+
+    // Blocks until the tracer gets to it
+    pub fn last_change(&self, addr: u64, len: usize) -> Option<LastChange> {
+        let (reply, response) = channel();
+        self.send(TracerCommand::LastChange{ addr: addr, len: len, reply: reply });
+        response.recv().unwrap_or(None)
+    }
+
+    // Blocks until the tracer gets to it
+    pub fn checkpoint(&self) -> Result<Pid, Errno> {
+        let (reply, response) = channel();
+        self.send(TracerCommand::Checkpoint{ reply: reply });
+        response.recv().unwrap_or(Err(Errno::ESRCH))
+    }
+
+    // Blocks until the tracer gets to it
+    pub fn restore_checkpoint(&self, pid: Pid, strip: HashMap<u64, u8>, event: DebugeeEvent) -> Result<Pid, Errno> {
+        let (reply, response) = channel();
+        self.send(TracerCommand::RestoreCheckpoint{ pid: pid, strip: strip, event: event, reply: reply });
+        response.recv().unwrap_or(Err(Errno::ESRCH))
+    }
+
+    // Blocks until the tracer gets to it
+    pub fn read_memory(&self, addr: u64, len: usize) -> Result<Vec<u8>, Errno> {
+        let (reply, response) = channel();
+        self.send(TracerCommand::ReadMemory{ addr: addr, len: len, reply: reply });
+        response.recv().unwrap_or(Err(Errno::ESRCH))
+    }
+
+    // Blocks until the tracer gets to it
+    pub fn write_memory(&self, addr: u64, data: &[u8]) -> Result<(), Errno> {
+        let (reply, response) = channel();
+        self.send(TracerCommand::WriteMemory{ addr: addr, data: data.to_vec(), reply: reply });
+        response.recv().unwrap_or(Err(Errno::ESRCH))
+    }
+}
+
+pub struct Tracer {
+    pid: Pid,
+    shared_pid: Arc<AtomicI32>,
+    running: bool,
+    // Last thing we told the debugee to do was a single step
+    stepping: bool,
+    // Can only set ptrace options once the debugee has stopped for the first time
+    options_set: bool,
+    // We sent a SIGSTOP to interrupt the debugee, but it stopped for something else first
+    interrupt_pending: bool,
+    // Some while recording
+    trace: Option<Trace>,
+    recorded_steps: Arc<AtomicUsize>,
+    // Forks of the debugee we keep stopped until they're needed
+    checkpoints: Vec<Pid>,
+
+    commands: Receiver<TracerCommand>,
+    events: Sender<TracerEvent>,
+
+    signal_policies: Arc<Mutex<SignalPolicies>>,
+}
+
+impl Tracer {
+    // Forks and execs the debugee on a fresh tracer thread. The kernel only lets the thread that
+    // forked the debugee ptrace it, so all ptrace/waitpid calls have to live on that thread
+    // Everything the debugee writes to stdout/stderr ends up in output, as well as on our own stdout
+    pub fn launch(path: String, signal_policies: Arc<Mutex<SignalPolicies>>, output: Arc<Mutex<Vec<u8>>>) -> Result<(TracerHandle, Receiver<TracerEvent>, JoinHandle<()>), Errno> {
+        let (command_sender, command_receiver) = channel();
+        let (event_sender, event_receiver) = channel();
+        let (launch_sender, launch_receiver) = channel();
+        let recorded_steps = Arc::new(AtomicUsize::new(0));
+        let tracer_recorded_steps = Arc::clone(&recorded_steps);
+        let shared_pid = Arc::new(AtomicI32::new(0));
+        let tracer_shared_pid = Arc::clone(&shared_pid);
+
+        let join_handle = Builder::new()
+            .name("TracerThread".to_owned())
+            .spawn(move || {
+                // CLOEXEC so nothing else we spawn inherits it. The child dup2s it onto its
+                // stdout/stderr, which clears the flag on those
+                let (output_read, output_write) = match pipe2(OFlag::O_CLOEXEC) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        reply_to(launch_sender, Err(e));
+                        return;
+                    },
+                };
+
+                let fork_res = unsafe { fork() };
+                let pid = match fork_res {
+                    Ok(ForkResult::Parent{ child, .. }) => child,
+                    Ok(ForkResult::Child) => Self::launch_child(&path, output_write),
+                    Err(e) => {
+                        reply_to(launch_sender, Err(e));
+                        return;
+                    },
+                };
+                // Otherwise we'd never see EOF on the read end
+                log_failure(close(output_write), "close our end of the output pipe");
+                Self::spawn_output_reader(output_read, output);
+                println!("Child pid: {pid}");
+                tracer_shared_pid.store(pid.as_raw(), Ordering::Relaxed);
+                reply_to(launch_sender, Ok(pid));
+
+                // At this point the debugee has launched and should SIGTRAP once it has exec'd
+                let mut tracer = Tracer{ pid: pid, shared_pid: tracer_shared_pid, running: true, stepping: false, options_set: false, interrupt_pending: false, trace: None, recorded_steps: tracer_recorded_steps, checkpoints: vec![], commands: command_receiver, events: event_sender, signal_policies: signal_policies };
+                tracer.run();
+                // Nothing to restore them into anymore
+                tracer.drop_checkpoints();
+            })
+            .map_err(|_| Errno::EAGAIN)?;
+
+        let pid = launch_receiver.recv().unwrap_or(Err(Errno::ECHILD))?;
+        Ok((TracerHandle{ pid: shared_pid, commands: command_sender, recorded_steps: recorded_steps }, event_receiver, join_handle))
+    }
+
+    fn launch_child(path: &str, output: RawFd) -> ! {
+        println!("Launching {path}");
+
+        ptrace::traceme().expect("Failed to TRACEME");
+
+        match personality(ADDR_NO_RANDOMIZE) {
+            Ok(p) => println!("Disabled ASLR. Previous personality: {:?}", p),
+            Err(e) => println!("Failed disabling ASLR: {:?}", e),
+        }
+
+        let mut command = Command::new(path);
+        if let Ok(stderr) = dup(output) {
+            unsafe {
+                command.stdout(Stdio::from_raw_fd(output)).stderr(Stdio::from_raw_fd(stderr));
+            }
+        }
+        // Only comes back if it failed
+        let e = command.exec();
+        println!("Failed to exec {}: {}", path, e);
+        exit(1);
+    }
+
+    // Detached, dies once the debugee (and anyone it passed its stdout on to) is gone
+    fn spawn_output_reader(fd: RawFd, output: Arc<Mutex<Vec<u8>>>) {
+        let res = Builder::new()
+            .name("OutputThread".to_owned())
+            .spawn(move || {
+                let mut file = unsafe { File::from_raw_fd(fd) };
+                let mut buf = [0u8; 4096];
+                loop {
+                    match file.read(&mut buf) {
+                        Ok(0) => return,
+                        Ok(n) => {
+                            // Our own stdout going away doesn't matter, output still gets it
+                            let mut stdout = std::io::stdout();
+                            stdout.write_all(&buf[..n]).ok();
+                            stdout.flush().ok();
+                            output.lock().unwrap().extend_from_slice(&buf[..n]);
+                        },
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+                        Err(_) => return,
+                    }
+                }
+            });
+        if res.is_err() {
+            println!("Failed to spawn output thread, debugee output is lost");
+        }
+    }
+
+    fn run(&mut self) {
+        loop {
+            if !self.running {
+                match self.commands.recv() {
+                    Ok(command) => {
+                        if !self.handle_command(command) {
+                            return;
+                        }
+                    },
+                    Err(_) => return,
+                }
+                continue;
+            }
+
+            match waitpid(self.pid, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::StillAlive) => {
+                    match self.commands.recv_timeout(POLL_INTERVAL) {
+                        Ok(command) => {
+                            if !self.handle_command(command) {
+                                return;
+                            }
+                        },
+                        Err(RecvTimeoutError::Timeout) => {},
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                },
+                Ok(status) => {
+                    if !self.handle_status(status) {
+                        return;
+                    }
+                },
+                Err(e) => {
+                    println!("waitpid failed ({}), tracer thread died!", e);
+                    return;
+                },
+            }
+        }
+    }
+
+    fn send_event(&self, event: DebugeeEvent, stop: Option<StopState>) {
+        self.send_tracer_event(TracerEvent{ event: event, stop: stop, siginfo: None });
+    }
+
+    fn send_tracer_event(&self, event: TracerEvent) {
+        if let Err(e) = self.events.send(event) {
+            println!("Run is gone, dropping {:?}", e.0.event);
+        }
+    }
+
+    fn gather_stop_state(&self) -> Option<StopState> {
+        let regs = ptrace::getregs(self.pid).ok()?;
+        Some(StopState{ regs: regs, fp_regs: read_fp_regs(self.pid).ok() })
+    }
+
+    // Returns false once there's nothing left to trace
+    fn handle_status(&mut self, status: WaitStatus) -> bool {
+        let event = match DebugeeEvent::from_wait_status(status) {
+            Some(e) => e,
+            None => return true,
+        };
+        self.running = false;
+        let was_stepping = self.stepping;
+        self.stepping = false;
+
+        if !self.options_set && !event.is_exit() {
+            log_failure(ptrace::setoptions(self.pid, PTRACE_OPTIONS), "set ptrace options");
+            self.options_set = true;
+        }
+
+        match event {
+            DebugeeEvent::Exited{..} | DebugeeEvent::Killed{..} => {
+                println!("Child is gone ({:?}), tracer thread died!", event);
+                self.send_event(event, None);
+                false
+            },
+            DebugeeEvent::Trap => {
+                let event = self.classify_trap(was_stepping);
+                self.send_event(event, self.gather_stop_state());
+                true
+            },
+            DebugeeEvent::SignalReceived{ signal: Signal::SIGSTOP } if self.interrupt_pending => {
+                // Leftover from interrupt(), nobody needs to know about it
+                self.interrupt_pending = false;
+                self.stepping = was_stepping;
+                self.resume(None);
+                true
+            },
+            DebugeeEvent::SignalReceived{ signal } => {
+                let policy = self.signal_policies.lock().unwrap().get(signal);
+                let siginfo = ptrace::getsiginfo(self.pid).ok();
+                if policy.stop {
+                    self.send_tracer_event(TracerEvent{ event: event, stop: self.gather_stop_state(), siginfo: siginfo });
+                    return true;
+                }
+
+                // Never surfaces to the user as a stop, just push it through
+                self.send_tracer_event(TracerEvent{ event: event, stop: None, siginfo: siginfo });
+                // A step stays a step, the signal just comes along with it
+                self.stepping = was_stepping;
+                self.resume(if policy.pass { Some(signal) } else { None });
+                true
+            },
+            DebugeeEvent::Clone{..} => {
+                let new_pid = ptrace::getevent(self.pid).ok().map(|tid| Pid::from_raw(tid as i32));
+                // TODO: we only debug the main thread for now. Let the new one go its own way,
+                // once it has reached its initial stop
+                if let Some(new_pid) = new_pid {
+                    log_failure(waitpid(new_pid, Some(WaitPidFlag::__WALL)), "wait for a new thread");
+                    log_failure(ptrace::detach(new_pid, None), "detach from a new thread");
+                }
+                self.send_event(DebugeeEvent::Clone{ new_pid: new_pid }, None);
+                self.stepping = was_stepping;
+                self.resume(None);
+                true
+            },
+            e => {
+                self.send_event(e, self.gather_stop_state());
+                true
+            },
+        }
+    }
+
+    // Int3s are left as a plain Trap, it's up to whoever placed them to recognize their own
+    fn classify_trap(&self, was_stepping: bool) -> DebugeeEvent {
+        let dr6 = read_debug_reg(self.pid, 6).unwrap_or(0);
+        if dr6 != 0 {
+            // The cpu never clears DR6 itself
+            log_failure(write_debug_reg(self.pid, 6, 0), "clear DR6");
+        }
+
+        // DR6 is sticky, so the si_code is the more reliable source of why we stopped
+        let code = ptrace::getsiginfo(self.pid).map(|i| i.si_code).unwrap_or(0);
+        if code == libc::SI_KERNEL || code == libc::TRAP_BRKPT {
+            return DebugeeEvent::Trap;
+        }
+
+        if dr6 & DR6_WATCHPOINT_MASK != 0 {
+            let index = (dr6 & DR6_WATCHPOINT_MASK).trailing_zeros() as usize;
+            let addr = read_debug_reg(self.pid, index).unwrap_or(0);
+            return DebugeeEvent::WatchpointTriggered{ index: index, addr: addr };
+        }
+        if was_stepping || code == libc::TRAP_TRACE || dr6 & DR6_SINGLE_STEP != 0 {
+            let rip = ptrace::getregs(self.pid).map(|r| r.rip).unwrap_or(0);
+            return DebugeeEvent::StepComplete{ addr: rip };
+        }
+
+        DebugeeEvent::Trap
+    }
+
+    fn resume(&mut self, signal: Option<Signal>) {
+        // Still set if something got in the way of a step, which then has to carry on as one
+        let res = if self.stepping {
+            ptrace::step(self.pid, signal)
+        } else {
+            ptrace::cont(self.pid, signal)
+        };
+        match res {
+            Ok(_) => self.running = true,
+            Err(e) => println!("Failed to continue debugee: {}", e),
+        }
+    }
+
+    // Returns false if the tracer should die
+    fn handle_command(&mut self, command: TracerCommand) -> bool {
+        match command {
+            TracerCommand::Cont(signal) => {
+                if !self.running {
+                    self.resume(signal);
+                }
+            },
+            TracerCommand::Step(signal) => {
+                if !self.running {
+                    match ptrace::step(self.pid, signal) {
+                        Ok(_) => {
+                            self.running = true;
+                            self.stepping = true;
+                        },
+                        Err(e) => println!("Failed to step debugee: {}", e),
+                    }
+                }
+            },
+            TracerCommand::StepOverBreakpoint{ addr, original, patched, then } => {
+                if self.running {
+                    return true;
+                }
+
+                let report = match then {
+                    AfterStepOver::Report => true,
+                    _ => false,
+                };
+                if !self.step_over_breakpoint(addr, &original, &patched, report) {
+                    return true;
+                }
+                match then {
+                    AfterStepOver::Cont(signal) => self.resume(signal),
+                    AfterStepOver::RecordedCont{ signal, breakpoints } => {
+                        if self.trace.is_none() {
+                            self.resume(signal);
+                        } else {
+                            return self.record_cont(signal, &breakpoints);
+                        }
+                    },
+                    AfterStepOver::Stay | AfterStepOver::Report => {},
+                }
+            },
+            TracerCommand::ReadMemory{ addr, len, reply } => {
+                reply_to(reply, self.read_memory(addr, len));
+            },
+            TracerCommand::WriteMemory{ addr, data, reply } => {
+                if !self.running {
+                    reply_to(reply, self.write_memory(addr, &data));
+                    return true;
+                }
+
+                match self.interrupt() {
+                    Ok(resume) => {
+                        reply_to(reply, self.write_memory(addr, &data));
+                        if resume {
+                            self.resume(None);
+                        }
+                    },
+                    Err(_) => {
+                        reply_to(reply, Err(Errno::ESRCH));
+                        return false;
+                    },
+                }
+            },
+            TracerCommand::Interrupt => {
+                if !self.running {
+                    return true;
+                }
+
+                match self.interrupt() {
+                    Ok(true) => {
+                        // Whatever step we were in the middle of is abandoned
+                        self.stepping = false;
+                        self.send_event(DebugeeEvent::Interrupted, self.gather_stop_state());
+                    },
+                    Ok(false) => {},
+                    Err(_) => return false,
+                }
+            },
+            TracerCommand::StartRecording => {
+                if self.trace.is_none() {
+                    self.trace = Some(Trace::new());
+                }
+            },
+            TracerCommand::StopRecording => {
+                self.trace = None;
+                self.recorded_steps.store(0, Ordering::Relaxed);
+            },
+            TracerCommand::RecordedCont{ signal, breakpoints } => {
+                if !self.running {
+                    if self.trace.is_none() {
+                        self.resume(signal);
+                    } else {
+                        return self.record_cont(signal, &breakpoints);
+                    }
+                }
+            },
+            TracerCommand::ReverseStep => {
+                if !self.running {
+                    self.reverse_step();
+                }
+            },
+            TracerCommand::ReverseCont{ breakpoints } => {
+                if !self.running {
+                    self.reverse_cont(&breakpoints);
+                }
+            },
+            TracerCommand::ReverseUntilChange{ addr, len } => {
+                if !self.running {
+                    self.reverse_until_change(addr, len);
+                }
+            },
+            TracerCommand::LastChange{ addr, len, reply } => {
+                reply_to(reply, self.trace.as_ref().and_then(|t| t.last_change(addr, len)));
+            },
+            TracerCommand::Kill => {
+                self.drop_checkpoints();
+                self.kill();
+                return false;
+            },
+            TracerCommand::Detach(signal) => {
+                self.drop_checkpoints();
+                self.detach(signal);
+                return false;
+            },
+            TracerCommand::Checkpoint{ reply } => {
+                if self.running {
+                    reply_to(reply, Err(Errno::EBUSY));
+                    return true;
+                }
+
+                let res = self.inject_fork();
+                if let Ok(pid) = res {
+                    self.checkpoints.push(pid);
+                }
+                reply_to(reply, res);
+            },
+            TracerCommand::RestoreCheckpoint{ pid, strip, event, reply } => {
+                if self.running || !self.checkpoints.contains(&pid) {
+                    reply_to(reply, Err(Errno::EINVAL));
+                    return true;
+                }
+
+                reply_to(reply, self.restore_checkpoint(pid, &strip, event));
+            },
+            TracerCommand::DropCheckpoint(pid) => {
+                if let Some(i) = self.checkpoints.iter().position(|p| *p == pid) {
+                    self.checkpoints.remove(i);
+                    kill_and_reap(pid);
+                }
+            },
+        }
+
+        true
+    }
+
+    fn kill(&mut self) {
+        if let Err(e) = nix::sys::signal::kill(self.pid, Signal::SIGKILL) {
+            println!("Failed to kill debugee: {}", e);
+            return;
+        }
+
+        // Reap it, so it doesn't stick around as a zombie. Anything that isn't it dying is stale
+        loop {
+            match waitpid(self.pid, Some(WaitPidFlag::__WALL)) {
+                Ok(status @ WaitStatus::Exited(..)) | Ok(status @ WaitStatus::Signaled(..)) => {
+                    if let Some(event) = DebugeeEvent::from_wait_status(status) {
+                        self.send_event(event, None);
+                    }
+                    return;
+                },
+                Ok(_) => {},
+                Err(e) => {
+                    println!("waitpid failed while killing debugee: {}", e);
+                    return;
+                },
+            }
+        }
+    }
+
+    fn detach(&mut self, signal: Option<Signal>) {
+        if self.running {
+            match self.interrupt() {
+                Ok(_) => {},
+                Err(_) => return,
+            }
+        }
+
+        if let Err(e) = ptrace::detach(self.pid, signal) {
+            println!("Failed to detach from debugee: {}", e);
+            return;
+        }
+        if self.interrupt_pending {
+            // Our SIGSTOP would put it to sleep as soon as we let go. SIGCONT discards it
+            log_failure(nix::sys::signal::kill(self.pid, Signal::SIGCONT), "SIGCONT the detached debugee");
+        }
+        self.send_event(DebugeeEvent::Detached, None);
+
+        // Still our child, someone has to reap it
+        loop {
+            match waitpid(self.pid, None) {
+                Ok(WaitStatus::Exited(..)) | Ok(WaitStatus::Signaled(..)) | Err(_) => return,
+                Ok(_) => {},
+            }
+        }
+    }
+
+    // Stops a running debugee so we can poke at it. Ok(true) if it's stopped just for us and should
+    // be resumed once we're done, Ok(false) if it stopped on its own on the way (and that stop has
+    // been reported as usual). Err if it's gone
+    fn interrupt(&mut self) -> Result<bool, ()> {
+        // Thread directed, so the detached threads don't end up in a group stop
+        let res = unsafe { libc::syscall(libc::SYS_tgkill, self.pid.as_raw(), self.pid.as_raw(), libc::SIGSTOP) };
+        if res != 0 {
+            println!("Failed to interrupt debugee: {}", Errno::last());
+            return Err(());
+        }
+
+        loop {
+            match waitpid(self.pid, Some(WaitPidFlag::__WALL)) {
+                Ok(WaitStatus::Stopped(_, Signal::SIGSTOP)) => {
+                    self.running = false;
+                    return Ok(true);
+                },
+                Ok(status) => {
+                    // Our SIGSTOP is still queued up behind whatever this is
+                    self.interrupt_pending = true;
+                    if !self.handle_status(status) {
+                        return Err(());
+                    }
+                    if !self.running {
+                        return Ok(false);
+                    }
+                },
+                Err(e) => {
+                    println!("waitpid failed while interrupting debugee: {}", e);
+                    return Err(());
+                },
+            }
+        }
+    }
+
+    // Returns true if the debugee is still stopped where it should be, right after the original
+    // instruction (or never was on the int3 to begin with). False if something else stopped it
+    fn step_over_breakpoint(&mut self, addr: u64, original: &[u8], patched: &[u8], report: bool) -> bool {
+        let mut regs = match ptrace::getregs(self.pid) {
+            Ok(r) => r,
+            Err(_) => return false,
+        };
+        if regs.rip - 1 != addr {
+            return true;
+        }
+
+        let hit_regs = regs;
+        if let Err(e) = self.write_memory(addr, original) {
+            println!("Failed to take the breakpoint at 0x{:x} out: {}", addr, e);
+            self.report_hit_again();
+            return false;
+        }
+        regs.rip = addr;
+        if let Err(e) = ptrace::setregs(self.pid, regs) {
+            println!("Failed to rewind to the breakpoint at 0x{:x}: {}", addr, e);
+            log_failure(self.write_memory(addr, patched), "put the breakpoint back");
+            self.report_hit_again();
+            return false;
+        }
+
+        if self.trace.is_some() {
+            // Original is already in, so no breakpoints to worry about
+            let stepped = match self.record_step(None, &HashMap::new()) {
+                StepOutcome::Stepped => {
+                    // Fold the rewind into the step, undoing it has to end up right after the int3 again
+                    let new_regs = ptrace::getregs(self.pid);
+                    if let (Ok(new_regs), Some(entry)) = (new_regs, self.trace.as_mut().and_then(|t| t.last_mut())) {
+                        entry.regs = regs_delta(&hit_regs, &new_regs);
+                    }
+                    if report {
+                        self.send_step_complete();
+                    }
+                    true
+                },
+                StepOutcome::Stopped | StepOutcome::Dead => false,
+            };
+            if self.process_alive() {
+                log_failure(self.write_memory(addr, patched), "put the breakpoint back");
+            }
+            return stepped;
+        }
+
+        if ptrace::step(self.pid, None).is_err() {
+            return false;
+        }
+        // Fine to block here, we're the only ones waiting on it and it's a single instruction
+        match waitpid(self.pid, None) {
+            Ok(WaitStatus::Stopped(_, Signal::SIGTRAP)) => {
+                // Otherwise the BS bit sticks around and the next int3 looks like a step
+                log_failure(write_debug_reg(self.pid, 6, 0), "clear DR6");
+                log_failure(self.write_memory(addr, patched), "put the breakpoint back");
+                if report {
+                    self.send_step_complete();
+                }
+                true
+            },
+            Ok(status) => {
+                // Something else happened on the way, e.g. the instruction faulted. Report it as is
+                log_failure(self.write_memory(addr, patched), "put the breakpoint back");
+                self.running = true;
+                self.handle_status(status);
+                false
+            },
+            Err(e) => {
+                println!("waitpid failed while stepping over a breakpoint: {}", e);
+                false
+            },
+        }
+    }
+
+    // Nothing moved, the stop gets reported as the same breakpoint hit again so the run doesn't
+    // wait forever for something that's never coming
+    fn report_hit_again(&self) {
+        self.send_event(DebugeeEvent::Trap, self.gather_stop_state());
+    }
+
+    fn send_step_complete(&self) {
+        let rip = ptrace::getregs(self.pid).map(|r| r.rip).unwrap_or(0);
+        self.send_event(DebugeeEvent::StepComplete{ addr: rip }, self.gather_stop_state());
+    }
+
+    fn process_alive(&self) -> bool {
+        ptrace::getregs(self.pid).is_ok()
+    }
+
+    // Single steps one instruction and logs what it changed. The debugee has to be stopped
+    fn record_step(&mut self, signal: Option<Signal>, breakpoints: &HashMap<u64, u8>) -> StepOutcome {
+        let regs = match ptrace::getregs(self.pid) {
+            Ok(r) => r,
+            Err(_) => return StepOutcome::Dead,
+        };
+        let fp_regs = read_fp_regs(self.pid).ok();
+
+        let original = breakpoints.get(&regs.rip).copied();
+        // Longest x86 instruction is 15 bytes
+        let mut code = self.read_memory(regs.rip, 16).unwrap_or(vec![]);
+        if let (Some(byte), Some(first)) = (original, code.first_mut()) {
+            *first = byte;
+        }
+
+        let mut memory = vec![];
+        for (addr, len) in memory_writes(&code, &regs) {
+            // Unreadable means it's about to fault, nothing to log
+            if let Ok(old) = self.read_memory(addr, len) {
+                memory.push(MemoryDelta{ addr: addr, old: old, new: vec![] });
+            }
+        }
+
+        if let Some(byte) = original {
+            log_failure(self.write_memory(regs.rip, &[byte]), "take a breakpoint out for a recorded step");
+        }
+        if let Err(e) = ptrace::step(self.pid, signal) {
+            println!("Failed to step debugee while recording: {}", e);
+            return StepOutcome::Stopped;
+        }
+        let status = waitpid(self.pid, Some(WaitPidFlag::__WALL));
+        if original.is_some() && self.process_alive() {
+            log_failure(self.write_memory(regs.rip, &[X86_INT3]), "put the breakpoint back");
+        }
+
+        match status {
+            Ok(WaitStatus::Stopped(_, Signal::SIGTRAP)) => {
+                // BS sticks around otherwise, same as in step_over_breakpoint
+                log_failure(write_debug_reg(self.pid, 6, 0), "clear DR6");
+
+                let new_regs = match ptrace::getregs(self.pid) {
+                    Ok(r) => r,
+                    Err(_) => return StepOutcome::Dead,
+                };
+                for delta in &mut memory {
+                    delta.new = self.read_memory(delta.addr, delta.old.len()).unwrap_or(vec![]);
+                }
+                // Couldn't read it back, so we don't know it changed
+                memory.retain(|d| d.new.len() == d.old.len());
+
+                // Without both sides there's nothing to undo them to, stepping back leaves them be
+                let fp_delta = match (&fp_regs, read_fp_regs(self.pid)) {
+                    (Some(old), Ok(new)) => fp_regs_delta(old, &new),
+                    _ => vec![],
+                };
+
+                let entry = TraceEntry{ addr: regs.rip, regs: regs_delta(&regs, &new_regs), fp_regs: fp_delta, memory: memory, breakpoint: false };
+                if let Some(trace) = self.trace.as_mut() {
+                    trace.push(entry);
+                    self.recorded_steps.store(trace.len(), Ordering::Relaxed);
+                }
+                StepOutcome::Stepped
+            },
+            Ok(status) => {
+                if !self.handle_status(status) {
+                    return StepOutcome::Dead;
+                }
+                if self.running {
+                    // A signal we don't stop for got passed through with a plain cont, so there's
+                    // going to be a hole in the trace. Better have none than a wrong one
+                    println!("Debugee ran off without us, recording starts over");
+                    if let Some(trace) = self.trace.as_mut() {
+                        trace.clear();
+                    }
+                    self.recorded_steps.store(0, Ordering::Relaxed);
+                }
+                StepOutcome::Stopped
+            },
+            Err(e) => {
+                println!("waitpid failed while recording: {}", e);
+                StepOutcome::Dead
+            },
+        }
+    }
+
+    // Returns false if the tracer should die
+    fn record_cont(&mut self, signal: Option<Signal>, breakpoints: &HashMap<u64, u8>) -> bool {
+        let mut signal = signal;
+        let mut steps: usize = 0;
+        loop {
+            // Might be sitting right on one if we just stepped back, that one doesn't count
+            if steps > 0 {
+                let rip = match ptrace::getregs(self.pid) {
+                    Ok(r) => r.rip,
+                    Err(_) => return false,
+                };
+                if breakpoints.contains_key(&rip) {
+                    self.fake_breakpoint_hit(rip);
+                    return true;
+                }
+            }
+
+            match self.record_step(signal.take(), breakpoints) {
+                StepOutcome::Stepped => {},
+                StepOutcome::Stopped => return true,
+                StepOutcome::Dead => return false,
+            }
+            steps += 1;
+
+            if steps % RECORDING_COMMAND_INTERVAL == 0 {
+                while let Ok(command) = self.commands.try_recv() {
+                    match command {
+                        TracerCommand::Interrupt => {
+                            self.send_event(DebugeeEvent::Interrupted, self.gather_stop_state());
+                            return true;
+                        },
+                        command => {
+                            if !self.handle_command(command) {
+                                return false;
+                            }
+                        },
+                    }
+                }
+            }
+        }
+    }
+
+    // Make it look exactly like the int3 at addr fired, so everything downstream (step over etc)
+    // works as usual. Logged like an instruction so stepping back goes through it
+    fn fake_breakpoint_hit(&mut self, addr: u64) {
+        let regs = match ptrace::getregs(self.pid) {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        let mut new_regs = regs;
+        new_regs.rip = addr + 1;
+        if let Err(e) = ptrace::setregs(self.pid, new_regs) {
+            // Report it where it really is, the next cont picks up from there
+            println!("Failed to fake a breakpoint hit at 0x{:x}: {}", addr, e);
+            self.send_event(DebugeeEvent::Interrupted, self.gather_stop_state());
+            return;
+        }
+
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(TraceEntry{ addr: addr, regs: regs_delta(&regs, &new_regs), fp_regs: vec![], memory: vec![], breakpoint: true });
+            self.recorded_steps.store(trace.len(), Ordering::Relaxed);
+        }
+        self.send_event(DebugeeEvent::Trap, self.gather_stop_state());
+    }
+
+    // Returns None once there's nothing left to undo
+    fn undo_step(&mut self) -> Option<TraceEntry> {
+        let entry = self.trace.as_mut()?.pop()?;
+        self.recorded_steps.store(self.trace.as_ref().map_or(0, |t| t.len()), Ordering::Relaxed);
+
+        for delta in entry.memory.iter().rev() {
+            log_failure(self.write_memory(delta.addr, &delta.old), "undo a recorded memory write");
+        }
+        let undone = ptrace::getregs(self.pid).and_then(|regs| ptrace::setregs(self.pid, undo_regs(&regs, &entry)));
+        log_failure(undone, "undo a recorded step's registers");
+        if !entry.fp_regs.is_empty() {
+            let undone = read_fp_regs(self.pid).and_then(|fp_regs| write_fp_regs(self.pid, &undo_fp_regs(&fp_regs, &entry)));
+            log_failure(undone, "undo a recorded step's x87/SSE/AVX registers");
+        }
+
+        Some(entry)
+    }
+
+    fn send_reverse_stop(&mut self) {
+        let rip = ptrace::getregs(self.pid).map(|r| r.rip).unwrap_or(0);
+        self.send_event(DebugeeEvent::StepComplete{ addr: rip }, self.gather_stop_state());
+    }
+
+    fn reverse_step(&mut self) {
+        // Fake int3s aren't worth stopping for on their own
+        while let Some(entry) = self.undo_step() {
+            if !entry.breakpoint {
+                break;
+            }
+        }
+        self.send_reverse_stop();
+    }
+
+    fn reverse_cont(&mut self, breakpoints: &HashMap<u64, u8>) {
+        let mut undone_any = false;
+        loop {
+            let hit = match self.trace.as_ref().and_then(|t| t.last()) {
+                Some(entry) => entry.breakpoint && breakpoints.contains_key(&entry.addr),
+                None => break,
+            };
+            // Stop right after the int3 "fired", same state as a normal hit
+            if hit && undone_any {
+                self.send_event(DebugeeEvent::Trap, self.gather_stop_state());
+                return;
+            }
+
+            self.undo_step();
+            undone_any = true;
+        }
+
+        // Start of the recording
+        self.send_reverse_stop();
+    }
+
+    fn reverse_until_change(&mut self, addr: u64, len: usize) {
+        let steps = self.trace.as_ref().and_then(|t| t.last_change(addr, len)).map_or(0, |c| c.steps_ago + 1);
+        for _ in 0..steps {
+            self.undo_step();
+        }
+        self.send_reverse_stop();
+    }
+
+    // Makes the stopped debugee call fork by putting a syscall instruction at rip for a single step.
+    // Everything is put back afterwards, in both processes, so the child is stopped exactly where the
+    // debugee is. Note the child really is the debugee's child, if it waits for all of its children
+    // it's going to wait forever
+    fn inject_fork(&mut self) -> Result<Pid, Errno> {
+        let regs = ptrace::getregs(self.pid)?;
+        let original = self.read_memory(regs.rip, X86_SYSCALL.len())?;
+
+        let mut fork_regs = regs;
+        fork_regs.rax = libc::SYS_fork as u64;
+        // Otherwise the kernel might think it's restarting whatever syscall we interrupted
+        fork_regs.orig_rax = u64::MAX;
+
+        // Only want to follow the fork we make ourselves
+        ptrace::setoptions(self.pid, PTRACE_OPTIONS | ptrace::Options::PTRACE_O_TRACEFORK)?;
+        self.write_memory(regs.rip, &X86_SYSCALL)?;
+        ptrace::setregs(self.pid, fork_regs)?;
+
+        let child = self.step_through_fork();
+
+        let restored = self.write_memory(regs.rip, &original).and_then(|_| ptrace::setregs(self.pid, regs));
+        log_failure(ptrace::setoptions(self.pid, PTRACE_OPTIONS), "reset ptrace options after forking a checkpoint");
+        if let Err(e) = restored {
+            println!("Failed to put the debugee back after forking a checkpoint, it's probably broken now: {}", e);
+            if let Ok(child) = child {
+                kill_and_reap(child);
+            }
+            return Err(e);
+        }
+        let child = child?;
+
+        // Kernel attaches us to the child and stops it before it gets to run anything
+        loop {
+            match waitpid(child, Some(WaitPidFlag::__WALL)) {
+                Ok(WaitStatus::Stopped(..)) | Ok(WaitStatus::PtraceEvent(..)) => break,
+                Ok(_) => {},
+                Err(e) => {
+                    kill_and_reap(child);
+                    return Err(e);
+                },
+            }
+        }
+
+        // Options are inherited, and it's still sitting right after our syscall
+        let prepared = ptrace::setoptions(child, PTRACE_OPTIONS)
+            .and_then(|_| write_process_memory(child, regs.rip, &original))
+            .and_then(|_| ptrace::setregs(child, regs))
+            .and_then(|_| write_debug_reg(child, 6, 0));
+        if let Err(e) = prepared {
+            kill_and_reap(child);
+            return Err(e);
+        }
+
+        println!("Checkpoint pid: {}", child);
+        Ok(child)
+    }
+
+    // Single steps the injected syscall. Returns the pid of the forked child
+    fn step_through_fork(&mut self) -> Result<Pid, Errno> {
+        let mut child = None;
+        ptrace::step(self.pid, None)?;
+        loop {
+            match waitpid(self.pid, Some(WaitPidFlag::__WALL))? {
+                WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_FORK) => {
+                    child = ptrace::getevent(self.pid).ok().map(|pid| Pid::from_raw(pid as i32));
+                    // Still has to return from the syscall
+                    ptrace::step(self.pid, None)?;
+                },
+                WaitStatus::Stopped(_, Signal::SIGTRAP) => {
+                    log_failure(write_debug_reg(self.pid, 6, 0), "clear DR6");
+                    break;
+                },
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => return Err(Errno::ESRCH),
+                status => {
+                    // TODO: we lose any signal that shows up in the middle of this
+                    println!("Dropping {:?} while forking a checkpoint", status);
+                    ptrace::step(self.pid, None)?;
+                },
+            }
+        }
+
+        child.ok_or(Errno::ECHILD)
+    }
+
+    fn restore_checkpoint(&mut self, checkpoint: Pid, strip: &HashMap<u64, u8>, event: DebugeeEvent) -> Result<Pid, Errno> {
+        // Fork the checkpoint instead of using it directly, so it can be restored again later
+        let old_pid = self.pid;
+        self.pid = checkpoint;
+        let new_pid = self.inject_fork();
+        self.pid = old_pid;
+        let new_pid = new_pid?;
+
+        kill_and_reap(old_pid);
+        self.pid = new_pid;
+        self.shared_pid.store(new_pid.as_raw(), Ordering::Relaxed);
+        self.stepping = false;
+        self.interrupt_pending = false;
+
+        for (addr, original) in strip {
+            log_failure(self.write_memory(*addr, &[*original]), "take a stale breakpoint out of the restored checkpoint");
+        }
+
+        // Nothing recorded so far happened in this process
+        if let Some(trace) = &mut self.trace {
+            trace.clear();
+            self.recorded_steps.store(0, Ordering::Relaxed);
+        }
+
+        self.send_event(DebugeeEvent::CheckpointRestored{ pid: checkpoint }, None);
+        self.send_event(event, self.gather_stop_state());
+        Ok(new_pid)
+    }
+
+    fn drop_checkpoints(&mut self) {
+        for pid in self.checkpoints.drain(..) {
+            kill_and_reap(pid);
+        }
+    }
+
+    fn read_memory(&self, addr: u64, len: usize) -> Result<Vec<u8>, Errno> {
+        let mut data = vec![0u8; len];
+        if self.running {
+            // ptrace can't peek into a running process, but this doesn't care
+            let remote = [RemoteIoVec{ base: addr as usize, len: len }];
+            let read = process_vm_readv(self.pid, &mut [std::io::IoSliceMut::new(&mut data)], &remote)?;
+            data.truncate(read);
+            return Ok(data);
+        }
+
+        let start = addr & !(WORD_SIZE - 1);
+        let end = addr + len as u64;
+        let mut word_addr = start;
+        while word_addr < end {
+            let word = ptrace::read(self.pid, word_addr as *mut c_void)?.to_le_bytes();
+            for (i, b) in word.iter().enumerate() {
+                let byte_addr = word_addr + i as u64;
+                if byte_addr >= addr && byte_addr < end {
+                    data[(byte_addr - addr) as usize] = *b;
+                }
+            }
+            word_addr += WORD_SIZE;
+        }
+
+        Ok(data)
+    }
+
+    fn write_memory(&self, addr: u64, data: &[u8]) -> Result<(), Errno> {
+        write_process_memory(self.pid, addr, data)
+    }
+}
+
+// Process has to be stopped
+fn write_process_memory(pid: Pid, addr: u64, data: &[u8]) -> Result<(), Errno> {
+    let end = addr + data.len() as u64;
+    let mut word_addr = addr & !(WORD_SIZE - 1);
+    while word_addr < end {
+        let mut word = ptrace::read(pid, word_addr as *mut c_void)?.to_le_bytes();
+        for (i, b) in word.iter_mut().enumerate() {
+            let byte_addr = word_addr + i as u64;
+            if byte_addr >= addr && byte_addr < end {
+                *b = data[(byte_addr - addr) as usize];
+            }
+        }
+        unsafe {
+            ptrace::write(pid, word_addr as *mut c_void, i64::from_le_bytes(word) as *mut c_void)?;
+        }
+        word_addr += WORD_SIZE;
+    }
+
+    Ok(())
+}
+
+// For the ones where there's nothing better to do than say so
+fn log_failure<T>(res: Result<T, Errno>, what: &str) {
+    if let Err(e) = res {
+        println!("Failed to {}: {}", what, e);
+    }
+}
+
+// Whoever asked might have given up waiting, e.g. the run got dropped
+fn reply_to<T>(reply: Sender<T>, value: T) {
+    if reply.send(value).is_err() {
+        println!("Nobody is waiting for the tracer's reply anymore");
+    }
+}
+
+// For processes nobody needs to hear about dying
+fn kill_and_reap(pid: Pid) {
+    if nix::sys::signal::kill(pid, Signal::SIGKILL).is_err() {
+        return;
+    }
+
+    loop {
+        match waitpid(pid, Some(WaitPidFlag::__WALL)) {
+            Ok(WaitStatus::Exited(..)) | Ok(WaitStatus::Signaled(..)) | Err(_) => return,
+            Ok(_) => {},
+        }
+    }
+}
@@ -0,0 +1,16 @@
+#include <signal.h>
+#include <unistd.h>
+
+volatile int handled = 0;
+
+void alarm_handler(int sig) {
+    handled = sig;
+}
+
+int main() {
+    signal(SIGALRM, alarm_handler);
+    long pid = getpid();
+    // Straight to the syscall, so the signal shows up in the middle of stepping
+    __asm__ volatile("syscall" : : "a"(62), "D"(pid), "S"(SIGALRM) : "rcx", "r11", "memory");
+    return handled == SIGALRM ? 0 : 1;
+}
@@ -0,0 +1,95 @@
+// Drives degrugger-cli over stdin against test_code/recursion.c, like the imgui frontend would the
+// same Session/Run
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{ Command, Stdio };
+
+fn test_exe(name: &str) -> PathBuf {
+    let src = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(format!("test_code/{}.c", name));
+    let exe = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let status = Command::new("cc").args(["-g", "-O0", "-o"]).arg(&exe).arg(&src).status().expect("no cc");
+    assert!(status.success());
+    exe
+}
+
+fn run_script(script: &str) -> String {
+    run_script_on("recursion", script)
+}
+
+fn run_script_on(name: &str, script: &str) -> String {
+    run_script_with(&test_exe(name), script)
+}
+
+fn run_script_with(exe: &PathBuf, script: &str) -> String {
+    let mut cli = Command::new(env!("CARGO_BIN_EXE_degrugger-cli"))
+        .arg(exe)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    cli.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+    let output = cli.wait_with_output().unwrap();
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+// Every test compiles into the same file, one at a time is plenty fast anyway
+static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[test]
+fn break_and_continue() {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let out = run_script("break fib\nrun\nprint n\ncontinue\nprint n\nquit\n");
+    assert!(out.contains("Breakpoint 0 at"), "{}", out);
+    assert!(out.contains("Breakpoint 0, fib () at recursion.c:6"), "{}", out);
+    assert!(out.contains("n = 0"), "{}", out);
+    assert!(out.contains("n = 1"), "{}", out);
+}
+
+#[test]
+fn step_lines() {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let out = run_script("break recursion.c:25\nrun\nstep\nstep\nbt\nquit\n");
+    assert!(out.contains("main () at recursion.c:25"), "{}", out);
+    // Into fib, then past the declaration
+    assert!(out.contains("fib () at recursion.c:6"), "{}", out);
+    assert!(out.contains("in fib () at recursion.c:"), "{}", out);
+    assert!(out.contains("in main () at recursion.c:25"), "{}", out);
+}
+
+#[test]
+fn finish_returns_to_caller() {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let out = run_script("break fib\nrun\ndelete 0\nfinish\nbt\nquit\n");
+    assert!(out.contains("Run till exit from fib ()"), "{}", out);
+    assert!(out.contains("main () at recursion.c:25"), "{}", out);
+    assert!(!out.contains("in fib ()"), "{}", out);
+}
+
+#[test]
+fn runs_to_exit() {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let out = run_script("run\n");
+    assert!(out.contains("Exited with code 0"), "{}", out);
+}
+
+#[test]
+fn exec_failure_reported() {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let exe = test_exe("recursion");
+    let not_exe = exe.with_file_name("recursion_not_executable");
+    std::fs::copy(&exe, &not_exe).unwrap();
+    std::fs::set_permissions(&not_exe, std::os::unix::fs::PermissionsExt::from_mode(0o644)).unwrap();
+    let out = run_script_with(&not_exe, "run\nquit\n");
+    assert!(out.contains("Failed to start:"), "{}", out);
+}
+
+// SIGALRM is passed straight through by default, the step has to end up in the handler instead of
+// turning into a continue
+#[test]
+fn step_into_passed_signal() {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let out = run_script_on("step_signal", "break step_signal.c:14\nrun\nstepi\nstepi\nstepi\nstepi\nstepi\nstepi\nbt\nquit\n");
+    assert!(out.contains("alarm_handler () at step_signal.c:"), "{}", out);
+    assert!(!out.contains("Exited with code"), "{}", out);
+}